@@ -20,12 +20,8 @@ fi
 mkdir -p /app/data/temp
 chown -R agent:agent /app/data
 
-chown agent:agent /etc/scripts/uploader.sh /etc/scripts/cancel-transfer.sh \
-                  /etc/scripts/generate-key-pair.sh /etc/scripts/revoke-key-pair.sh \
-                  /etc/scripts/extract-archive.sh
-chmod u+rx,u-w,g-rwx,o-rwx /etc/scripts/uploader.sh /etc/scripts/cancel-transfer.sh \
-          /etc/scripts/generate-key-pair.sh /etc/scripts/revoke-key-pair.sh \
-          /etc/scripts/extract-archive.sh
+chown agent:agent /etc/scripts/extract-archive.sh
+chmod u+rx,u-w,g-rwx,o-rwx /etc/scripts/extract-archive.sh
 
 if [ -f /app/data/client/.ssh/id_rsa ];then
   echo "SSH keys exist"
@@ -1,43 +1,183 @@
 #!/usr/bin/with-contenv bash
 
+# paths as configured for the agent, which exports them to the scripts it runs
+TEMP_DIR=${AGENT_TEMP_DATA_DIR:-/app/data/temp/}
+CLI=${AGENT_CLI_EXECUTABLE_PATH:-/app/cli}
+
+# the interface of the script, passed first by the callers which ask for it
+# with --interface before calling the script of a remote agent, so that
+# agents of different versions do not misread each other's arguments
+INTERFACE=2
+if [ "$1" = "--interface" ]; then
+  echo $INTERFACE
+  exit 0
+fi
+if [ "$1" != "$INTERFACE" ]; then
+  echo "Unsupported call of the extract script, the calling agent has to be upgraded to interface $INTERFACE" >&2
+  exit 88
+fi
+shift
+
 ARCHIVE_NANE=$1
-ARCHIVE_PATH=/app/data/temp/$ARCHIVE_NANE.dst.tar
+ARCHIVE_PATH=${TEMP_DIR%/}/$ARCHIVE_NANE.dst.tar
 EXTRACT_PATH=$2
-IS_COMPRESSED=$3
-OVERWRITE=$4
+OVERWRITE=$3
+MANIFEST=$4
+# comma separated list of the metadata to apply: modes, mtimes, owners, xattrs
+PRESERVE=${5:-modes,mtimes}
+# name of the file listing the conflict strategies of the items, if shipped
+# with the archive the existing files are resolved one by one
+CONFLICTS=$6
 
-SKIP_OLD_FILES_FLAG=
-GZIP_FLAG=
+# an archive name of '-' reads the archive streamed to stdin
+if [ "$ARCHIVE_NANE" = "-" ]; then
+  ARCHIVE_PATH=-
+fi
 
-if [ "$IS_COMPRESSED" = "true" ]; then
-  GZIP_FLAG=-z
+# detect the codec of the archive from its magic bytes, the first bytes of
+# an archive read from stdin are kept to be passed on to tar
+MAGIC_PATH=
+if [ "$ARCHIVE_PATH" = "-" ]; then
+  MAGIC_PATH=$(mktemp "${TEMP_DIR%/}/magic-XXXXXX")
+  dd bs=1 count=6 of=$MAGIC_PATH 2>/dev/null
+  MAGIC=$(od -An -tx1 -N6 $MAGIC_PATH | tr -d ' \n')
+else
+  MAGIC=$(od -An -tx1 -N6 $ARCHIVE_PATH | tr -d ' \n')
 fi
 
-if [ "$DISTRO" = "debian" ]; then
+CODEC_FLAG=
+case "$MAGIC" in
+  1f8b*) CODEC_FLAG=-z ;;
+  28b52ffd*) CODEC_FLAG=--zstd ;;
+  fd377a585a00) CODEC_FLAG=-J ;;
+esac
 
-  if [ "$OVERWRITE" = "false" ]; then
-    SKIP_OLD_FILES_FLAG=--skip-old-files
-  fi
+# flags of tar applying the metadata listed in $PRESERVE, symlinks and
+# special files are kept
+TAR_FLAGS=()
+preserves() {
+  [[ ",$PRESERVE," == *",$1,"* ]]
+}
+if preserves modes; then
+  TAR_FLAGS+=(--same-permissions)
+else
+  TAR_FLAGS+=(--no-same-permissions)
+fi
+if ! preserves mtimes; then
+  TAR_FLAGS+=(--touch)
+fi
+if preserves owners; then
+  TAR_FLAGS+=(--same-owner)
+else
+  TAR_FLAGS+=(--no-same-owner)
+fi
+if preserves xattrs; then
+  TAR_FLAGS+=(--xattrs "--xattrs-include=*")
+fi
 
-  tar $SKIP_OLD_FILES_FLAG -x $GZIP_FLAG -f $ARCHIVE_PATH -C $EXTRACT_PATH
+# extracts the archive to $1
+extract_to() {
+  if [ "$ARCHIVE_PATH" = "-" ]; then
+    cat $MAGIC_PATH - | tar -x $CODEC_FLAG "${TAR_FLAGS[@]}" -f - -C "$1"
+  else
+    tar -x $CODEC_FLAG "${TAR_FLAGS[@]}" -f $ARCHIVE_PATH -C "$1"
+  fi
+}
 
-  rm -rf ARCHIVE_PATH
+# checks the files extracted to $1 against the sha256 manifest shipped
+# with the archive, the callers of this interface always ship one so an
+# archive without it is truncated or tampered with
+verify_manifest() {
+  local DIR=$1
+  if [ -z "$MANIFEST" ] || [ ! -f "$DIR/$MANIFEST" ]; then
+    echo "integrity::missing::$MANIFEST" >&2
+    return 87
+  fi
 
-elif [ "$DISTRO" = "alpine" ]; then
+  # only the lines of the failed files are printed, ending with ": FAILED"
+  # or ": FAILED open or read"
+  FAILED=$(cd "$DIR" && sha256sum -c --quiet "$MANIFEST" 2>/dev/null | sed -E 's/: FAILED( open or read)?$//' | tr '\n' ',' | sed 's/,$//')
+  rm -f "$DIR/$MANIFEST"
 
-  if [ "$OVERWRITE" = "true" ]; then
-    tar -x $GZIP_FLAG -f $ARCHIVE_PATH -C $EXTRACT_PATH
-  else
-    TMP_PATH=/app/data/temp/$ARCHIVE_NANE-tmp
-    #echo $TMP_PATH
-    mkdir $TMP_PATH
-    #echo tar -x $GZIP_FLAG -f $ARCHIVE_PATH -C $TMP_PATH
-    tar -x $GZIP_FLAG -f $ARCHIVE_PATH -C $TMP_PATH
-    #echo rsync -r --ignore-existing $TMP_PATH/* $EXTRACT_PATH
-    rsync -r --ignore-existing $TMP_PATH/* $EXTRACT_PATH
-    rm -rf $TMP_PATH
+  if [ -n "$FAILED" ]; then
+    echo "integrity::mismatch::$FAILED" >&2
+    return 87
   fi
+}
 
-  rm -rf ARCHIVE_PATH
+# moves the entries of the directory $1 into the directory $2 by renaming
+# them, directories existing on both sides are merged and the other
+# existing entries are only replaced if $CLOBBER
+move_entries() {
+  local SRC=$1 DST=$2 ENTRY TARGET
+  for ENTRY in "$SRC"/* "$SRC"/.*; do
+    case "${ENTRY##*/}" in
+      .|..) continue ;;
+    esac
+    if [ ! -e "$ENTRY" ] && [ ! -L "$ENTRY" ]; then
+      continue
+    fi
+    TARGET=$DST/${ENTRY##*/}
+    if [ ! -e "$TARGET" ] && [ ! -L "$TARGET" ]; then
+      mv "$ENTRY" "$TARGET" || return 1
+    elif [ -d "$ENTRY" ] && [ ! -L "$ENTRY" ] && [ -d "$TARGET" ] && [ ! -L "$TARGET" ]; then
+      move_entries "$ENTRY" "$TARGET" || return 1
+      # the merged directory takes the metadata shipped with the archive
+      if preserves modes; then
+        chmod "$(stat -c %a "$ENTRY")" "$TARGET"
+      fi
+      if preserves owners; then
+        chown "$(stat -c %u:%g "$ENTRY")" "$TARGET" 2>/dev/null
+      fi
+      if preserves mtimes; then
+        touch -r "$ENTRY" "$TARGET"
+      fi
+    elif [ "$CLOBBER" = "true" ]; then
+      rm -rf "$TARGET" && mv "$ENTRY" "$TARGET" || return 1
+    fi
+  done
+}
 
+# extract to a hidden dir in the destination first, so the archive can be
+# verified before any existing file is replaced, then move the entries in
+# place, which only renames them as both are on the same file system
+STAGE_PATH=$(mktemp -d "${EXTRACT_PATH%/}/.webscp-extract-XXXXXX")
+EXIT_CODE=$?
+if [ $EXIT_CODE -eq 0 ]; then
+  extract_to "$STAGE_PATH"
+  EXIT_CODE=$?
+fi
+if [ $EXIT_CODE -eq 0 ]; then
+  verify_manifest "$STAGE_PATH"
+  EXIT_CODE=$?
+fi
+# existing files are overwritten regardless of any conflict strategies if
+# $OVERWRITE, otherwise the strategies are applied to the extracted files
+# which exist in the destination and the remaining files then replace the
+# existing ones
+CLOBBER=$OVERWRITE
+if [ -n "$CONFLICTS" ] && [ -f "$STAGE_PATH/$CONFLICTS" ]; then
+  mv "$STAGE_PATH/$CONFLICTS" "$STAGE_PATH.conflicts"
+  if [ $EXIT_CODE -eq 0 ] && [ "$OVERWRITE" != "true" ]; then
+    "$CLI" local-resolve-conflicts "$STAGE_PATH" "$EXTRACT_PATH" "$STAGE_PATH.conflicts"
+    EXIT_CODE=$?
+    CLOBBER=true
+  fi
+  rm -f "$STAGE_PATH.conflicts"
 fi
+# do not move a partially extracted or corrupted archive in place
+if [ $EXIT_CODE -eq 0 ]; then
+  move_entries "$STAGE_PATH" "${EXTRACT_PATH%/}"
+  EXIT_CODE=$?
+fi
+if [ -n "$STAGE_PATH" ]; then
+  rm -rf "$STAGE_PATH"
+fi
+
+if [ "$ARCHIVE_PATH" != "-" ]; then
+  rm -rf $ARCHIVE_PATH
+else
+  rm -f $MAGIC_PATH
+fi
+
+exit $EXIT_CODE
@@ -1,19 +1,40 @@
 use flate2::{write::GzEncoder, Compression};
-use rocket::tokio::task;
+use rocket::{
+    serde::{json::serde_json, Deserialize, Serialize},
+    tokio::task,
+};
+use sha2::{Digest, Sha256};
 use std::{
+    fmt,
+    fmt::{Display, Formatter},
     fs,
-    fs::File,
-    io::{Error, ErrorKind::Interrupted},
+    fs::{File, Metadata},
+    io,
+    io::{stdout, Error, ErrorKind::Interrupted, Read, Write},
     ops::Deref,
+    os::unix::fs::FileTypeExt,
     path::Path,
-    sync::{Arc, Mutex},
-    time::{Duration, Instant},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use tar::Builder;
+use tar::{Builder, EntryType, Header, HeaderMode};
 use urlencoding::decode;
+use xz2::write::XzEncoder;
 
-use crate::files_api::{FilesApi, Transfer};
+use crate::{
+    conflict::ConflictRule,
+    files_api::{FilesApi, Transfer},
+    filter::{join_path, SkipFilter, SkipRules},
+    sync::{is_unchanged, DestinationListing},
+    transfer_store::TransferStore,
+};
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
 pub struct ArchiveItem {
     pub source: String,
     pub destination: String,
@@ -25,21 +46,492 @@ pub struct ArchiveError {
     pub message: String,
 }
 
+/// ProgressSink determines where an `ArchiveWriter` reports its progress.
+/// When archiving locally for an upload, progress updates are sent to Files
+/// on behalf of the transfer and recorded in the transfer store. When
+/// running on behalf of another agent, e.g. archiving for a download, status
+/// updates are written to stdout and relayed back over SSH.
+#[derive(Clone)]
+#[allow(clippy::large_enum_variant)]
+pub enum ProgressSink {
+    Files(Transfer, TransferStore),
+    Stdout(Arc<Mutex<bool>>),
+}
+
+impl ProgressSink {
+    pub async fn send(&self, message: &str) {
+        match self {
+            ProgressSink::Files(transfer, store) => {
+                store.record(&transfer.transfer_id, message);
+                FilesApi::new()
+                    .send_upload_status_update_async(transfer, message)
+                    .await;
+            }
+            ProgressSink::Stdout(_) => self.print(message),
+        }
+    }
+
+    fn print(&self, message: &str) {
+        if let ProgressSink::Stdout(cancel_requested) = self {
+            // the reading end of stdout going away means the transfer was
+            // cancelled on the other side, so set the 'cancel requested' flag
+            let mut out = stdout();
+            if writeln!(out, "{}", message).is_err() || out.flush().is_err() {
+                *cancel_requested.lock().unwrap() = true;
+            }
+        }
+    }
+}
+
+/// Returns the name of the integrity manifest added to the archive of a
+/// transfer, see `ArchiveWriter::write_manifest`.
+pub fn get_manifest_name(archive_name: &str) -> String {
+    format!(".webscp-{}.sha256", archive_name)
+}
+
+/// Returns the name of the file listing the conflict strategies added to the
+/// archive of a transfer, see `ArchiveWriter::write_conflict_rules`.
+pub fn get_conflicts_name(archive_name: &str) -> String {
+    format!(".webscp-{}.conflicts", archive_name)
+}
+
+/// At most this many bytes are read from the sources, and from each file, to
+/// estimate how well the archive compresses.
+pub const COMPRESSION_SAMPLE_SIZE: usize = 1024 * 1024;
+pub const COMPRESSION_SAMPLE_SIZE_PER_FILE: usize = 64 * 1024;
+
+/// `auto` compression picks the fastest codec up to this ping latency and
+/// the strongest one above the slow latency.
+const AUTO_COMPRESSION_LAN_LATENCY_MS: u64 = 2;
+const AUTO_COMPRESSION_SLOW_LATENCY_MS: u64 = 100;
+
+/// `auto` compression leaves data whose sample compresses to more than this
+/// part of its size uncompressed.
+const AUTO_COMPRESSION_MAX_RATIO: f64 = 0.9;
+
+/// ArchiveOutput is the destination an `ArchiveWriter` writes the archive
+/// to, e.g. a local file or the stdin of a remote command.
+pub type ArchiveOutput = Box<dyn Write + Send>;
+
+/// ArchiveOptions controls which files an `ArchiveWriter` adds to the archive.
+#[derive(Clone, Default)]
+pub struct ArchiveOptions {
+    /// When set, files already present in the destination with the same
+    /// size and mtime (or digest) are left out of the archive.
+    pub destination_listing: Option<DestinationListing>,
+    pub metadata: MetadataPolicy,
+    pub skip_rules: SkipRules,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum SymlinkPolicy {
+    /// archive the file or directory a link points to
+    #[default]
+    Follow,
+    /// archive the link itself
+    Keep,
+}
+
+/// MetadataPolicy determines which file metadata a copy preserves, both
+/// when archiving the sources and when extracting the archive.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde", default)]
+pub struct MetadataPolicy {
+    pub symlinks: SymlinkPolicy,
+    pub empty_dirs: bool,
+    pub special_files: bool,
+    pub modes: bool,
+    pub mtimes: bool,
+    pub owners: bool,
+    pub xattrs: bool,
+}
+
+impl Default for MetadataPolicy {
+    fn default() -> Self {
+        Self {
+            symlinks: SymlinkPolicy::Follow,
+            empty_dirs: false,
+            special_files: false,
+            modes: true,
+            mtimes: true,
+            owners: false,
+            xattrs: false,
+        }
+    }
+}
+
+impl MetadataPolicy {
+    /// Returns the argument of the extract script listing the metadata to
+    /// apply to the extracted files.
+    pub fn get_extract_arg(&self) -> String {
+        let preserved: Vec<&str> = [
+            (self.modes, "modes"),
+            (self.mtimes, "mtimes"),
+            (self.owners, "owners"),
+            (self.xattrs, "xattrs"),
+        ]
+        .iter()
+        .filter(|(keep, _)| *keep)
+        .map(|(_, name)| *name)
+        .collect();
+
+        match preserved.is_empty() {
+            true => "none".to_string(),
+            false => preserved.join(","),
+        }
+    }
+}
+
+/// Codec is the compression format of an archive. `Auto` is resolved to one
+/// of the others before archiving, see `choose_compression`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Codec {
+    None,
+    Gzip,
+    Zstd,
+    Xz,
+    Auto,
+}
+
+impl Codec {
+    fn name(&self) -> &'static str {
+        match self {
+            Codec::None => "none",
+            Codec::Gzip => "gzip",
+            Codec::Zstd => "zstd",
+            Codec::Xz => "xz",
+            Codec::Auto => "auto",
+        }
+    }
+
+    /// Returns the file extension of the archives compressed with the codec.
+    pub fn get_extension(&self) -> &'static str {
+        match self {
+            Codec::None | Codec::Auto => "tar",
+            Codec::Gzip => "tar.gz",
+            Codec::Zstd => "tar.zst",
+            Codec::Xz => "tar.xz",
+        }
+    }
+
+    fn get_level_range(&self) -> (u32, u32) {
+        match self {
+            Codec::Gzip => (0, 9),
+            Codec::Zstd => (1, 19),
+            Codec::Xz => (0, 9),
+            Codec::None | Codec::Auto => (0, 0),
+        }
+    }
+
+    fn get_default_level(&self) -> u32 {
+        match self {
+            // the level archives were always compressed with
+            Codec::Gzip => 1,
+            Codec::Zstd => 3,
+            Codec::Xz => 6,
+            Codec::None | Codec::Auto => 0,
+        }
+    }
+}
+
+/// ArchiveCompression is the codec, and optionally the level, an archive is
+/// compressed with. It is written as `<codec>[:<level>]`, e.g. "zstd:3" or
+/// "none". For backward compatibility `true` stands for gzip at its fastest
+/// level and `false` for no compression.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(
+    crate = "rocket::serde",
+    try_from = "CompressionValue",
+    into = "String"
+)]
+pub struct ArchiveCompression {
+    pub codec: Codec,
+    pub level: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde", untagged)]
+enum CompressionValue {
+    Flag(bool),
+    Spec(String),
+}
+
+impl ArchiveCompression {
+    pub fn new(codec: Codec, level: u32) -> Self {
+        Self {
+            codec,
+            level: Some(level),
+        }
+    }
+
+    pub fn none() -> Self {
+        Self {
+            codec: Codec::None,
+            level: None,
+        }
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        !matches!(self.codec, Codec::None)
+    }
+
+    /// Returns the level to compress with, the codec's default if not set.
+    pub fn get_level(&self) -> u32 {
+        self.level.unwrap_or_else(|| self.codec.get_default_level())
+    }
+}
+
+impl Default for ArchiveCompression {
+    fn default() -> Self {
+        Self::none()
+    }
+}
+
+impl Display for ArchiveCompression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.codec {
+            Codec::None | Codec::Auto => write!(f, "{}", self.codec.name()),
+            _ => write!(f, "{}:{}", self.codec.name(), self.get_level()),
+        }
+    }
+}
+
+impl FromStr for ArchiveCompression {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (name, level) = match value.split_once(':') {
+            Some((name, level)) => match level.parse::<u32>() {
+                Ok(l) => (name, Some(l)),
+                Err(_) => return Err(format!("Invalid compression level {}", level)),
+            },
+            None => (value, None),
+        };
+        let codec = match name {
+            "none" | "false" => Codec::None,
+            "gzip" => Codec::Gzip,
+            "true" if level.is_none() => return Ok(Self::new(Codec::Gzip, 1)),
+            "true" => return Err(format!("Compression {} has no levels", name)),
+            "zstd" => Codec::Zstd,
+            "xz" => Codec::Xz,
+            "auto" => Codec::Auto,
+            _ => return Err(format!("Unknown compression codec {}", name)),
+        };
+
+        let (min, max) = codec.get_level_range();
+        match level {
+            Some(_) if max == 0 => Err(format!("Compression {} has no levels", name)),
+            Some(l) if l < min || l > max => Err(format!(
+                "Compression level of {} must be between {} and {}",
+                name, min, max
+            )),
+            _ => Ok(Self { codec, level }),
+        }
+    }
+}
+
+impl TryFrom<CompressionValue> for ArchiveCompression {
+    type Error = String;
+
+    fn try_from(value: CompressionValue) -> Result<Self, Self::Error> {
+        match value {
+            CompressionValue::Flag(true) => Ok(Self::new(Codec::Gzip, 1)),
+            CompressionValue::Flag(false) => Ok(Self::none()),
+            CompressionValue::Spec(spec) => spec.parse(),
+        }
+    }
+}
+
+impl From<ArchiveCompression> for String {
+    fn from(compression: ArchiveCompression) -> Self {
+        compression.to_string()
+    }
+}
+
+/// Wraps `output` in the encoder of the codec of `compression`. Encoders
+/// write the end of the compressed stream when dropped.
+pub fn get_encoder(
+    output: ArchiveOutput,
+    compression: ArchiveCompression,
+) -> Result<ArchiveOutput, Error> {
+    let level = compression.get_level();
+    let encoder: ArchiveOutput = match compression.codec {
+        Codec::None => output,
+        // 'auto' should have been resolved by now, fall back to the old default
+        Codec::Gzip | Codec::Auto => Box::new(GzEncoder::new(output, Compression::new(level))),
+        Codec::Zstd => Box::new(zstd::Encoder::new(output, level as i32)?.auto_finish()),
+        Codec::Xz => Box::new(XzEncoder::new(output, level)),
+    };
+
+    Ok(encoder)
+}
+
+/// Picks the codec for an `auto` transfer. Over a fast network compressing
+/// costs more time than it saves, so a fast codec is chosen, slower links
+/// get stronger compression. Data that hardly compresses, judged by the
+/// `ratio` of a sample (compressed / original size), is not compressed.
+pub fn choose_compression(latency_ms: Option<u64>, ratio: Option<f64>) -> ArchiveCompression {
+    if matches!(ratio, Some(r) if r > AUTO_COMPRESSION_MAX_RATIO) {
+        return ArchiveCompression::none();
+    }
+
+    match latency_ms {
+        Some(l) if l <= AUTO_COMPRESSION_LAN_LATENCY_MS => ArchiveCompression::new(Codec::Zstd, 1),
+        Some(l) if l > AUTO_COMPRESSION_SLOW_LATENCY_MS => ArchiveCompression::new(Codec::Xz, 6),
+        _ => ArchiveCompression::new(Codec::Zstd, 9),
+    }
+}
+
+/// Reads a sample of the sources of `items` of at most
+/// `COMPRESSION_SAMPLE_SIZE` bytes, taking up to
+/// `COMPRESSION_SAMPLE_SIZE_PER_FILE` bytes from each file.
+pub fn sample_sources(source_base_path: &str, items: &[ArchiveItem]) -> Vec<u8> {
+    let mut sample = Vec::new();
+
+    for item in items.iter() {
+        let src_ = decode(&item.source).unwrap().into_owned();
+        let src = src_.replacen("/files", source_base_path, 1);
+        add_to_sample(&mut sample, Path::new(&src));
+    }
+
+    sample
+}
+
+/// Returns the total size of the regular files under the sources of `items`,
+/// the size of the archive of a streamed transfer before compression.
+pub fn get_sources_size(source_base_path: &str, items: &[ArchiveItem]) -> u64 {
+    items
+        .iter()
+        .map(|item| {
+            let src_ = decode(&item.source).unwrap().into_owned();
+            let src = src_.replacen("/files", source_base_path, 1);
+            get_size(Path::new(&src))
+        })
+        .sum()
+}
+
+fn get_size(src_path: &Path) -> u64 {
+    let meta = match src_path.metadata() {
+        Ok(m) => m,
+        Err(_) => return 0,
+    };
+
+    if meta.is_dir() {
+        return match fs::read_dir(src_path) {
+            Ok(entries) => entries.flatten().map(|e| get_size(&e.path())).sum(),
+            Err(_) => 0,
+        };
+    }
+
+    match meta.file_type().is_file() {
+        true => meta.len(),
+        false => 0,
+    }
+}
+
+/// Appends up to `COMPRESSION_SAMPLE_SIZE_PER_FILE` bytes of `file` to
+/// `sample` until it reaches `COMPRESSION_SAMPLE_SIZE`.
+pub fn read_sample(sample: &mut Vec<u8>, file: &mut File) {
+    let remaining = COMPRESSION_SAMPLE_SIZE.saturating_sub(sample.len());
+    let limit = remaining.min(COMPRESSION_SAMPLE_SIZE_PER_FILE) as u64;
+    let _ = file.take(limit).read_to_end(sample);
+}
+
+/// Returns the size of `sample` compressed with `compression` relative to
+/// its original size.
+pub fn get_compression_ratio(sample: &[u8], compression: ArchiveCompression) -> Option<f64> {
+    if sample.is_empty() {
+        return None;
+    }
+
+    let compressed_size = Arc::new(AtomicUsize::new(0));
+    let output = Box::new(CountingOutput(compressed_size.clone()));
+    let mut encoder = get_encoder(output, compression).ok()?;
+    encoder.write_all(sample).ok()?;
+    drop(encoder);
+
+    Some(compressed_size.load(Ordering::Relaxed) as f64 / sample.len() as f64)
+}
+
+fn add_to_sample(sample: &mut Vec<u8>, src_path: &Path) {
+    if sample.len() >= COMPRESSION_SAMPLE_SIZE {
+        return;
+    }
+    let meta = match src_path.metadata() {
+        Ok(m) => m,
+        Err(_) => return,
+    };
+
+    if meta.is_dir() {
+        let entries = match fs::read_dir(src_path) {
+            Ok(e) => e,
+            Err(_) => return,
+        };
+        for entry in entries.flatten() {
+            add_to_sample(sample, &entry.path());
+        }
+        return;
+    }
+
+    if meta.file_type().is_file() {
+        if let Ok(mut file) = File::open(src_path) {
+            read_sample(sample, &mut file);
+        }
+    }
+}
+
+/// HashingReader passes through what is read from `inner` while computing
+/// its sha256 digest, so that a file is hashed as it is added to the archive.
+struct HashingReader<R: Read> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// CountingOutput discards what is written to it, only counting the bytes.
+struct CountingOutput(Arc<AtomicUsize>);
+
+impl Write for CountingOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.fetch_add(buf.len(), Ordering::Relaxed);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 pub struct ArchiveWriter {
-    compress: bool,
-    file_path: String,
-    gzip_writer: Option<Builder<GzEncoder<File>>>,
-    tar_writer: Option<Builder<File>>,
+    compression: ArchiveCompression,
+    file_path: Option<String>,
+    tar_writer: Builder<ArchiveOutput>,
     source_base_path: String,
     progress: ProgressCounter,
     last_update_sent_at: Instant,
     cancel_requested: Arc<Mutex<bool>>,
+    progress_sink: ProgressSink,
+    digests: Vec<(String, String)>,
+    options: ArchiveOptions,
+    filter: SkipFilter,
+    entries_left_out: usize,
+    incomplete_items: Vec<String>,
 }
 
 pub struct ProgressCounter {
     items_total: usize,
     items_added: usize,
     files_added: usize,
+    files_skipped: usize,
+    files_excluded: usize,
 }
 
 impl Clone for ProgressCounter {
@@ -48,6 +540,8 @@ impl Clone for ProgressCounter {
             items_total: self.items_total,
             items_added: self.items_added,
             files_added: self.files_added,
+            files_skipped: self.files_skipped,
+            files_excluded: self.files_excluded,
         }
     }
 }
@@ -55,9 +549,11 @@ impl Clone for ProgressCounter {
 impl ArchiveWriter {
     pub fn new(
         archive_path: &str,
-        compress: bool,
+        compression: ArchiveCompression,
         source_base_path: &str,
         cancel_requested: Arc<Mutex<bool>>,
+        progress_sink: ProgressSink,
+        options: ArchiveOptions,
     ) -> Result<Self, ArchiveError> {
         let file = match File::create(archive_path) {
             Ok(f) => f,
@@ -69,47 +565,96 @@ impl ArchiveWriter {
             }
         };
 
-        if compress {
-            let enc = GzEncoder::new(file, Compression::fast());
-            let writer = Builder::new(enc);
-            Ok(Self {
-                compress,
-                file_path: archive_path.to_string(),
-                tar_writer: None,
-                gzip_writer: Some(writer),
-                source_base_path: String::from(source_base_path),
-                progress: ProgressCounter {
-                    items_total: 0,
-                    items_added: 0,
-                    files_added: 0,
-                },
-                last_update_sent_at: Instant::now(),
-                cancel_requested,
-            })
-        } else {
-            let writer = Builder::new(file);
-            Ok(Self {
-                compress,
-                file_path: archive_path.to_string(),
-                tar_writer: Some(writer),
-                gzip_writer: None,
-                source_base_path: String::from(source_base_path),
-                progress: ProgressCounter {
-                    items_total: 0,
-                    items_added: 0,
-                    files_added: 0,
-                },
-                last_update_sent_at: Instant::now(),
-                cancel_requested,
-            })
+        let mut writer = Self::from_output(
+            Box::new(file),
+            compression,
+            source_base_path,
+            cancel_requested,
+            progress_sink,
+            options,
+        )?;
+        writer.file_path = Some(archive_path.to_string());
+
+        Ok(writer)
+    }
+
+    /// Creates an `ArchiveWriter` that writes the archive to `output`
+    /// instead of a local file.
+    pub fn from_output(
+        output: ArchiveOutput,
+        compression: ArchiveCompression,
+        source_base_path: &str,
+        cancel_requested: Arc<Mutex<bool>>,
+        progress_sink: ProgressSink,
+        options: ArchiveOptions,
+    ) -> Result<Self, ArchiveError> {
+        let output = match get_encoder(output, compression) {
+            Ok(o) => o,
+            Err(e) => {
+                return Err(ArchiveError {
+                    code: 304,
+                    message: format!("Cannot create {} encoder: {}", compression, e),
+                });
+            }
+        };
+        let filter = match SkipFilter::new(&options.skip_rules) {
+            Ok(f) => f,
+            Err(e) => {
+                return Err(ArchiveError {
+                    code: 305,
+                    message: e,
+                })
+            }
+        };
+
+        Ok(Self {
+            compression,
+            file_path: None,
+            tar_writer: Builder::new(output),
+            source_base_path: String::from(source_base_path),
+            progress: ProgressCounter {
+                items_total: 0,
+                items_added: 0,
+                files_added: 0,
+                files_skipped: 0,
+                files_excluded: 0,
+            },
+            last_update_sent_at: Instant::now(),
+            cancel_requested,
+            progress_sink,
+            digests: Vec::new(),
+            options,
+            filter,
+            entries_left_out: 0,
+            incomplete_items: Vec::new(),
+        })
+    }
+
+    /// Returns the number of files left out of the archive because they
+    /// are unchanged in the destination, if a destination listing was given.
+    pub fn files_skipped(&self) -> Option<usize> {
+        self.options
+            .destination_listing
+            .as_ref()
+            .map(|_| self.progress.files_skipped)
+    }
+
+    /// Returns the number of files and directories left out of the archive
+    /// by the skip rules, if there are any.
+    pub fn files_excluded(&self) -> Option<usize> {
+        match self.filter.is_active() {
+            true => Some(self.progress.files_excluded),
+            false => None,
         }
     }
 
-    pub async fn crate_archive<'a>(
-        &'a mut self,
-        items: Vec<ArchiveItem>,
-        transfer: &'a Transfer,
-    ) -> Result<(), ArchiveError> {
+    /// Returns the sources of the items of which some entries were left out
+    /// of the archive, by the skip rules or as unsupported file types.
+    pub fn incomplete_items(&self) -> &[String] {
+        &self.incomplete_items
+    }
+
+    pub async fn crate_archive(&mut self, items: Vec<ArchiveItem>) -> Result<(), ArchiveError> {
         self.progress.items_total = items.len();
 
         // loop through submitted items adding each to the archive
@@ -119,8 +664,9 @@ impl ArchiveWriter {
             let src = src_.replacen("/files", &self.source_base_path, 1);
             let dst = String::from(dst_.trim_start_matches('/'));
             self.progress.items_added += 1;
+            let entries_left_out = self.entries_left_out;
 
-            let res = match self.add_file_to_archive(src.clone(), dst, transfer) {
+            let res = match self.add_file_to_archive(src.clone(), dst, "") {
                 Ok(_) => Ok::<(), Error>(()),
                 Err(e) => {
                     // abort archive operation on user request
@@ -140,6 +686,9 @@ impl ArchiveWriter {
                     message: err.to_string(),
                 });
             }
+            if self.entries_left_out > entries_left_out {
+                self.incomplete_items.push(item.source.clone());
+            }
 
             task::yield_now().await;
 
@@ -151,39 +700,104 @@ impl ArchiveWriter {
                 self.progress.items_total,
                 self.progress.files_added,
             );
-            FilesApi::new()
-                .send_upload_status_update_async(transfer, msg)
-                .await;
+            self.progress_sink.send(msg).await;
         }
 
         Ok(())
     }
 
+    /// Adds a manifest named `name` to the archive listing the SHA-256
+    /// digest of each file added, in the format of `sha256sum`. The remote
+    /// checks the extracted files against it before reporting completion.
+    pub fn write_manifest(&mut self, name: &str) -> Result<(), ArchiveError> {
+        let manifest: String = self
+            .digests
+            .iter()
+            .map(|(digest, path)| format!("{}  {}\n", digest, path))
+            .collect();
+
+        self.append_file(name, manifest.as_bytes())
+    }
+
+    /// Adds a file named `name` to the archive listing the conflict
+    /// strategies of the items, which the remote applies to the files
+    /// already present in the destination.
+    pub fn write_conflict_rules(
+        &mut self,
+        name: &str,
+        rules: &[ConflictRule],
+    ) -> Result<(), ArchiveError> {
+        let content = serde_json::to_string(rules).unwrap();
+
+        self.append_file(name, content.as_bytes())
+    }
+
+    fn append_file(&mut self, name: &str, content: &[u8]) -> Result<(), ArchiveError> {
+        let mut header = Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o600);
+        header.set_mtime(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        );
+        header.set_cksum();
+
+        let res = self.tar_writer.append_data(&mut header, name, content);
+
+        res.map_err(|e| {
+            self.remove_archive();
+            ArchiveError {
+                code: 303,
+                message: e.to_string(),
+            }
+        })
+    }
+
     fn remove_archive(&self) {
-        let _ = fs::remove_file(&self.file_path);
+        if let Some(file_path) = &self.file_path {
+            let _ = fs::remove_file(file_path);
+        }
     }
 
     fn get_job_type(&self) -> &'static str {
-        match self.compress {
+        match self.compression.is_compressed() {
             true => "compressed",
             false => "archived",
         }
     }
 
+    /// Adds the file or directory at `src` to the archive as `path`, where
+    /// `item_path` is its path relative to the item being archived.
     fn add_file_to_archive(
         &mut self,
         src: String,
         path: String,
-        transfer: &Transfer,
+        item_path: &str,
     ) -> Result<(), Error> {
+        let policy = self.options.metadata.clone();
         let src_path = Path::new(src.as_str());
-        let src_meta = match src_path.metadata() {
-            Ok(m) => m,
-            Err(e) => return Err(e),
+        let src_meta = match policy.symlinks {
+            SymlinkPolicy::Keep => src_path.symlink_metadata()?,
+            SymlinkPolicy::Follow => src_path.metadata()?,
         };
 
+        // skip entries matching the skip rules, but not the item itself
+        if !item_path.is_empty() && self.filter.is_skipped(item_path, src_meta.is_dir()) {
+            self.progress.files_excluded += 1;
+            self.entries_left_out += 1;
+            return Ok(());
+        }
+
         if src_meta.is_dir() {
+            // add the directory itself so that it is created even if empty
+            if policy.empty_dirs {
+                self.append_entry(&src_meta, src_path, &path, None)?;
+            }
+
             // walk path and recurse on items
+            self.filter.enter_dir(src_path, item_path);
             for item in fs::read_dir(src_path)? {
                 let item = item?;
                 let item_fn = item
@@ -193,15 +807,26 @@ impl ArchiveWriter {
                     .to_os_string()
                     .into_string()
                     .unwrap();
-                let item_path = item.path().into_os_string().into_string().unwrap();
+                let item_src = item.path().into_os_string().into_string().unwrap();
                 let item_dst = format!("{}/{}", path, item_fn);
 
-                self.add_file_to_archive(item_path, item_dst, transfer)?;
+                self.add_file_to_archive(item_src, item_dst, &join_path(item_path, &item_fn))?;
             }
+            self.filter.leave_dir();
         }
 
-        // skip non regular files
-        if !src_meta.file_type().is_file() {
+        // skip non regular files unless the policy keeps them
+        let file_type = src_meta.file_type();
+        let is_special =
+            file_type.is_fifo() || file_type.is_char_device() || file_type.is_block_device();
+        let link_target = match file_type.is_symlink() {
+            true => Some(fs::read_link(src_path)?),
+            false => None,
+        };
+        if !file_type.is_file() && link_target.is_none() && !(is_special && policy.special_files) {
+            if !src_meta.is_dir() {
+                self.entries_left_out += 1;
+            }
             return Ok(());
         }
 
@@ -210,22 +835,20 @@ impl ArchiveWriter {
         if *do_cancel.deref() {
             return Err(Error::new(Interrupted, "Operation aborted by user request"));
         }
+        drop(do_cancel);
+
+        // skip files which are unchanged in the destination
+        if let Some(listing) = &self.options.destination_listing {
+            if file_type.is_file() && is_unchanged(listing, &path, src_path) {
+                self.progress.files_skipped += 1;
+                return Ok(());
+            }
+        }
 
         // try adding the file
         let src_copy = src.clone();
-        let res = match self.compress {
-            true => self
-                .gzip_writer
-                .as_mut()
-                .unwrap()
-                .append_path_with_name(src, path),
-
-            false => self
-                .tar_writer
-                .as_mut()
-                .unwrap()
-                .append_path_with_name(src, path),
-        };
+        let path_copy = path.clone();
+        let res = self.append_entry(&src_meta, src_path, &path, link_target.as_deref());
 
         if let Err(err) = res {
             self.remove_archive();
@@ -233,30 +856,215 @@ impl ArchiveWriter {
             let err_msg = format!("{} {}", err, src_copy);
             Err(Error::new(err.kind(), err_msg))
         } else {
+            // record the digest of the file for the manifest
+            if let Ok(Some(digest)) = res {
+                self.digests.push((digest, path_copy));
+            }
             self.progress.files_added += 1;
 
             // send progress updates not more frequently than one in every 3 seconds
             if self.last_update_sent_at.elapsed() > Duration::from_secs(3) {
                 self.last_update_sent_at = Instant::now();
 
-                let transfer_copy = transfer.clone();
+                let progress_sink = self.progress_sink.clone();
                 let update_type = self.get_job_type();
                 let progress = self.progress.clone();
-                task::spawn(async move {
-                    let msg = &format!(
-                        "progress::{}::{}/{}/{}",
-                        update_type,
-                        progress.items_added,
-                        progress.items_total,
-                        progress.files_added,
-                    );
-                    FilesApi::new()
-                        .send_upload_status_update_async(&transfer_copy, msg)
-                        .await;
-                });
+                let msg = format!(
+                    "progress::{}::{}/{}/{}",
+                    update_type, progress.items_added, progress.items_total, progress.files_added,
+                );
+                match progress_sink {
+                    ProgressSink::Files(..) => {
+                        task::spawn(async move {
+                            progress_sink.send(&msg).await;
+                        });
+                    }
+                    ProgressSink::Stdout(_) => progress_sink.print(&msg),
+                }
             }
 
             Ok(())
         }
     }
+
+    /// Appends the entry of the file, directory, link or special file at
+    /// `src_path` to the archive as `path`, preceded by its extended
+    /// attributes if the policy keeps them. Modes, mtimes and owners are
+    /// always recorded, the extract step decides which of them to apply.
+    /// Returns the digest of the content of a regular file.
+    fn append_entry(
+        &mut self,
+        meta: &Metadata,
+        src_path: &Path,
+        path: &str,
+        link_target: Option<&Path>,
+    ) -> Result<Option<String>, Error> {
+        let xattrs = match self.options.metadata.xattrs {
+            true => get_xattrs(src_path, self.options.metadata.symlinks),
+            false => Vec::new(),
+        };
+
+        append_entry(
+            &mut self.tar_writer,
+            meta,
+            src_path,
+            path,
+            link_target,
+            &xattrs,
+        )
+    }
+}
+
+fn append_entry<W: Write>(
+    builder: &mut Builder<W>,
+    meta: &Metadata,
+    src_path: &Path,
+    path: &str,
+    link_target: Option<&Path>,
+    xattrs: &[(String, Vec<u8>)],
+) -> Result<Option<String>, Error> {
+    if !xattrs.is_empty() {
+        append_xattrs(builder, xattrs)?;
+    }
+
+    let mut header = Header::new_gnu();
+    header.set_metadata_in_mode(meta, HeaderMode::Complete);
+
+    if let Some(target) = link_target {
+        builder.append_link(&mut header, path, target)?;
+        return Ok(None);
+    }
+    if meta.is_file() {
+        // the content is hashed as it is archived rather than read twice
+        let mut reader = HashingReader {
+            inner: File::open(src_path)?,
+            hasher: Sha256::new(),
+        };
+        builder.append_data(&mut header, path, &mut reader)?;
+        return Ok(Some(format!("{:x}", reader.hasher.finalize())));
+    }
+
+    builder.append_data(&mut header, path, io::empty())?;
+    Ok(None)
+}
+
+/// Appends a PAX extended header holding `xattrs`, which applies to the
+/// entry following it, in the format GNU tar extracts with `--xattrs`.
+fn append_xattrs<W: Write>(
+    builder: &mut Builder<W>,
+    xattrs: &[(String, Vec<u8>)],
+) -> Result<(), Error> {
+    let mut data = Vec::new();
+    for (name, value) in xattrs.iter() {
+        // each record is '<length> SCHILY.xattr.<name>=<value>\n', where the
+        // length includes its own digits
+        let key = format!("SCHILY.xattr.{}", name);
+        let rest = key.len() + value.len() + 3;
+        let mut length = rest + 1;
+        while length != rest + length.to_string().len() {
+            length = rest + length.to_string().len();
+        }
+
+        data.extend_from_slice(format!("{} {}=", length, key).as_bytes());
+        data.extend_from_slice(value);
+        data.push(b'\n');
+    }
+
+    let mut header = Header::new_ustar();
+    header.set_entry_type(EntryType::XHeader);
+    header.set_mode(0o644);
+    header.set_size(data.len() as u64);
+    builder.append_data(&mut header, "././@PaxHeader", data.as_slice())
+}
+
+/// Returns the extended attributes of the file at `src_path`, or of the
+/// file a symlink points to if links are followed.
+fn get_xattrs(src_path: &Path, symlinks: SymlinkPolicy) -> Vec<(String, Vec<u8>)> {
+    let path = match symlinks {
+        SymlinkPolicy::Follow => fs::canonicalize(src_path).unwrap_or(src_path.to_path_buf()),
+        SymlinkPolicy::Keep => src_path.to_path_buf(),
+    };
+    let names = match xattr::list(&path) {
+        Ok(n) => n,
+        Err(_) => return Vec::new(),
+    };
+
+    names
+        .filter_map(|name| {
+            let value = xattr::get(&path, &name).ok().flatten()?;
+            Some((name.to_string_lossy().into_owned(), value))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_compression() {
+        let parse = |value: &str| value.parse::<ArchiveCompression>();
+        assert_eq!(parse("true"), Ok(ArchiveCompression::new(Codec::Gzip, 1)));
+        assert_eq!(parse("false"), Ok(ArchiveCompression::none()));
+        assert_eq!(parse("none"), Ok(ArchiveCompression::none()));
+        assert_eq!(parse("gzip:9"), Ok(ArchiveCompression::new(Codec::Gzip, 9)));
+        assert_eq!(
+            parse("zstd:19"),
+            Ok(ArchiveCompression::new(Codec::Zstd, 19))
+        );
+        assert_eq!(parse("xz:0"), Ok(ArchiveCompression::new(Codec::Xz, 0)));
+        assert_eq!(
+            parse("auto"),
+            Ok(ArchiveCompression {
+                codec: Codec::Auto,
+                level: None
+            })
+        );
+
+        let zstd = parse("zstd").unwrap();
+        assert_eq!(zstd.level, None);
+        assert_eq!(zstd.get_level(), 3);
+        assert_eq!(zstd.to_string(), "zstd:3");
+        assert_eq!(parse("gzip").unwrap().to_string(), "gzip:1");
+    }
+
+    #[test]
+    fn reject_invalid_compression() {
+        for value in [
+            "", "brotli", "GZIP", "true:1", "none:1", "auto:3", "gzip:", "gzip:x", "gzip:-1",
+            "gzip:10", "zstd:0", "zstd:20", "xz:10",
+        ] {
+            assert!(
+                value.parse::<ArchiveCompression>().is_err(),
+                "{value} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn deserialize_compression() {
+        let from_json = |value: &str| serde_json::from_str::<ArchiveCompression>(value);
+        assert_eq!(
+            from_json("true").unwrap(),
+            ArchiveCompression::new(Codec::Gzip, 1)
+        );
+        assert_eq!(from_json("false").unwrap(), ArchiveCompression::none());
+        assert_eq!(
+            from_json("\"zstd:7\"").unwrap(),
+            ArchiveCompression::new(Codec::Zstd, 7)
+        );
+        assert_eq!(from_json("\"auto\"").unwrap().codec, Codec::Auto);
+        assert!(from_json("\"zstd:25\"").is_err());
+        assert!(from_json("\"lz4\"").is_err());
+        assert!(from_json("1").is_err());
+        assert!(from_json("null").is_err());
+    }
+
+    #[test]
+    fn serialize_compression() {
+        let to_json = |compression| serde_json::to_string(&compression).unwrap();
+        assert_eq!(to_json(ArchiveCompression::none()), "\"none\"");
+        assert_eq!(to_json(ArchiveCompression::new(Codec::Xz, 6)), "\"xz:6\"");
+        assert_eq!(to_json("gzip".parse().unwrap()), "\"gzip:1\"");
+    }
 }
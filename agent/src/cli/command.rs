@@ -1,20 +1,52 @@
-use std::process::exit;
+use rocket::{serde::json::serde_json, tokio::runtime::Builder};
+use std::{
+    fs,
+    io::{stdin, Read},
+    path::Path,
+    process::{exit, Command},
+    sync::{Arc, Mutex},
+};
 
 use urlencoding::encode;
 
-use crate::{client::*, constants::*};
+use crate::{
+    archive::{
+        get_conflicts_name, get_manifest_name, ArchiveCompression, ArchiveItem, ArchiveOptions,
+        ArchiveWriter, MetadataPolicy, ProgressSink,
+    },
+    client::*,
+    config::CONFIG,
+    conflict::{resolve_conflicts, ConflictRule},
+    constants::*,
+    files_api::Transfer,
+    filter::SkipRules,
+    keys::{add_authorized_key, remove_authorized_key},
+    sync::{delete_files, list_destination},
+    upload::{archive_and_upload, send_transfer_result},
+};
 
 pub fn command_exchange_keys(client: Client, args: Option<Vec<String>>) {
     let args = args.unwrap();
     if args.len() < 5 {
         eprintln!(
-            "Usage: cli {} <host> <port> <agent_secret>",
+            "Usage: cli {} <host> <port> <agent_secret> [<agent_id> <user_id>]",
             COMMAND_EXCHANGE_KEYS
         );
         exit(136);
     }
     let secret = &args[4];
-    client.exchange_keys(secret);
+    // the key of this agent is given to the remote without an agent id
+    let agent = match (args.get(5), args.get(6)) {
+        (Some(agent_id), Some(user_id)) => match (agent_id.parse(), user_id.parse()) {
+            (Ok(agent_id), Ok(user_id)) => Some((agent_id, user_id)),
+            _ => {
+                eprintln!("Invalid agent id or user id");
+                exit(136);
+            }
+        },
+        _ => None,
+    };
+    client.exchange_keys(secret, agent);
 }
 
 pub fn command_get_remote_resource(client: Client, args: Option<Vec<String>>) {
@@ -130,7 +162,16 @@ pub fn command_get_remote_version(client: Client, _: Option<Vec<String>>) {
 }
 
 pub fn command_ping(client: Client, _: Option<Vec<String>>) {
-    let (connect, exec) = client.ping();
+    let (connect, exec) = match client.ping() {
+        Ok(durations) => durations,
+        Err(e) => {
+            match e.http_code {
+                Some(http_code) => eprint!("{} {}", http_code, e.message),
+                None => eprint!("{}", e.message),
+            }
+            exit(e.code);
+        }
+    };
 
     println!(
         "{{\"connect\": \"{}ms\", \"exec\": \"{}ms\"}}",
@@ -138,6 +179,173 @@ pub fn command_ping(client: Client, _: Option<Vec<String>>) {
     );
 }
 
+pub fn command_get_remote_host_key(client: Client, _: Option<Vec<String>>) {
+    client.get_remote_host_key();
+}
+
+pub fn command_repin_remote_host_key(client: Client, args: Option<Vec<String>>) {
+    let args = args.unwrap();
+    if args.len() < 5 {
+        eprintln!(
+            "Usage: cli {} <host> <port> <fingerprint>",
+            COMMAND_REPIN_REMOTE_HOST_KEY
+        );
+        exit(170);
+    }
+    let fingerprint = &args[4];
+
+    client.repin_remote_host_key(fingerprint);
+}
+
+pub fn command_remote_add_key(client: Client, args: Option<Vec<String>>) {
+    let args = args.unwrap();
+    if args.len() < 6 {
+        eprintln!(
+            "Usage: cli {} <host> <port> <public_key_file> <private_key_file>",
+            COMMAND_REMOTE_ADD_KEY
+        );
+        exit(175);
+    }
+    let public_key_file = &args[4];
+    let private_key_file = &args[5];
+
+    client.add_remote_key(public_key_file, private_key_file);
+}
+
+pub fn command_remote_remove_key(client: Client, args: Option<Vec<String>>) {
+    let args = args.unwrap();
+    if args.len() < 5 {
+        eprintln!(
+            "Usage: cli {} <host> <port> <public_key_file>",
+            COMMAND_REMOTE_REMOVE_KEY
+        );
+        exit(176);
+    }
+    let public_key_file = &args[4];
+
+    client.remove_remote_key(public_key_file);
+}
+
+pub fn command_local_add_authorized_key(_: Client, args: Option<Vec<String>>) {
+    let args = args.unwrap();
+    if args.len() < 4 {
+        eprintln!(
+            "Usage: cli {} <key_type> <key> [comment]",
+            COMMAND_LOCAL_ADD_AUTHORIZED_KEY
+        );
+        exit(171);
+    }
+
+    match add_authorized_key(&args[2], &args[3], args.get(4).map(|c| c.as_str())) {
+        Ok(added) => print!("{added}"),
+        Err(e) => {
+            eprint!("500 Cannot add authorized key: {e}");
+            exit(172);
+        }
+    }
+}
+
+pub fn command_local_remove_authorized_key(_: Client, args: Option<Vec<String>>) {
+    let args = args.unwrap();
+    if args.len() < 3 {
+        eprintln!("Usage: cli {} <key>", COMMAND_LOCAL_REMOVE_AUTHORIZED_KEY);
+        exit(177);
+    }
+
+    match remove_authorized_key(&args[2]) {
+        Ok(removed) => print!("{removed}"),
+        Err(e) => {
+            eprint!("500 Cannot remove authorized key: {e}");
+            exit(172);
+        }
+    }
+}
+
+/// Runs the extract script of this agent with the arguments given, reading
+/// a streamed archive from stdin.
+pub fn command_local_extract_archive(_: Client, args: Option<Vec<String>>) {
+    let args = args.unwrap();
+    if args.len() < 3 {
+        eprintln!(
+            "Usage: cli {} --interface | <interface> <archive_name> ...",
+            COMMAND_LOCAL_EXTRACT_ARCHIVE
+        );
+        exit(182);
+    }
+
+    match Command::new(&CONFIG.extract_archive_script_path)
+        .args(&args[2..])
+        .envs(CONFIG.get_script_env())
+        .status()
+    {
+        Ok(status) => exit(status.code().unwrap_or(182)),
+        Err(e) => {
+            eprint!("500 Cannot run the extract script: {e}");
+            exit(182);
+        }
+    }
+}
+
+/// Prints the path of the archive of a transfer in the temp data dir of this
+/// agent, see `ARCHIVE_SUFFIXES`.
+pub fn command_local_get_archive_path(_: Client, args: Option<Vec<String>>) {
+    let args = args.unwrap();
+    if args.len() < 4 {
+        eprintln!(
+            "Usage: cli {} <archive_name> <suffix>",
+            COMMAND_LOCAL_GET_ARCHIVE_PATH
+        );
+        exit(183);
+    }
+
+    let (archive_name, suffix) = (&args[2], &args[3]);
+    if !is_file_name(archive_name) || !ARCHIVE_SUFFIXES.contains(&suffix.as_str()) {
+        eprint!("400 Invalid archive {archive_name}.{suffix}");
+        exit(183);
+    }
+
+    print!("{}{}.{}", CONFIG.temp_data_dir, archive_name, suffix);
+}
+
+/// Prints the info on the user who created the temporary access token with
+/// the hash given, left for the agents the token is handed to.
+pub fn command_local_get_token_user(_: Client, args: Option<Vec<String>>) {
+    let args = args.unwrap();
+    if args.len() < 3 || !is_file_name(&args[2]) {
+        eprintln!("Usage: cli {} <token_hash>", COMMAND_LOCAL_GET_TOKEN_USER);
+        exit(184);
+    }
+
+    match fs::read_to_string(format!("{}/{}", CONFIG.ssh_dir_path, args[2])) {
+        Ok(user_info) => print!("{user_info}"),
+        Err(e) => {
+            eprint!("404 Unknown access token: {e}");
+            exit(184);
+        }
+    }
+}
+
+/// Removes the file of the temporary access token with the hash given,
+/// which signals that the token was used for pairing and can be revoked.
+pub fn command_local_release_token(_: Client, args: Option<Vec<String>>) {
+    let args = args.unwrap();
+    if args.len() < 3 || !is_file_name(&args[2]) {
+        eprintln!("Usage: cli {} <token_hash>", COMMAND_LOCAL_RELEASE_TOKEN);
+        exit(185);
+    }
+
+    let _ = fs::remove_file(format!("{}/{}", CONFIG.ssh_dir_path, args[2]));
+}
+
+/// Returns true if `name` is a plain file name, which cannot point outside
+/// the directory it is looked up in.
+fn is_file_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+}
+
 pub fn command_get_local_version(client: Client, _: Option<Vec<String>>) {
     const AGENT_VERSION: Option<&str> = option_env!("CARGO_PKG_VERSION");
     let agent_version = AGENT_VERSION.unwrap_or("unknown").to_string();
@@ -165,6 +373,385 @@ pub fn command_remote_before_copy(client: Client, args: Option<Vec<String>>) {
     client.remote_before_copy(user_id, remote_token, items);
 }
 
+pub fn command_remote_before_download(client: Client, args: Option<Vec<String>>) {
+    let args = args.unwrap();
+    if args.len() < 7 {
+        eprintln!(
+            "Usage: cli {} <host> <port> <user_id> <remote_token> <items>",
+            COMMAND_REMOTE_BEFORE_DOWNLOAD
+        );
+        exit(143);
+    }
+    let user_id: u32 = args[4].parse().unwrap_or(0);
+    let remote_token = &args[5];
+    let items = &args[6];
+
+    client.remote_before_download(user_id, remote_token, items);
+}
+
+pub fn command_local_before_download(client: Client<'_>, args: Option<Vec<String>>) {
+    let args = args.unwrap();
+    if args.len() < 5 {
+        eprintln!(
+            "Usage: cli {} <user_id> <token> <items>",
+            COMMAND_LOCAL_BEFORE_DOWNLOAD
+        );
+        exit(144);
+    }
+
+    let user_id: u32 = args[2].parse().unwrap_or(0);
+    let token = String::from(&args[3]);
+    let items = String::from(&args[4]);
+
+    match client
+        .files_api
+        .local_before_download(user_id, token, items)
+    {
+        Ok(response) => {
+            print!("{}", response.trim());
+        }
+        Err(e) => {
+            let mut msg = e.message;
+            let http_code = match e.http_code {
+                None => String::new(),
+                Some(code) => {
+                    msg = msg.replacen(&format!("{} ", code), "", 1);
+                    format!("{} ", code)
+                }
+            };
+            eprint!("{}{}", http_code, msg);
+            exit(e.code);
+        }
+    }
+}
+
+pub fn command_remote_create_archive(client: Client, args: Option<Vec<String>>) {
+    let args = args.unwrap();
+    if args.len() < 8 {
+        eprintln!(
+            "Usage: cli {} <host> <port> <archive_name> <source_root> <compress> <items> \
+            [conflicts]",
+            COMMAND_REMOTE_CREATE_ARCHIVE
+        );
+        exit(145);
+    }
+    let archive_name = &args[4];
+    let source_root = &args[5];
+    let compress = parse_compression(&args[6], 158);
+    let items = &args[7];
+    let conflicts = args.get(8).map(|c| c.as_str());
+
+    client.remote_create_archive(archive_name, source_root, compress, items, conflicts);
+}
+
+pub fn command_local_create_archive(_: Client, args: Option<Vec<String>>) {
+    let args = args.unwrap();
+    if args.len() < 6 {
+        eprintln!(
+            "Usage: cli {} <archive_name> <source_root> <compress> <items> [conflicts]",
+            COMMAND_LOCAL_CREATE_ARCHIVE
+        );
+        exit(146);
+    }
+    let archive_name = &args[2];
+    let source_root = &args[3];
+    let compress = parse_compression(&args[4], 159);
+
+    let items: Vec<ArchiveItem> = match serde_json::from_str(&args[5]) {
+        Ok(i) => i,
+        Err(e) => {
+            eprint!("400 Invalid items: {}", e);
+            exit(147);
+        }
+    };
+    // the conflict strategies of the items, applied by the destination
+    let rules: Vec<ConflictRule> = match args.get(6) {
+        None => Vec::new(),
+        Some(rules) => match serde_json::from_str(rules) {
+            Ok(r) => r,
+            Err(e) => {
+                eprint!("400 Invalid conflict rules: {}", e);
+                exit(195);
+            }
+        },
+    };
+
+    // progress lines are written to stdout, the 'cancel requested' flag
+    // is set when the reading end of stdout goes away
+    let cancel_requested = Arc::new(Mutex::new(false));
+    let archive_path = format!("{}{}.src.tar.gz", CONFIG.temp_data_dir, archive_name);
+    let mut archive_writer = match ArchiveWriter::new(
+        &archive_path,
+        compress,
+        source_root,
+        cancel_requested.clone(),
+        ProgressSink::Stdout(cancel_requested.clone()),
+        ArchiveOptions::default(),
+    ) {
+        Ok(w) => w,
+        Err(e) => {
+            eprint!("500 {}", e.message);
+            exit(e.code);
+        }
+    };
+
+    let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+    let archive_result = match runtime.block_on(archive_writer.crate_archive(items)) {
+        Ok(_) => archive_writer.write_manifest(&get_manifest_name(archive_name)),
+        Err(e) => Err(e),
+    };
+    let archive_result = archive_result.and_then(|_| match rules.is_empty() {
+        true => Ok(()),
+        false => archive_writer.write_conflict_rules(&get_conflicts_name(archive_name), &rules),
+    });
+    if let Err(e) = archive_result {
+        let _ = fs::remove_file(&archive_path);
+        eprint!("500 {}", e.message);
+        exit(e.code);
+    }
+
+    // ensure the encoder has flushed
+    drop(archive_writer);
+
+    if *cancel_requested.lock().unwrap() {
+        let _ = fs::remove_file(&archive_path);
+        eprint!("200 Operation aborted by user request");
+        exit(148);
+    }
+
+    let size = fs::metadata(&archive_path).map(|m| m.len()).unwrap_or(0);
+    println!("result::{}", size);
+}
+
+pub fn command_remote_do_copy(client: Client, args: Option<Vec<String>>) {
+    let args = args.unwrap();
+    if args.len() < 13 {
+        eprintln!(
+            "Usage: cli {} <host> <port> <transfer_id> <source_root> <dst_host> <dst_port> \
+            <dst_agent_id> <destination_root> <compress> <overwrite> <items>",
+            COMMAND_REMOTE_DO_COPY
+        );
+        exit(150);
+    }
+    let transfer_id = &args[4];
+    let source_root = &args[5];
+    let dst_host = &args[6];
+    let dst_port = &args[7];
+    let dst_agent_id = parse_agent_id(&args[8], 193);
+    let destination_root = &args[9];
+    let compress = parse_compression(&args[10], 160);
+    let overwrite = args[11] == "true";
+    let items = &args[12];
+
+    client.remote_do_copy(
+        transfer_id,
+        source_root,
+        dst_host,
+        dst_port,
+        dst_agent_id,
+        destination_root,
+        compress,
+        overwrite,
+        items,
+    );
+}
+
+pub fn command_local_do_copy(_: Client, args: Option<Vec<String>>) {
+    let args = args.unwrap();
+    if args.len() < 11 {
+        eprintln!(
+            "Usage: cli {} <transfer_id> <source_root> <host> <port> <agent_id> \
+            <destination_root> <compress> <overwrite> <items>",
+            COMMAND_LOCAL_DO_COPY
+        );
+        exit(151);
+    }
+
+    let items: Vec<ArchiveItem> = match serde_json::from_str(&args[10]) {
+        Ok(i) => i,
+        Err(e) => {
+            eprint!("400 Invalid items: {}", e);
+            exit(152);
+        }
+    };
+
+    // the destination is reached with the key pair this agent was paired
+    // with it for, see `keys::find_connection_key`
+    let mut transfer = Transfer {
+        agent_id: parse_agent_id(&args[6], 194),
+        host: args[4].to_string(),
+        port: args[5].to_string(),
+        transfer_id: args[2].to_string(),
+        local_path: args[3].to_string(),
+        remote_path: args[7].to_string(),
+        compress: parse_compression(&args[8], 161),
+        overwrite: args[9] == "true",
+        size: 0,
+        metadata: MetadataPolicy::default(),
+        streams: 1,
+        skip_rules: SkipRules::default(),
+        conflicts: Vec::new(),
+        move_sources: false,
+        moved_items: None,
+        rc_auth: String::new(),
+    };
+
+    // status updates are written to stdout, the 'cancel requested' flag
+    // is set when the reading end of stdout goes away
+    let cancel_requested = Arc::new(Mutex::new(false));
+    let progress = ProgressSink::Stdout(cancel_requested.clone());
+
+    let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+    let result = runtime.block_on(async {
+        let bandwidth_limit = Arc::new(Mutex::new(0));
+        let result = archive_and_upload(
+            &mut transfer,
+            items,
+            &progress,
+            &cancel_requested,
+            &bandwidth_limit,
+            ArchiveOptions::default(),
+        )
+        .await;
+        send_transfer_result(&progress, result).await
+    });
+
+    // the error has been reported on stdout already
+    if let Err(e) = result {
+        exit(e.code);
+    }
+}
+
+pub fn command_remote_check_pairing(client: Client, args: Option<Vec<String>>) {
+    let args = args.unwrap();
+    if args.len() < 7 {
+        eprintln!(
+            "Usage: cli {} <host> <port> <dst_host> <dst_port> <dst_agent_id>",
+            COMMAND_REMOTE_CHECK_PAIRING
+        );
+        exit(188);
+    }
+    let dst_agent_id = parse_agent_id(&args[6], 192);
+
+    client.remote_check_pairing(&args[4], &args[5], dst_agent_id);
+}
+
+pub fn command_local_check_pairing(_: Client, args: Option<Vec<String>>) {
+    let args = args.unwrap();
+    if args.len() < 5 {
+        eprintln!(
+            "Usage: cli {} <host> <port> <agent_id>",
+            COMMAND_LOCAL_CHECK_PAIRING
+        );
+        exit(189);
+    }
+    let port = match args[3].parse::<i16>() {
+        Ok(port) => port,
+        Err(_) => {
+            eprint!("400 Invalid port: {}", args[3]);
+            exit(190);
+        }
+    };
+    let agent_id = parse_agent_id(&args[4], 191);
+
+    // the host key has to be pinned and the key of the connection accepted
+    if let Err(e) = Client::for_agent(&args[2], port, Some(agent_id)).ping() {
+        match e.http_code {
+            Some(http_code) => eprint!("{} {}", http_code, e.message),
+            None => eprint!("{}", e.message),
+        }
+        exit(e.code);
+    }
+}
+
+pub fn command_local_list_destination(_: Client, args: Option<Vec<String>>) {
+    let args = args.unwrap();
+    if args.len() < 5 {
+        eprintln!(
+            "Usage: cli {} <destination_root> <checksum> <items>",
+            COMMAND_LOCAL_LIST_DESTINATION
+        );
+        exit(153);
+    }
+    let destination_root = &args[2];
+    let checksum = args[3] == "true";
+
+    let items: Vec<ArchiveItem> = match serde_json::from_str(&args[4]) {
+        Ok(i) => i,
+        Err(e) => {
+            eprint!("400 Invalid items: {}", e);
+            exit(154);
+        }
+    };
+
+    let listing = list_destination(destination_root, &items, checksum);
+    print!("{}", serde_json::to_string(&listing).unwrap());
+}
+
+pub fn command_local_delete_files(_: Client, args: Option<Vec<String>>) {
+    let args = args.unwrap();
+    if args.len() < 3 {
+        eprintln!(
+            "Usage: cli {} <destination_root> < <paths>",
+            COMMAND_LOCAL_DELETE_FILES
+        );
+        exit(155);
+    }
+    let destination_root = &args[2];
+
+    // the paths are read from stdin as there may be too many for the command line
+    let mut input = String::new();
+    let _ = stdin().read_to_string(&mut input);
+    let paths: Vec<String> = match serde_json::from_str(&input) {
+        Ok(p) => p,
+        Err(e) => {
+            eprint!("400 Invalid paths: {}", e);
+            exit(156);
+        }
+    };
+
+    match delete_files(destination_root, &paths) {
+        Ok(deleted) => print!("{}", deleted),
+        Err(errors) => {
+            eprint!("500 {}", errors.join(", "));
+            exit(157);
+        }
+    }
+}
+
+pub fn command_local_resolve_conflicts(_: Client, args: Option<Vec<String>>) {
+    let args = args.unwrap();
+    if args.len() < 5 {
+        eprintln!(
+            "Usage: cli {} <extracted_root> <destination_root> <rules_path>",
+            COMMAND_LOCAL_RESOLVE_CONFLICTS
+        );
+        exit(162);
+    }
+
+    let rules: Result<Vec<ConflictRule>, String> = fs::read_to_string(&args[4])
+        .map_err(|e| e.to_string())
+        .and_then(|r| serde_json::from_str(&r).map_err(|e| e.to_string()));
+    let rules = match rules {
+        Ok(r) => r,
+        Err(e) => {
+            eprint!("400 Invalid conflict rules: {}", e);
+            exit(163);
+        }
+    };
+
+    match resolve_conflicts(Path::new(&args[2]), Path::new(&args[3]), &rules) {
+        Ok(resolutions) => println!(
+            "conflicts::{}",
+            serde_json::to_string(&resolutions).unwrap()
+        ),
+        Err(e) => {
+            eprint!("409 {}", e);
+            exit(164);
+        }
+    }
+}
+
 pub fn command_local_before_copy(client: Client<'_>, args: Option<Vec<String>>) {
     let args = args.unwrap();
     if args.len() < 5 {
@@ -197,3 +784,25 @@ pub fn command_local_before_copy(client: Client<'_>, args: Option<Vec<String>>)
         }
     }
 }
+
+/// Parses the `<compress>` argument of a command, see `ArchiveCompression`,
+/// exiting with `exit_code` if it is invalid.
+fn parse_agent_id(arg: &str, exit_code: i32) -> u32 {
+    match arg.parse::<u32>() {
+        Ok(id) => id,
+        Err(_) => {
+            eprint!("400 Invalid agent id: {}", arg);
+            exit(exit_code);
+        }
+    }
+}
+
+fn parse_compression(arg: &str, exit_code: i32) -> ArchiveCompression {
+    match arg.parse::<ArchiveCompression>() {
+        Ok(c) => c,
+        Err(e) => {
+            eprint!("400 {}", e);
+            exit(exit_code);
+        }
+    }
+}
@@ -3,7 +3,7 @@ use std::process::{Command, Stdio};
 
 use tokio::process::Command as AsyncCommand;
 
-use crate::constants::DEFAULTS;
+use crate::config::CONFIG;
 
 pub struct CommandError {
     pub code: i32,
@@ -23,6 +23,7 @@ pub fn run_command(
     // attempt to execute command
     let result = Command::new(program)
         .args(command_args)
+        .envs(CONFIG.get_script_env())
         .stdout(Stdio::piped())
         .output();
 
@@ -60,7 +61,11 @@ pub async fn run_command_async(
 
     // setup and execute command
     let mut cmd = AsyncCommand::new(program);
-    let result = cmd.args(command_args).output().await;
+    let result = cmd
+        .args(command_args)
+        .envs(CONFIG.get_script_env())
+        .output()
+        .await;
 
     // return error if failed to execute command
     if let Err(err) = result {
@@ -95,7 +100,7 @@ fn get_command_args<'a>(
     let program;
     if is_cli {
         // prepend command to the provided list of arguments and execute cli as the program
-        program = DEFAULTS.cli_executable_path;
+        program = CONFIG.cli_executable_path.as_str();
         command_args.push(command);
         command_args.append(&mut args.clone());
     } else {
@@ -0,0 +1,308 @@
+use once_cell::sync::Lazy;
+use rocket::serde::Deserialize;
+use std::{env, fs, net::IpAddr, path::Path, process::exit};
+
+use crate::access_token::KeyAlgorithm;
+
+/// The configuration file read when `AGENT_CONFIG` does not name one.
+const DEFAULT_CONFIG_FILE: &str = "/app/agent.toml";
+const ENV_NAME_CONFIG_FILE: &str = "AGENT_CONFIG";
+/// Settings are overridden by environment variables named after them in
+/// upper case with this prefix, e.g. `AGENT_TEMP_DATA_DIR`.
+const ENV_PREFIX: &str = "AGENT_";
+
+/// Exit code of both binaries when the configuration is invalid.
+const INVALID_CONFIG_EXIT_CODE: i32 = 165;
+
+/// The configuration of the agent, loaded on first use, see `Config::load`.
+/// An invalid configuration terminates the process.
+pub static CONFIG: Lazy<Config> = Lazy::new(|| match Config::load() {
+    Ok(config) => config,
+    Err(e) => exit_invalid(&e),
+});
+
+/// Config holds the settings of the agent. Each of them can be set in the
+/// TOML configuration file and overridden by an environment variable; the
+/// settings not given take the values used in the Docker image.
+///
+/// The settings only apply to this agent, remote agents are asked for their
+/// paths through their own cli, see `REMOTE_CLI`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde", default, deny_unknown_fields)]
+pub struct Config {
+    /// base URL of the Files API
+    pub files_address: String,
+    /// address and port the webserver listens on
+    pub listen_address: String,
+    pub listen_port: u16,
+    pub cli_executable_path: String,
+    pub ssh_dir_path: String,
+    pub authorized_keys_file: String,
+    pub known_hosts_file: String,
+    pub private_key_file: String,
+    pub public_key_file: String,
+    /// path prefix of the key files written for the temporary access tokens
+    /// of remote agents
+    pub temporary_key_file_name: String,
+    /// algorithm of the keys handed out as temporary access tokens, ed25519
+    /// or ecdsa-p256; tokens in the format of older agents are ecdsa-p256
+    pub temporary_key_algorithm: String,
+    /// directory of the key pairs generated for each connection
+    pub connection_key_dir: String,
+    /// directory of the archives being transferred, ends with a '/'
+    pub temp_data_dir: String,
+    pub transfer_store_file: String,
+    pub schedule_store_file: String,
+    pub connection_store_file: String,
+    pub extract_archive_script_path: String,
+    /// seconds to wait for a connection to a remote agent
+    pub connect_timeout: u64,
+    /// seconds to wait for a response of the Files API
+    pub request_timeout: u64,
+    /// the number of transfers running at a time, 0 meaning unlimited
+    pub max_concurrent_transfers: usize,
+    pub max_concurrent_transfers_per_remote: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            files_address: "http://files".to_string(),
+            listen_address: "0.0.0.0".to_string(),
+            listen_port: 80,
+            cli_executable_path: "/app/cli".to_string(),
+            ssh_dir_path: "/app/data/client/.ssh".to_string(),
+            authorized_keys_file: "/app/data/client/.ssh/authorized_keys".to_string(),
+            known_hosts_file: "/app/data/client/.ssh/known_hosts".to_string(),
+            private_key_file: "/app/data/client/.ssh/id_rsa".to_string(),
+            public_key_file: "/app/data/client/.ssh/id_rsa.pub".to_string(),
+            temporary_key_file_name: "/app/data/client/.ssh/id_access_token".to_string(),
+            temporary_key_algorithm: "ed25519".to_string(),
+            connection_key_dir: "/app/data/client/.ssh/connections".to_string(),
+            temp_data_dir: "/app/data/temp/".to_string(),
+            transfer_store_file: "/app/data/transfers.json".to_string(),
+            schedule_store_file: "/app/data/schedules.json".to_string(),
+            connection_store_file: "/app/data/connections.json".to_string(),
+            extract_archive_script_path: "/etc/scripts/extract-archive.sh".to_string(),
+            connect_timeout: 10,
+            request_timeout: 10,
+            max_concurrent_transfers: 4,
+            max_concurrent_transfers_per_remote: 2,
+        }
+    }
+}
+
+/// The settings which hold a number, all others hold a string.
+const NUMERIC_SETTINGS: [&str; 5] = [
+    "listen_port",
+    "connect_timeout",
+    "request_timeout",
+    "max_concurrent_transfers",
+    "max_concurrent_transfers_per_remote",
+];
+
+const STRING_SETTINGS: [&str; 16] = [
+    "files_address",
+    "listen_address",
+    "cli_executable_path",
+    "ssh_dir_path",
+    "authorized_keys_file",
+    "known_hosts_file",
+    "private_key_file",
+    "public_key_file",
+    "temporary_key_file_name",
+    "temporary_key_algorithm",
+    "connection_key_dir",
+    "temp_data_dir",
+    "transfer_store_file",
+    "schedule_store_file",
+    "connection_store_file",
+    "extract_archive_script_path",
+];
+
+/// Environment variables read before the ones with `ENV_PREFIX`, kept for
+/// existing deployments.
+const LEGACY_ENV_NAMES: [(&str, &str); 3] = [
+    ("FILES_ADDRESS", "files_address"),
+    ("MAX_CONCURRENT_TRANSFERS", "max_concurrent_transfers"),
+    (
+        "MAX_CONCURRENT_TRANSFERS_PER_REMOTE",
+        "max_concurrent_transfers_per_remote",
+    ),
+];
+
+impl Config {
+    /// Loads the configuration file named by `AGENT_CONFIG`, or the default
+    /// one if it exists, applies the overrides from the environment and
+    /// validates the result.
+    pub fn load() -> Result<Self, String> {
+        let mut settings = match env::var(ENV_NAME_CONFIG_FILE) {
+            Ok(path) => read_settings(&path)?,
+            Err(_) if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                read_settings(DEFAULT_CONFIG_FILE)?
+            }
+            Err(_) => toml::value::Table::new(),
+        };
+
+        let mut env_names: Vec<(String, &str)> = LEGACY_ENV_NAMES
+            .iter()
+            .map(|(name, key)| (name.to_string(), *key))
+            .collect();
+        for key in NUMERIC_SETTINGS.iter().chain(STRING_SETTINGS.iter()) {
+            env_names.push((format!("{}{}", ENV_PREFIX, key.to_uppercase()), key));
+        }
+        for (name, key) in env_names {
+            if let Ok(value) = env::var(&name) {
+                let value = match NUMERIC_SETTINGS.contains(&key) {
+                    true => value
+                        .trim()
+                        .parse::<i64>()
+                        .map(toml::Value::Integer)
+                        .map_err(|_| format!("{name} is not a number: {value}"))?,
+                    false => toml::Value::String(value),
+                };
+                settings.insert(key.to_string(), value);
+            }
+        }
+
+        let mut config: Config = toml::Value::Table(settings)
+            .try_into()
+            .map_err(|e| e.to_string())?;
+
+        // paths of the archives are built by appending to the directory
+        if !config.temp_data_dir.ends_with('/') {
+            config.temp_data_dir.push('/');
+        }
+
+        let errors = config.validate();
+        if !errors.is_empty() {
+            return Err(errors.join("; "));
+        }
+
+        Ok(config)
+    }
+
+    /// Returns the problems of the settings which do not depend on the
+    /// files installed.
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        let files_address = self.files_address.trim_end_matches('/');
+        if !(files_address.starts_with("http://") || files_address.starts_with("https://"))
+            || files_address.split_once("://").map(|(_, h)| h.is_empty()) == Some(true)
+        {
+            errors.push(format!(
+                "files_address must be an http(s) URL: {}",
+                self.files_address
+            ));
+        }
+        if self.listen_address.parse::<IpAddr>().is_err() {
+            errors.push(format!(
+                "listen_address must be an IP address: {}",
+                self.listen_address
+            ));
+        }
+        if self.connect_timeout == 0 {
+            errors.push("connect_timeout must be at least 1 second".to_string());
+        }
+        if self.request_timeout == 0 {
+            errors.push("request_timeout must be at least 1 second".to_string());
+        }
+        if KeyAlgorithm::from_name(&self.temporary_key_algorithm).is_none() {
+            errors.push(format!(
+                "temporary_key_algorithm must be ed25519 or ecdsa-p256: {}",
+                self.temporary_key_algorithm
+            ));
+        }
+
+        let paths = [
+            ("cli_executable_path", &self.cli_executable_path),
+            ("ssh_dir_path", &self.ssh_dir_path),
+            ("authorized_keys_file", &self.authorized_keys_file),
+            ("known_hosts_file", &self.known_hosts_file),
+            ("private_key_file", &self.private_key_file),
+            ("public_key_file", &self.public_key_file),
+            ("temporary_key_file_name", &self.temporary_key_file_name),
+            ("connection_key_dir", &self.connection_key_dir),
+            ("temp_data_dir", &self.temp_data_dir),
+            ("transfer_store_file", &self.transfer_store_file),
+            ("schedule_store_file", &self.schedule_store_file),
+            ("connection_store_file", &self.connection_store_file),
+            (
+                "extract_archive_script_path",
+                &self.extract_archive_script_path,
+            ),
+        ];
+        for (key, path) in paths {
+            if !path.starts_with('/') {
+                errors.push(format!("{key} must be an absolute path: {path}"));
+            }
+        }
+
+        errors
+    }
+
+    /// Terminates the process if any of the files the webserver runs is
+    /// missing: the CLI, the scripts and the directories it writes to.
+    pub fn require_installation(&self) {
+        let mut errors = Vec::new();
+
+        let files = [
+            ("cli_executable_path", &self.cli_executable_path),
+            (
+                "extract_archive_script_path",
+                &self.extract_archive_script_path,
+            ),
+        ];
+        for (key, path) in files {
+            if !Path::new(path).is_file() {
+                errors.push(format!("{key} does not exist: {path}"));
+            }
+        }
+
+        let dirs = [
+            ("ssh_dir_path", &self.ssh_dir_path),
+            ("temp_data_dir", &self.temp_data_dir),
+        ];
+        for (key, path) in dirs {
+            if !Path::new(path).is_dir() {
+                errors.push(format!("{key} is not a directory: {path}"));
+            }
+        }
+
+        if !errors.is_empty() {
+            exit_invalid(&errors.join("; "));
+        }
+    }
+
+    /// Returns the environment variables holding the settings the scripts
+    /// read, to be set on the processes this agent runs so that the scripts
+    /// use the same paths.
+    pub fn get_script_env(&self) -> Vec<(String, &str)> {
+        [
+            ("cli_executable_path", &self.cli_executable_path),
+            ("ssh_dir_path", &self.ssh_dir_path),
+            ("authorized_keys_file", &self.authorized_keys_file),
+            ("temp_data_dir", &self.temp_data_dir),
+        ]
+        .into_iter()
+        .map(|(key, value)| {
+            (
+                format!("{}{}", ENV_PREFIX, key.to_uppercase()),
+                value.as_str(),
+            )
+        })
+        .collect()
+    }
+}
+
+fn exit_invalid(error: &str) -> ! {
+    eprintln!("Invalid configuration: {error}");
+    exit(INVALID_CONFIG_EXIT_CODE);
+}
+
+fn read_settings(path: &str) -> Result<toml::value::Table, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("Cannot read {path}: {e}"))?;
+
+    toml::from_str(&content).map_err(|e| format!("Cannot parse {path}: {e}"))
+}
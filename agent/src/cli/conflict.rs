@@ -0,0 +1,354 @@
+use rocket::serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    fs::Metadata,
+    io::Error,
+    path::{Path, PathBuf},
+};
+
+/// ConflictStrategy determines what happens to a file of a transfer which
+/// already exists in the destination.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(crate = "rocket::serde", rename_all = "kebab-case")]
+pub enum ConflictStrategy {
+    /// replace the existing file
+    Overwrite,
+    /// keep the existing file
+    Skip,
+    /// keep the existing file and add the new one with a numbered suffix
+    Rename,
+    /// replace the existing file if the new one was modified later, which
+    /// requires the mtimes to be preserved when extracting
+    OverwriteIfNewer,
+    /// abort the transfer without changing the destination
+    Fail,
+}
+
+/// ConflictRule applies a strategy to the files at or under `path`, which is
+/// relative to the destination root as it appears in the archive.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ConflictRule {
+    pub path: String,
+    pub strategy: ConflictStrategy,
+}
+
+/// ConflictResolution reports the strategy applied to a file which existed
+/// in the destination, and the name the new file was given if renamed.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ConflictResolution {
+    pub path: String,
+    pub strategy: ConflictStrategy,
+    pub replaced: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub renamed_to: Option<String>,
+}
+
+/// Prepares the files extracted to `extracted_root` for being merged onto
+/// `destination_root`, replacing the existing files, by applying the
+/// strategy of the matching rule to each file which exists in both: files to
+/// keep in the destination are removed from the extracted ones and files to
+/// rename are renamed there. Nothing is changed if any of the conflicts is
+/// to fail the transfer, their paths are returned as the error instead.
+pub fn resolve_conflicts(
+    extracted_root: &Path,
+    destination_root: &Path,
+    rules: &[ConflictRule],
+) -> Result<Vec<ConflictResolution>, String> {
+    let mut conflicts = Vec::new();
+    find_conflicts(extracted_root, destination_root, "", rules, &mut conflicts)
+        .map_err(|e| e.to_string())?;
+
+    let failed: Vec<&str> = conflicts
+        .iter()
+        .filter(|(_, strategy)| *strategy == ConflictStrategy::Fail)
+        .map(|(path, _)| path.as_str())
+        .collect();
+    if !failed.is_empty() {
+        return Err(format!(
+            "Files exist in the destination: {}",
+            failed.join(", ")
+        ));
+    }
+
+    let mut resolutions = Vec::new();
+    for (path, strategy) in conflicts {
+        let extracted = extracted_root.join(&path);
+        let existing = destination_root.join(&path);
+        let resolution = resolve_conflict(&extracted, &existing, &path, strategy)
+            .map_err(|e| format!("{} {}", e, path))?;
+        resolutions.push(resolution);
+    }
+
+    Ok(resolutions)
+}
+
+/// Collects the paths of the extracted entries which exist in the
+/// destination along with the strategy to apply to them. Directories
+/// existing on both sides are merged, so only their contents can conflict.
+fn find_conflicts(
+    extracted_root: &Path,
+    destination_root: &Path,
+    path: &str,
+    rules: &[ConflictRule],
+    conflicts: &mut Vec<(String, ConflictStrategy)>,
+) -> Result<(), Error> {
+    for entry in fs::read_dir(extracted_root.join(path))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let entry_path = match path.is_empty() {
+            true => name,
+            false => format!("{}/{}", path, name),
+        };
+
+        let existing = match fs::symlink_metadata(destination_root.join(&entry_path)) {
+            Ok(m) => m,
+            Err(_) => continue,
+        };
+        if entry.file_type()?.is_dir() && existing.is_dir() {
+            find_conflicts(
+                extracted_root,
+                destination_root,
+                &entry_path,
+                rules,
+                conflicts,
+            )?;
+            continue;
+        }
+
+        let strategy = get_strategy(rules, &entry_path);
+        conflicts.push((entry_path, strategy));
+    }
+
+    Ok(())
+}
+
+fn resolve_conflict(
+    extracted: &Path,
+    existing: &Path,
+    path: &str,
+    strategy: ConflictStrategy,
+) -> Result<ConflictResolution, Error> {
+    let extracted_meta = fs::symlink_metadata(extracted)?;
+    let existing_meta = fs::symlink_metadata(existing)?;
+
+    let replace = match strategy {
+        ConflictStrategy::Overwrite => true,
+        ConflictStrategy::OverwriteIfNewer => {
+            get_mtime(&extracted_meta) > get_mtime(&existing_meta)
+        }
+        _ => false,
+    };
+    let mut renamed_to = None;
+
+    if replace {
+        // a directory is not replaced by a file, merging would fail
+        if existing_meta.is_dir() {
+            return Err(Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "Cannot replace a directory with a file",
+            ));
+        }
+        // the merge replaces files but not a file with a directory
+        if extracted_meta.is_dir() {
+            fs::remove_file(existing)?;
+        }
+    } else if strategy == ConflictStrategy::Rename {
+        let new_path = get_free_path(extracted, existing);
+        fs::rename(extracted, &new_path)?;
+        let new_name = new_path.file_name().unwrap().to_string_lossy().into_owned();
+        renamed_to = Some(match path.rsplit_once('/') {
+            Some((dir, _)) => format!("{}/{}", dir, new_name),
+            None => new_name,
+        });
+    } else {
+        // keep the existing file
+        match extracted_meta.is_dir() {
+            true => fs::remove_dir_all(extracted)?,
+            false => fs::remove_file(extracted)?,
+        }
+    }
+
+    Ok(ConflictResolution {
+        path: path.to_string(),
+        strategy,
+        replaced: replace,
+        renamed_to,
+    })
+}
+
+/// Returns the strategy of the rule for `path` or for the closest directory
+/// of it. Files not covered by any rule are kept.
+fn get_strategy(rules: &[ConflictRule], path: &str) -> ConflictStrategy {
+    rules
+        .iter()
+        .filter(|r| {
+            let rule_path = r.path.trim_matches('/');
+            path == rule_path
+                || rule_path.is_empty()
+                || path.starts_with(&format!("{}/", rule_path))
+        })
+        .max_by_key(|r| r.path.trim_matches('/').len())
+        .map(|r| r.strategy)
+        .unwrap_or(ConflictStrategy::Skip)
+}
+
+/// Returns the path of `extracted` renamed with the first numbered suffix,
+/// e.g. "name (1).txt", which is free both next to it and in the destination.
+fn get_free_path(extracted: &Path, existing: &Path) -> PathBuf {
+    let stem = extracted
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let extension = extracted
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+
+    let mut n = 1;
+    loop {
+        let name = format!("{} ({}){}", stem, n, extension);
+        let candidate = extracted.with_file_name(&name);
+        if fs::symlink_metadata(&candidate).is_err()
+            && fs::symlink_metadata(existing.with_file_name(&name)).is_err()
+        {
+            return candidate;
+        }
+        n += 1;
+    }
+}
+
+fn get_mtime(meta: &Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("webscp-conflict-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("extracted")).unwrap();
+        fs::create_dir_all(dir.join("destination")).unwrap();
+        dir
+    }
+
+    fn write(path: &Path, content: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    fn rule(path: &str, strategy: ConflictStrategy) -> ConflictRule {
+        ConflictRule {
+            path: path.to_string(),
+            strategy,
+        }
+    }
+
+    #[test]
+    fn strategy_of_the_closest_rule() {
+        let rules = vec![
+            rule("/docs", ConflictStrategy::Overwrite),
+            rule("docs/drafts/", ConflictStrategy::Rename),
+            rule("docs/drafts/final.txt", ConflictStrategy::Fail),
+        ];
+        assert_eq!(
+            get_strategy(&rules, "docs/a.txt"),
+            ConflictStrategy::Overwrite
+        );
+        assert_eq!(
+            get_strategy(&rules, "docs/drafts/b.txt"),
+            ConflictStrategy::Rename
+        );
+        assert_eq!(
+            get_strategy(&rules, "docs/drafts/final.txt"),
+            ConflictStrategy::Fail
+        );
+        // a common prefix is not a parent directory
+        assert_eq!(get_strategy(&rules, "docs2/a.txt"), ConflictStrategy::Skip);
+        assert_eq!(get_strategy(&rules, "other.txt"), ConflictStrategy::Skip);
+
+        let rules = vec![rule("", ConflictStrategy::Overwrite)];
+        assert_eq!(
+            get_strategy(&rules, "any/file"),
+            ConflictStrategy::Overwrite
+        );
+    }
+
+    #[test]
+    fn rename_keeps_both_files() {
+        let dir = temp_dir("rename");
+        let (extracted, destination) = (dir.join("extracted"), dir.join("destination"));
+        write(&extracted.join("docs/a.txt"), "new");
+        write(&extracted.join("docs/new.txt"), "only new");
+        write(&destination.join("docs/a.txt"), "old");
+        write(&destination.join("docs/a (1).txt"), "renamed before");
+
+        let rules = vec![rule("docs", ConflictStrategy::Rename)];
+        let resolutions = resolve_conflicts(&extracted, &destination, &rules).unwrap();
+        assert_eq!(resolutions.len(), 1);
+        assert_eq!(resolutions[0].path, "docs/a.txt");
+        assert!(!resolutions[0].replaced);
+        assert_eq!(resolutions[0].renamed_to.as_deref(), Some("docs/a (2).txt"));
+
+        // the renamed file is merged next to the existing one
+        assert!(!extracted.join("docs/a.txt").exists());
+        assert_eq!(
+            fs::read_to_string(extracted.join("docs/a (2).txt")).unwrap(),
+            "new"
+        );
+        assert!(extracted.join("docs/new.txt").exists());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn skip_and_overwrite() {
+        let dir = temp_dir("skip");
+        let (extracted, destination) = (dir.join("extracted"), dir.join("destination"));
+        write(&extracted.join("keep.txt"), "new");
+        write(&extracted.join("replace.txt"), "new");
+        write(&destination.join("keep.txt"), "old");
+        write(&destination.join("replace.txt"), "old");
+
+        let rules = vec![rule("replace.txt", ConflictStrategy::Overwrite)];
+        let resolutions = resolve_conflicts(&extracted, &destination, &rules).unwrap();
+        assert_eq!(resolutions.len(), 2);
+
+        // kept files are removed from the extracted ones
+        assert!(!extracted.join("keep.txt").exists());
+        assert!(extracted.join("replace.txt").exists());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn fail_changes_nothing() {
+        let dir = temp_dir("fail");
+        let (extracted, destination) = (dir.join("extracted"), dir.join("destination"));
+        write(&extracted.join("a.txt"), "new");
+        write(&extracted.join("b.txt"), "new");
+        write(&destination.join("a.txt"), "old");
+        write(&destination.join("b.txt"), "old");
+
+        let rules = vec![
+            rule("", ConflictStrategy::Rename),
+            rule("b.txt", ConflictStrategy::Fail),
+        ];
+        let error = resolve_conflicts(&extracted, &destination, &rules).unwrap_err();
+        assert_eq!(error, "Files exist in the destination: b.txt");
+
+        // the other conflicts are not resolved either
+        assert!(extracted.join("a.txt").exists());
+        assert!(!extracted.join("a (1).txt").exists());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -5,46 +5,57 @@ pub const COMMAND_GET_REMOTE_RESOURCE: &str = "get-remote-resource";
 pub const COMMAND_REMOTE_BEFORE_COPY: &str = "remote-before-copy";
 pub const COMMAND_REMOTE_DO_COPY: &str = "remote-do-copy";
 pub const COMMAND_LOCAL_BEFORE_COPY: &str = "local-before-copy";
+pub const COMMAND_REMOTE_BEFORE_DOWNLOAD: &str = "remote-before-download";
+pub const COMMAND_LOCAL_BEFORE_DOWNLOAD: &str = "local-before-download";
+pub const COMMAND_REMOTE_CREATE_ARCHIVE: &str = "remote-create-archive";
+pub const COMMAND_LOCAL_CREATE_ARCHIVE: &str = "local-create-archive";
+pub const COMMAND_LOCAL_DO_COPY: &str = "local-do-copy";
+pub const COMMAND_REMOTE_CHECK_PAIRING: &str = "remote-check-pairing";
+pub const COMMAND_LOCAL_CHECK_PAIRING: &str = "local-check-pairing";
+pub const COMMAND_LOCAL_LIST_DESTINATION: &str = "local-list-destination";
+pub const COMMAND_LOCAL_DELETE_FILES: &str = "local-delete-files";
+pub const COMMAND_LOCAL_RESOLVE_CONFLICTS: &str = "local-resolve-conflicts";
+pub const COMMAND_LOCAL_ADD_AUTHORIZED_KEY: &str = "local-add-authorized-key";
+pub const COMMAND_LOCAL_REMOVE_AUTHORIZED_KEY: &str = "local-remove-authorized-key";
+pub const COMMAND_LOCAL_EXTRACT_ARCHIVE: &str = "local-extract-archive";
+pub const COMMAND_LOCAL_GET_ARCHIVE_PATH: &str = "local-get-archive-path";
+pub const COMMAND_LOCAL_GET_TOKEN_USER: &str = "local-get-token-user";
+pub const COMMAND_LOCAL_RELEASE_TOKEN: &str = "local-release-token";
 pub const COMMAND_EXCHANGE_KEYS: &str = "exchange-keys";
 pub const COMMAND_GET_REMOTE_USER: &str = "get-remote-user";
 pub const COMMAND_GET_TOKEN_USER: &str = "get-token-user";
 pub const COMMAND_GET_LOCAL_USER: &str = "get-local-user";
 pub const COMMAND_PING: &str = "ping";
+pub const COMMAND_GET_REMOTE_HOST_KEY: &str = "get-remote-host-key";
+pub const COMMAND_REPIN_REMOTE_HOST_KEY: &str = "repin-remote-host-key";
+pub const COMMAND_REMOTE_ADD_KEY: &str = "remote-add-key";
+pub const COMMAND_REMOTE_REMOVE_KEY: &str = "remote-remove-key";
 
-pub struct Defaults {
-    pub cli_executable_path: &'static str,
-    pub default_fb_api_address: &'static str,
-    pub authorized_keys_file: &'static str,
-    pub known_hosts_file: &'static str,
-    pub private_key_file: &'static str,
-    pub public_key_file: &'static str,
-    pub temp_data_dir: &'static str,
-    pub with_contenv: &'static str,
-    pub env_name_fb_api_address: &'static str,
-    pub uploader_script_path: &'static str,
-    pub cancel_transfer_script_path: &'static str,
-    pub generate_key_pair_script_path: &'static str,
-    pub revoke_key_pair_script_path: &'static str,
-    pub extract_archive_script_path: &'static str,
-    pub temporary_key_file_name: &'static str,
-    pub ssh_dir_path: &'static str,
-}
+/// The cli of remote agents, found on their PATH, which resolves the paths
+/// of the remote from its own configuration.
+pub const REMOTE_CLI: &str = "webscp-cli";
+/// The cli of remote agents which do not provide `REMOTE_CLI`, at its
+/// location in the Docker image of older versions.
+pub const LEGACY_REMOTE_CLI: &str = "with-contenv /app/cli";
 
-pub const DEFAULTS: Defaults = Defaults {
-    cli_executable_path: "/app/cli",
-    default_fb_api_address: "http://files",
-    authorized_keys_file: "/app/data/client/.ssh/authorized_keys",
-    known_hosts_file: "/app/data/client/.ssh/known_hosts",
-    private_key_file: "/app/data/client/.ssh/id_rsa",
-    public_key_file: "/app/data/client/.ssh/id_rsa.pub",
-    temp_data_dir: "/app/data/temp/",
-    with_contenv: "with-contenv",
-    env_name_fb_api_address: "FILES_ADDRESS",
-    uploader_script_path: "/etc/scripts/uploader.sh",
-    cancel_transfer_script_path: "/etc/scripts/cancel-transfer.sh",
-    generate_key_pair_script_path: "/etc/scripts/generate-key-pair.sh",
-    revoke_key_pair_script_path: "/etc/scripts/revoke-key-pair.sh",
-    extract_archive_script_path: "/etc/scripts/extract-archive.sh",
-    temporary_key_file_name: "/app/data/client/.ssh/id_ecdsa-pem",
-    ssh_dir_path: "/app/data/client/.ssh",
-};
+/// The suffixes of the archives of a transfer in the temp data dir, on the
+/// destination and on the source of a download.
+pub const ARCHIVE_SUFFIXES: [&str; 2] = ["dst.tar", "src.tar.gz"];
+
+pub const TRANSFER_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Uploads are split into at most this many parallel streams, each sending
+/// at least `UPLOAD_STREAM_MIN_PART_SIZE` bytes.
+pub const MAX_UPLOAD_STREAMS: usize = 16;
+pub const UPLOAD_STREAM_MIN_PART_SIZE: u64 = 8 * 1024 * 1024;
+
+/// The interface of the extract script this agent calls, passed as its first
+/// argument. The scripts of remote agents are asked for theirs first.
+pub const EXTRACT_ARCHIVE_INTERFACE: u32 = 2;
+
+/// Host key types negotiated with remote agents, in order of preference.
+/// Keys of the type pinned when pairing, ECDSA, are checked first.
+pub const HOST_KEY_PREFERENCES: &str = "ecdsa-sha2-nistp256,ecdsa-sha2-nistp384,ecdsa-sha2-nistp521,ssh-ed25519,rsa-sha2-512,rsa-sha2-256,ssh-rsa";
+
+/// The runs of a schedule kept in its history.
+pub const MAX_SCHEDULE_HISTORY: usize = 20;
@@ -0,0 +1,352 @@
+use glob::{MatchOptions, Pattern};
+use rocket::serde::{Deserialize, Serialize};
+use std::{fs, iter, path::Path};
+
+/// Names of the files listing patterns of paths to skip, in the format of
+/// `.gitignore`, which apply to the directory they are found in.
+pub const IGNORE_FILE_NAMES: [&str; 2] = [".gitignore", ".webscpignore"];
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// SkipRules select the entries of the copied directories to leave out of a
+/// transfer. Patterns are globs matched like the lines of a `.gitignore`: a
+/// pattern without a slash matches the name of an entry at any depth, other
+/// patterns match its path relative to the copied item, a trailing slash
+/// only matches directories and a leading `!` takes an entry back in. The
+/// items selected for the copy themselves are never skipped.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(crate = "rocket::serde", default)]
+pub struct SkipRules {
+    /// if set, only files matching one of these patterns are copied
+    pub include: Vec<String>,
+    /// files and directories matching these patterns are not copied
+    pub exclude: Vec<String>,
+    /// also skip the entries listed in the ignore files of the sources
+    pub ignore_files: bool,
+}
+
+struct Rule {
+    pattern: Pattern,
+    negated: bool,
+    dir_only: bool,
+    anchored: bool,
+}
+
+impl Rule {
+    /// Parses a line of an ignore file or a pattern of a request. Returns
+    /// `None` for blank lines and comments.
+    fn parse(line: &str) -> Result<Option<Self>, String> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(l) => (true, l),
+            None => (false, line),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(l) => (true, l),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let pattern = Pattern::new(line.trim_start_matches('/'))
+            .map_err(|e| format!("Invalid pattern {}: {}", line, e))?;
+
+        Ok(Some(Self {
+            pattern,
+            negated,
+            dir_only,
+            anchored,
+        }))
+    }
+
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+
+        match self.anchored {
+            true => self.pattern.matches_with(path, MATCH_OPTIONS),
+            false => {
+                let name = path.rsplit('/').next().unwrap_or(path);
+                self.pattern.matches_with(name, MATCH_OPTIONS)
+            }
+        }
+    }
+}
+
+/// RuleSet holds the rules of an ignore file, or of the request, which apply
+/// to the paths under `base`.
+struct RuleSet {
+    base: String,
+    rules: Vec<Rule>,
+}
+
+impl RuleSet {
+    /// Returns the rule set's verdict on `path`, the last matching rule wins,
+    /// or `None` if no rule matches.
+    fn is_skipped(&self, path: &str, is_dir: bool) -> Option<bool> {
+        let path = match self.base.is_empty() {
+            true => path,
+            false => path.strip_prefix(&self.base)?.strip_prefix('/')?,
+        };
+
+        self.rules
+            .iter()
+            .rev()
+            .find(|r| r.matches(path, is_dir))
+            .map(|r| !r.negated)
+    }
+}
+
+/// SkipFilter applies `SkipRules` while walking the sources of a transfer.
+/// Paths are relative to the copied item. The rules of the ignore files are
+/// picked up by `enter_dir` and dropped by `leave_dir`.
+pub struct SkipFilter {
+    include: Vec<Rule>,
+    exclude: RuleSet,
+    ignore_files: bool,
+    ignored: Vec<RuleSet>,
+    entered: Vec<bool>,
+}
+
+impl SkipFilter {
+    pub fn new(rules: &SkipRules) -> Result<Self, String> {
+        Ok(Self {
+            include: parse_rules(&rules.include)?,
+            exclude: RuleSet {
+                base: String::new(),
+                rules: parse_rules(&rules.exclude)?,
+            },
+            ignore_files: rules.ignore_files,
+            ignored: Vec::new(),
+            entered: Vec::new(),
+        })
+    }
+
+    /// Returns false if the filter skips nothing.
+    pub fn is_active(&self) -> bool {
+        !self.include.is_empty() || !self.exclude.rules.is_empty() || self.ignore_files
+    }
+
+    /// Returns true if the entry at `path` is to be left out. Directories
+    /// are only skipped by exclude patterns and ignore files, the include
+    /// patterns select the files within them.
+    pub fn is_skipped(&self, path: &str, is_dir: bool) -> bool {
+        // the request's patterns take precedence over the ignore files,
+        // deeper ignore files over the ones above them
+        let skipped = self
+            .ignored
+            .iter()
+            .chain(iter::once(&self.exclude))
+            .rev()
+            .find_map(|set| set.is_skipped(path, is_dir))
+            .unwrap_or(false);
+        if skipped {
+            return true;
+        }
+
+        !is_dir && !self.include.is_empty() && !self.include.iter().any(|r| r.matches(path, false))
+    }
+
+    /// Returns true if the file at `path` or one of the directories it is
+    /// in is skipped, e.g. to keep a mirror transfer from deleting it.
+    pub fn is_path_skipped(&self, path: &str) -> bool {
+        let mut dir = String::new();
+        let components: Vec<&str> = path.split('/').collect();
+        for component in &components[..components.len() - 1] {
+            if !dir.is_empty() {
+                dir.push('/');
+            }
+            dir.push_str(component);
+            if self.is_skipped(&dir, true) {
+                return true;
+            }
+        }
+
+        self.is_skipped(path, false)
+    }
+
+    /// Picks up the rules of the ignore files of the directory at `src_path`
+    /// found at `path`, which apply until the matching `leave_dir`.
+    pub fn enter_dir(&mut self, src_path: &Path, path: &str) {
+        let mut rules = Vec::new();
+        if self.ignore_files {
+            for name in IGNORE_FILE_NAMES {
+                // unreadable ignore files and invalid lines are disregarded
+                if let Ok(content) = fs::read_to_string(src_path.join(name)) {
+                    rules.extend(
+                        content
+                            .lines()
+                            .filter_map(|l| Rule::parse(l).ok().flatten()),
+                    );
+                }
+            }
+        }
+
+        let has_rules = !rules.is_empty();
+        if has_rules {
+            self.ignored.push(RuleSet {
+                base: path.to_string(),
+                rules,
+            });
+        }
+        self.entered.push(has_rules);
+    }
+
+    pub fn leave_dir(&mut self) {
+        if self.entered.pop() == Some(true) {
+            self.ignored.pop();
+        }
+    }
+}
+
+/// Returns the path of the entry `name` of the directory at `path`, both
+/// relative to the copied item.
+pub fn join_path(path: &str, name: &str) -> String {
+    match path.is_empty() {
+        true => name.to_string(),
+        false => format!("{}/{}", path, name),
+    }
+}
+
+fn parse_rules(patterns: &[String]) -> Result<Vec<Rule>, String> {
+    let mut rules = Vec::new();
+    for pattern in patterns.iter() {
+        if let Some(rule) = Rule::parse(pattern)? {
+            rules.push(rule);
+        }
+    }
+
+    Ok(rules)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(include: &[&str], exclude: &[&str]) -> SkipFilter {
+        SkipFilter::new(&SkipRules {
+            include: include.iter().map(|p| p.to_string()).collect(),
+            exclude: exclude.iter().map(|p| p.to_string()).collect(),
+            ignore_files: false,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn patterns_without_slash_match_names_at_any_depth() {
+        let f = filter(&[], &["*.log"]);
+        assert!(f.is_skipped("a.log", false));
+        assert!(f.is_skipped("dir/sub/b.log", false));
+        assert!(!f.is_skipped("a.log.txt", false));
+        assert!(!f.is_skipped("log", false));
+    }
+
+    #[test]
+    fn patterns_with_slash_match_relative_paths() {
+        let f = filter(&[], &["build/out", "/target", "src/*.rs"]);
+        assert!(f.is_skipped("build/out", false));
+        assert!(!f.is_skipped("x/build/out", false));
+        assert!(f.is_skipped("target", true));
+        assert!(!f.is_skipped("src/target", true));
+        assert!(f.is_skipped("src/main.rs", false));
+        assert!(!f.is_skipped("src/bin/main.rs", false));
+    }
+
+    #[test]
+    fn trailing_slash_only_matches_directories() {
+        let f = filter(&[], &["cache/"]);
+        assert!(f.is_skipped("cache", true));
+        assert!(f.is_skipped("a/cache", true));
+        assert!(!f.is_skipped("cache", false));
+    }
+
+    #[test]
+    fn last_matching_pattern_wins() {
+        let f = filter(&[], &["*.log", "!keep.log"]);
+        assert!(f.is_skipped("a.log", false));
+        assert!(!f.is_skipped("keep.log", false));
+        assert!(!f.is_skipped("dir/keep.log", false));
+
+        let f = filter(&[], &["!keep.log", "*.log"]);
+        assert!(f.is_skipped("keep.log", false));
+    }
+
+    #[test]
+    fn include_patterns_select_files_only() {
+        let f = filter(&["*.rs"], &[]);
+        assert!(!f.is_skipped("main.rs", false));
+        assert!(!f.is_skipped("src/lib.rs", false));
+        assert!(f.is_skipped("README.md", false));
+        assert!(!f.is_skipped("docs", true));
+    }
+
+    #[test]
+    fn path_skipped_by_its_directories() {
+        let f = filter(&[], &["node_modules/"]);
+        assert!(f.is_path_skipped("node_modules/a/index.js"));
+        assert!(f.is_path_skipped("web/node_modules/index.js"));
+        assert!(!f.is_path_skipped("web/index.js"));
+        assert!(!f.is_path_skipped("node_modules"));
+    }
+
+    #[test]
+    fn parse_patterns() {
+        let f = filter(&[], &["", "# comment", "   "]);
+        assert!(!f.is_active());
+        assert!(!f.is_skipped("# comment", false));
+        assert!(filter(&["*.rs"], &[]).is_active());
+        assert!(filter(&[], &["*.log"]).is_active());
+
+        for pattern in ["[", "a/[b", "***"] {
+            let rules = SkipRules {
+                exclude: vec![pattern.to_string()],
+                ..Default::default()
+            };
+            assert!(
+                SkipFilter::new(&rules).is_err(),
+                "{pattern} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn ignore_files_apply_to_their_directory() {
+        let root = std::env::temp_dir().join(format!("webscp-filter-{}", std::process::id()));
+        let sub = root.join("sub");
+        fs::create_dir_all(&sub).unwrap();
+        fs::write(root.join(".gitignore"), "*.tmp\n# comment\n").unwrap();
+        fs::write(sub.join(".webscpignore"), "!keep.tmp\n/local\n").unwrap();
+
+        let mut f = SkipFilter::new(&SkipRules {
+            exclude: vec!["!request.tmp".to_string()],
+            ignore_files: true,
+            ..Default::default()
+        })
+        .unwrap();
+        f.enter_dir(&root, "");
+        assert!(f.is_skipped("a.tmp", false));
+        assert!(!f.is_skipped("request.tmp", false));
+        assert!(!f.is_skipped("local", false));
+
+        f.enter_dir(&sub, "sub");
+        assert!(f.is_skipped("sub/a.tmp", false));
+        assert!(!f.is_skipped("sub/keep.tmp", false));
+        assert!(f.is_skipped("sub/local", false));
+        assert!(!f.is_skipped("sub/x/local", false));
+
+        f.leave_dir();
+        assert!(!f.is_skipped("sub/local", false));
+        assert!(f.is_skipped("sub/keep.tmp", false));
+        f.leave_dir();
+        assert!(!f.is_skipped("a.tmp", false));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,404 @@
+use base64::{
+    engine::general_purpose::{STANDARD, STANDARD_NO_PAD},
+    Engine,
+};
+use rocket::serde::{json::serde_json, Deserialize, Serialize};
+use sha256::digest;
+use std::{
+    fs,
+    fs::{DirBuilder, OpenOptions},
+    io::Write,
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::Path,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::config::CONFIG;
+
+/// Connection is a remote agent which trusts a key of this agent, recorded
+/// when paired or, for the connections paired before the record was kept,
+/// when first used.
+///
+/// Connections paired for an agent in Files have a key pair of their own,
+/// the others share the key pair of this agent and have no `agent_id`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Connection {
+    pub host: String,
+    pub port: i16,
+    /// id of the agent in Files the key pair was generated for
+    #[serde(default)]
+    pub agent_id: Option<u32>,
+    /// id of the Files user who paired the agent
+    #[serde(default)]
+    pub user_id: Option<u32>,
+    /// fingerprint of the key of this agent the remote trusts
+    pub key_fingerprint: String,
+    pub paired_at: u64,
+    pub updated_at: u64,
+}
+
+/// Returns the type and the base64 encoded blob of the OpenSSH public key
+/// in the file at `path`.
+pub fn read_public_key(path: &str) -> Result<(String, String), String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("Cannot read {path}: {e}"))?;
+    let mut fields = content.split_whitespace();
+
+    match (fields.next(), fields.next()) {
+        (Some(key_type), Some(blob)) => Ok((key_type.to_string(), blob.to_string())),
+        _ => Err(format!("Invalid public key in {path}")),
+    }
+}
+
+/// Returns the SHA256 fingerprint of a public key blob, in the format of
+/// OpenSSH.
+pub fn get_key_fingerprint(blob: &str) -> Result<String, String> {
+    let key = STANDARD
+        .decode(blob)
+        .map_err(|e| format!("Invalid public key: {e}"))?;
+    let hex = digest(&key[..]);
+    let hash: Vec<u8> = (0..hex.len())
+        .step_by(2)
+        .filter_map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect();
+
+    Ok(format!("SHA256:{}", STANDARD_NO_PAD.encode(hash)))
+}
+
+/// Returns the fingerprint of the public key of this agent.
+pub fn get_own_key_fingerprint() -> Result<String, String> {
+    let (_, blob) = read_public_key(&CONFIG.public_key_file)?;
+
+    get_key_fingerprint(&blob)
+}
+
+/// Returns the paths of the private and the public key this agent had before
+/// its key was last rotated. The key pair is kept for the remote agents the
+/// rotation did not reach, which are moved on to the new key when connected.
+pub fn get_previous_key_files() -> (String, String) {
+    (
+        format!("{}.old", CONFIG.private_key_file),
+        format!("{}.old", CONFIG.public_key_file),
+    )
+}
+
+/// Returns the fingerprint of the previous key of this agent if it is kept,
+/// see `get_previous_key_files`.
+pub fn get_previous_key_fingerprint() -> Option<String> {
+    let (_, public_key_file) = get_previous_key_files();
+    let (_, blob) = read_public_key(&public_key_file).ok()?;
+
+    get_key_fingerprint(&blob).ok()
+}
+
+/// Returns the paths of the private and the public key of the connection of
+/// the agent with the id `agent_id`.
+pub fn get_connection_key_files(agent_id: u32) -> (String, String) {
+    let private_key_file = format!("{}/id_rsa-{agent_id}", CONFIG.connection_key_dir);
+    let public_key_file = format!("{private_key_file}.pub");
+
+    (private_key_file, public_key_file)
+}
+
+/// Generates the key pair of the connection of the agent with the id
+/// `agent_id`, replacing the one generated before if any. The comment of
+/// the key names the agent and the user so that its entry in the authorized
+/// keys of the remote can be told apart.
+pub fn create_connection_key(agent_id: u32, user_id: u32) -> Result<(String, String), String> {
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&CONFIG.connection_key_dir)
+        .map_err(|e| format!("Cannot create {}: {e}", CONFIG.connection_key_dir))?;
+
+    let (private_key_file, public_key_file) = get_connection_key_files(agent_id);
+    // ssh-keygen prompts before overwriting a key
+    let _ = fs::remove_file(&private_key_file);
+    let _ = fs::remove_file(&public_key_file);
+
+    let comment = format!("webscp-agent-{agent_id}-user-{user_id}");
+    let output = Command::new("ssh-keygen")
+        .args([
+            "-q", "-t", "rsa", "-b", "4096", "-N", "", "-C", &comment, "-f",
+        ])
+        .arg(&private_key_file)
+        .output()
+        .map_err(|e| format!("Cannot run ssh-keygen: {e}"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Cannot generate key pair: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok((private_key_file, public_key_file))
+}
+
+/// Deletes the key pair of the connection of the agent with the id
+/// `agent_id`.
+pub fn remove_connection_key(agent_id: u32) {
+    let (private_key_file, public_key_file) = get_connection_key_files(agent_id);
+    let _ = fs::remove_file(private_key_file);
+    let _ = fs::remove_file(public_key_file);
+}
+
+/// Returns the paths of the private and the public key to authenticate to
+/// a remote agent with as the agent `agent_id`: the key pair generated for
+/// it when pairing. The key of another agent is never used, even if it is
+/// authorized on the same remote. `None` means the key pair of this agent.
+pub fn find_connection_key(agent_id: Option<u32>) -> Option<(String, String)> {
+    agent_id
+        .map(get_connection_key_files)
+        .filter(|(private_key_file, public_key_file)| {
+            Path::new(private_key_file).is_file() && Path::new(public_key_file).is_file()
+        })
+}
+
+/// Adds the key to the authorized keys of this agent unless already there,
+/// followed by `comment` if any. Returns true if added.
+pub fn add_authorized_key(
+    key_type: &str,
+    blob: &str,
+    comment: Option<&str>,
+) -> Result<bool, String> {
+    add_key_to_file(&CONFIG.authorized_keys_file, key_type, blob, comment)
+}
+
+/// Removes the key from the authorized keys of this agent. Returns the
+/// number of entries removed.
+pub fn remove_authorized_key(blob: &str) -> Result<usize, String> {
+    remove_key_from_file(&CONFIG.authorized_keys_file, blob)
+}
+
+fn add_key_to_file(
+    authorized_keys_file: &str,
+    key_type: &str,
+    blob: &str,
+    comment: Option<&str>,
+) -> Result<bool, String> {
+    let content = fs::read_to_string(authorized_keys_file).unwrap_or_default();
+    if content.lines().any(|line| line_has_key(line, blob)) {
+        return Ok(false);
+    }
+
+    // keys appended before without a line break are completed first
+    let separator = match content.is_empty() || content.ends_with('\n') {
+        true => "",
+        false => "\n",
+    };
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(authorized_keys_file)
+        .map_err(|e| e.to_string())?;
+    let entry = match comment {
+        Some(comment) => format!("{key_type} {blob} {comment}"),
+        None => format!("{key_type} {blob}"),
+    };
+    writeln!(file, "{separator}{entry}").map_err(|e| e.to_string())?;
+
+    Ok(true)
+}
+
+fn remove_key_from_file(authorized_keys_file: &str, blob: &str) -> Result<usize, String> {
+    let content = match fs::read_to_string(authorized_keys_file) {
+        Ok(c) => c,
+        Err(_) => return Ok(0),
+    };
+
+    let kept: Vec<&str> = content
+        .lines()
+        .filter(|line| !line_has_key(line, blob))
+        .collect();
+    let removed = content.lines().count() - kept.len();
+    if removed == 0 {
+        return Ok(0);
+    }
+
+    // replace the file in one step, sessions may be authenticating meanwhile
+    let mut new_content = kept.join("\n");
+    if !new_content.is_empty() {
+        new_content.push('\n');
+    }
+    let temp_path = format!("{}.tmp", authorized_keys_file);
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&temp_path)
+        .map_err(|e| e.to_string())?;
+    file.write_all(new_content.as_bytes())
+        .map_err(|e| e.to_string())?;
+    fs::rename(&temp_path, authorized_keys_file).map_err(|e| e.to_string())?;
+
+    Ok(removed)
+}
+
+/// Returns the connections of this agent.
+pub fn list_connections() -> Vec<Connection> {
+    match fs::read_to_string(&CONFIG.connection_store_file) {
+        Ok(content) => serde_json::from_str(&content).unwrap_or_default(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Records that the remote agent at `host` and `port` trusts the key with
+/// the fingerprint `key_fingerprint`, generated for the agent `agent_id` of
+/// the user `user_id` or, without `agent_id`, the key of this agent.
+pub fn record_connection(
+    host: &str,
+    port: i16,
+    agent_id: Option<u32>,
+    user_id: Option<u32>,
+    key_fingerprint: &str,
+) -> Result<(), String> {
+    let now = now();
+    let mut connections = list_connections();
+    match connections
+        .iter_mut()
+        .find(|c| is_connection(c, host, port, agent_id))
+    {
+        Some(connection) => {
+            connection.host = host.to_string();
+            connection.port = port;
+            connection.user_id = user_id.or(connection.user_id);
+            connection.key_fingerprint = key_fingerprint.to_string();
+            connection.updated_at = now;
+        }
+        None => connections.push(Connection {
+            host: host.to_string(),
+            port,
+            agent_id,
+            user_id,
+            key_fingerprint: key_fingerprint.to_string(),
+            paired_at: now,
+            updated_at: now,
+        }),
+    }
+
+    save_connections(&connections)
+}
+
+/// Records the connection to the remote agent at `host` and `port` with the
+/// key of this agent, unless recorded already.
+pub fn remember_connection(host: &str, port: i16) {
+    if list_connections()
+        .iter()
+        .any(|c| is_connection(c, host, port, None))
+    {
+        return;
+    }
+    if let Ok(fingerprint) = get_own_key_fingerprint() {
+        let _ = record_connection(host, port, None, None, &fingerprint);
+    }
+}
+
+/// Removes the connection of the agent `agent_id` or, without `agent_id`,
+/// the connection to the remote agent at `host` and `port` with the key of
+/// this agent.
+pub fn forget_connection(host: &str, port: i16, agent_id: Option<u32>) -> Result<(), String> {
+    let mut connections = list_connections();
+    connections.retain(|c| !is_connection(c, host, port, agent_id));
+
+    save_connections(&connections)
+}
+
+fn save_connections(connections: &[Connection]) -> Result<(), String> {
+    let content = serde_json::to_string(connections).map_err(|e| e.to_string())?;
+
+    // write to a temp file first so that a crash cannot leave a truncated store behind
+    let temp_path = format!(
+        "{}.{}.tmp",
+        CONFIG.connection_store_file,
+        std::process::id()
+    );
+    fs::write(&temp_path, content).map_err(|e| e.to_string())?;
+    fs::rename(&temp_path, &CONFIG.connection_store_file).map_err(|e| e.to_string())
+}
+
+/// Returns true if `connection` is the one of the agent `agent_id` or,
+/// without `agent_id`, the one to `host` and `port` with the key of this
+/// agent.
+fn is_connection(connection: &Connection, host: &str, port: i16, agent_id: Option<u32>) -> bool {
+    match agent_id {
+        Some(_) => connection.agent_id == agent_id,
+        None => connection.agent_id.is_none() && connection.host == host && connection.port == port,
+    }
+}
+
+/// Returns true if the authorized keys entry `line`, which may start with
+/// options, is for the key `blob`.
+fn line_has_key(line: &str, blob: &str) -> bool {
+    !line.trim_start().starts_with('#') && line.split_whitespace().any(|field| field == blob)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOB: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIKey";
+    const OTHER_BLOB: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIOther";
+
+    fn temp_file(name: &str, content: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("webscp-keys-{}-{}", name, std::process::id()));
+        fs::write(&path, content).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn line_has_key_matches_whole_fields() {
+        assert!(line_has_key(&format!("ssh-ed25519 {BLOB}"), BLOB));
+        assert!(line_has_key(&format!("ssh-ed25519 {BLOB} comment"), BLOB));
+        // entries may start with options
+        assert!(line_has_key(
+            &format!("from=\"10.0.0.1\",no-pty ssh-ed25519 {BLOB} comment"),
+            BLOB
+        ));
+        assert!(!line_has_key(&format!("# ssh-ed25519 {BLOB}"), BLOB));
+        assert!(!line_has_key(&format!("ssh-ed25519 {BLOB}X"), BLOB));
+        assert!(!line_has_key(&format!("ssh-ed25519 {OTHER_BLOB}"), BLOB));
+    }
+
+    #[test]
+    fn add_key_once() {
+        // the last entry was written without a line break
+        let path = temp_file("add", &format!("ssh-ed25519 {OTHER_BLOB}"));
+        assert!(add_key_to_file(&path, "ssh-ed25519", BLOB, Some("webscp-test")).unwrap());
+        assert!(!add_key_to_file(&path, "ssh-ed25519", BLOB, None).unwrap());
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("ssh-ed25519 {OTHER_BLOB}\nssh-ed25519 {BLOB} webscp-test\n")
+        );
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn remove_key_keeps_other_entries() {
+        let content = format!(
+            "# ssh-ed25519 {BLOB}\nssh-ed25519 {BLOB} a\nssh-ed25519 {OTHER_BLOB}\n\
+            no-pty ssh-ed25519 {BLOB} b\n"
+        );
+        let path = temp_file("remove", &content);
+        assert_eq!(remove_key_from_file(&path, BLOB).unwrap(), 2);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("# ssh-ed25519 {BLOB}\nssh-ed25519 {OTHER_BLOB}\n")
+        );
+        assert_eq!(remove_key_from_file(&path, BLOB).unwrap(), 0);
+
+        let _ = fs::remove_file(&path);
+        assert_eq!(remove_key_from_file(&path, BLOB).unwrap(), 0);
+    }
+}
@@ -1,13 +1,22 @@
+pub mod access_token;
 pub mod archive;
 pub mod client;
 pub mod command;
 mod command_runner;
+pub mod config;
+pub mod conflict;
 pub mod constants;
 #[path = "../files_api.rs"]
 mod files_api;
+pub mod filter;
+pub mod keys;
+pub mod sync;
+pub mod transfer_store;
+pub mod upload;
 
-use crate::{client::Client, command::*, constants::*};
+use crate::{client::Client, command::*, config::CONFIG, constants::*};
 
+use once_cell::sync::Lazy;
 use std::{env, process::exit};
 
 pub struct FutureCommandError {
@@ -16,6 +25,9 @@ pub struct FutureCommandError {
 }
 
 fn main() {
+    // load the configuration, exiting if invalid
+    Lazy::force(&CONFIG);
+
     let args: Vec<String> = env::args().collect();
     #[allow(clippy::type_complexity)]
     let mut exec: Option<fn(Client, Option<Vec<String>>)> = None;
@@ -32,6 +44,19 @@ fn main() {
         COMMAND_GET_LOCAL_RESOURCE => exec = Some(command_get_local_resource),
         COMMAND_GET_LOCAL_USER => exec = Some(command_get_local_user),
         COMMAND_LOCAL_BEFORE_COPY => exec = Some(command_local_before_copy),
+        COMMAND_LOCAL_BEFORE_DOWNLOAD => exec = Some(command_local_before_download),
+        COMMAND_LOCAL_CREATE_ARCHIVE => exec = Some(command_local_create_archive),
+        COMMAND_LOCAL_DO_COPY => exec = Some(command_local_do_copy),
+        COMMAND_LOCAL_CHECK_PAIRING => exec = Some(command_local_check_pairing),
+        COMMAND_LOCAL_LIST_DESTINATION => exec = Some(command_local_list_destination),
+        COMMAND_LOCAL_DELETE_FILES => exec = Some(command_local_delete_files),
+        COMMAND_LOCAL_RESOLVE_CONFLICTS => exec = Some(command_local_resolve_conflicts),
+        COMMAND_LOCAL_ADD_AUTHORIZED_KEY => exec = Some(command_local_add_authorized_key),
+        COMMAND_LOCAL_REMOVE_AUTHORIZED_KEY => exec = Some(command_local_remove_authorized_key),
+        COMMAND_LOCAL_EXTRACT_ARCHIVE => exec = Some(command_local_extract_archive),
+        COMMAND_LOCAL_GET_ARCHIVE_PATH => exec = Some(command_local_get_archive_path),
+        COMMAND_LOCAL_GET_TOKEN_USER => exec = Some(command_local_get_token_user),
+        COMMAND_LOCAL_RELEASE_TOKEN => exec = Some(command_local_release_token),
         _ => {}
     }
     match exec {
@@ -54,9 +79,17 @@ fn main() {
         COMMAND_GET_REMOTE_VERSION => exec = Some(command_get_remote_version),
         COMMAND_GET_REMOTE_RESOURCE => exec = Some(command_get_remote_resource),
         COMMAND_REMOTE_BEFORE_COPY => exec = Some(command_remote_before_copy),
+        COMMAND_REMOTE_BEFORE_DOWNLOAD => exec = Some(command_remote_before_download),
+        COMMAND_REMOTE_CREATE_ARCHIVE => exec = Some(command_remote_create_archive),
+        COMMAND_REMOTE_DO_COPY => exec = Some(command_remote_do_copy),
+        COMMAND_REMOTE_CHECK_PAIRING => exec = Some(command_remote_check_pairing),
         COMMAND_GET_REMOTE_USER => exec = Some(command_get_remote_user),
         COMMAND_GET_TOKEN_USER => exec = Some(command_get_token_user),
         COMMAND_PING => exec = Some(command_ping),
+        COMMAND_GET_REMOTE_HOST_KEY => exec = Some(command_get_remote_host_key),
+        COMMAND_REPIN_REMOTE_HOST_KEY => exec = Some(command_repin_remote_host_key),
+        COMMAND_REMOTE_ADD_KEY => exec = Some(command_remote_add_key),
+        COMMAND_REMOTE_REMOVE_KEY => exec = Some(command_remote_remove_key),
         _ => {
             eprintln!("Invalid command {}", command);
             exit(129);
@@ -0,0 +1,438 @@
+use rocket::serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    fs::Metadata,
+    path::{Component, Path},
+    time::UNIX_EPOCH,
+};
+use urlencoding::decode;
+
+use crate::{
+    archive::ArchiveItem,
+    filter::{join_path, SkipFilter},
+};
+
+/// DestinationEntry describes a file found in the destination tree of a
+/// sync transfer.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct DestinationEntry {
+    pub size: u64,
+    pub mtime: u64,
+    pub hash: Option<String>,
+}
+
+/// DestinationListing maps the paths of the files in the destination tree,
+/// relative to the destination root as they appear in the archive, to
+/// their size, mtime and optionally their SHA-256 digest.
+pub type DestinationListing = HashMap<String, DestinationEntry>;
+
+/// Lists the regular files under the destinations of `items` within
+/// `destination_root`. Digests are only computed if `checksum` is set.
+pub fn list_destination(
+    destination_root: &str,
+    items: &[ArchiveItem],
+    checksum: bool,
+) -> DestinationListing {
+    let mut listing = DestinationListing::new();
+
+    for item in items.iter() {
+        let dst_ = decode(&item.destination).unwrap().into_owned();
+        let dst = dst_.trim_start_matches('/');
+
+        // do not look outside of the destination root
+        if Path::new(dst)
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            continue;
+        }
+
+        let path = format!("{}/{}", destination_root.trim_end_matches('/'), dst);
+        add_to_listing(&mut listing, &path, dst.to_string(), checksum);
+    }
+
+    listing
+}
+
+/// Returns true if the local file at `src_path`, to be archived as `path`,
+/// is already present in the destination with the same content.
+pub fn is_unchanged(listing: &DestinationListing, path: &str, src_path: &Path) -> bool {
+    let entry = match listing.get(path) {
+        Some(e) => e,
+        None => return false,
+    };
+    let meta = match src_path.metadata() {
+        Ok(m) => m,
+        Err(_) => return false,
+    };
+    if meta.len() != entry.size {
+        return false;
+    }
+
+    // compare digests if the listing has them, mtimes otherwise
+    match &entry.hash {
+        Some(hash) => match sha256::try_digest(src_path) {
+            Ok(digest) => &digest == hash,
+            Err(_) => false,
+        },
+        None => get_mtime(&meta) == entry.mtime,
+    }
+}
+
+/// SourceList holds the paths, as they appear in the archive, of the regular
+/// files under the sources of a transfer and of the entries left out of it
+/// by the skip rules.
+#[derive(Default)]
+pub struct SourceList {
+    pub files: HashSet<String>,
+    pub skipped: HashSet<String>,
+}
+
+/// Lists the regular files under the sources of `items` the way
+/// `ArchiveWriter` walks them, applying the skip rules of `filter`.
+pub fn list_sources(
+    source_base_path: &str,
+    items: &[ArchiveItem],
+    filter: &mut SkipFilter,
+) -> SourceList {
+    let mut sources = SourceList::default();
+
+    for item in items.iter() {
+        let src_ = decode(&item.source).unwrap().into_owned();
+        let dst_ = decode(&item.destination).unwrap().into_owned();
+        let src = src_.replacen("/files", source_base_path, 1);
+        let dst = dst_.trim_start_matches('/');
+
+        add_to_sources(&mut sources, filter, Path::new(&src), dst.to_string(), "");
+    }
+
+    sources
+}
+
+/// Returns the files in the destination listing which are missing from
+/// `sources`, i.e. the files a mirror transfer deletes. Files skipped by the
+/// skip rules of `filter`, or within a skipped directory, are kept.
+pub fn get_extraneous_files(
+    listing: &DestinationListing,
+    sources: &SourceList,
+    items: &[ArchiveItem],
+    filter: &SkipFilter,
+) -> Vec<String> {
+    let destinations: Vec<String> = items
+        .iter()
+        .map(|item| {
+            let dst_ = decode(&item.destination).unwrap().into_owned();
+            format!("{}/", dst_.trim_start_matches('/'))
+        })
+        .collect();
+    let is_skipped = |path: &str| {
+        let in_skipped_dir = path
+            .match_indices('/')
+            .any(|(i, _)| sources.skipped.contains(&path[..i]));
+        let item_path = destinations
+            .iter()
+            .find_map(|d| path.strip_prefix(d.as_str()));
+
+        sources.skipped.contains(path)
+            || in_skipped_dir
+            || matches!(item_path, Some(p) if filter.is_path_skipped(p))
+    };
+
+    let mut paths: Vec<String> = listing
+        .keys()
+        .filter(|p| !sources.files.contains(*p) && !is_skipped(p))
+        .cloned()
+        .collect();
+    paths.sort();
+
+    paths
+}
+
+/// Deletes the files at `paths`, relative to `destination_root`. Paths
+/// which are not regular files or which lead outside of the destination
+/// root, including through a symlinked directory, are refused. Returns the
+/// number of files deleted or the errors of the files that were not.
+pub fn delete_files(destination_root: &str, paths: &[String]) -> Result<usize, Vec<String>> {
+    let root = match fs::canonicalize(destination_root) {
+        Ok(r) => r,
+        Err(e) => return Err(vec![format!("{} {}", e, destination_root)]),
+    };
+    let mut deleted = 0;
+    let mut errors = Vec::new();
+
+    for path in paths.iter() {
+        if Path::new(path)
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            errors.push(format!("Invalid path {}", path));
+            continue;
+        }
+
+        let full_path = root.join(path);
+        let is_inside_root = match full_path.parent().map(fs::canonicalize) {
+            Some(Ok(parent)) => parent.starts_with(&root),
+            _ => false,
+        };
+        if !is_inside_root {
+            errors.push(format!("Path outside of destination {}", path));
+            continue;
+        }
+
+        match fs::symlink_metadata(&full_path) {
+            Ok(meta) if meta.file_type().is_file() => match fs::remove_file(&full_path) {
+                Ok(_) => deleted += 1,
+                Err(e) => errors.push(format!("{} {}", e, path)),
+            },
+            Ok(_) => errors.push(format!("Not a regular file {}", path)),
+            // already gone
+            Err(_) => {}
+        }
+    }
+
+    match errors.is_empty() {
+        true => Ok(deleted),
+        false => Err(errors),
+    }
+}
+
+fn add_to_sources(
+    sources: &mut SourceList,
+    filter: &mut SkipFilter,
+    src_path: &Path,
+    path: String,
+    item_path: &str,
+) {
+    let meta = match src_path.metadata() {
+        Ok(m) => m,
+        Err(_) => return,
+    };
+
+    if !item_path.is_empty() && filter.is_skipped(item_path, meta.is_dir()) {
+        sources.skipped.insert(path);
+        return;
+    }
+
+    if meta.is_dir() {
+        let entries = match fs::read_dir(src_path) {
+            Ok(e) => e,
+            Err(_) => return,
+        };
+        filter.enter_dir(src_path, item_path);
+        for entry in entries.flatten() {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            add_to_sources(
+                sources,
+                filter,
+                &entry.path(),
+                format!("{}/{}", path, file_name),
+                &join_path(item_path, &file_name),
+            );
+        }
+        filter.leave_dir();
+        return;
+    }
+
+    if meta.file_type().is_file() {
+        sources.files.insert(path);
+    }
+}
+
+fn add_to_listing(listing: &mut DestinationListing, path: &str, name: String, checksum: bool) {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(m) => m,
+        Err(_) => return,
+    };
+
+    if meta.is_dir() {
+        let entries = match fs::read_dir(path) {
+            Ok(e) => e,
+            Err(_) => return,
+        };
+        for entry in entries.flatten() {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            add_to_listing(
+                listing,
+                &format!("{}/{}", path, file_name),
+                format!("{}/{}", name, file_name),
+                checksum,
+            );
+        }
+        return;
+    }
+
+    if !meta.file_type().is_file() {
+        return;
+    }
+
+    let hash = match checksum {
+        true => sha256::try_digest(Path::new(path)).ok(),
+        false => None,
+    };
+    listing.insert(
+        name,
+        DestinationEntry {
+            size: meta.len(),
+            mtime: get_mtime(&meta),
+            hash,
+        },
+    );
+}
+
+fn get_mtime(meta: &Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::SkipRules;
+    use std::{os::unix::fs::symlink, path::PathBuf};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("webscp-sync-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(path: &Path, content: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    fn item(source: &str, destination: &str) -> ArchiveItem {
+        ArchiveItem {
+            source: source.to_string(),
+            destination: destination.to_string(),
+        }
+    }
+
+    fn filter(exclude: &[&str]) -> SkipFilter {
+        SkipFilter::new(&SkipRules {
+            exclude: exclude.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn set(paths: &[&str]) -> HashSet<String> {
+        paths.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn list_sources_applies_skip_rules() {
+        let root = temp_dir("sources");
+        write(&root.join("src/a.txt"), "a");
+        write(&root.join("src/debug.log"), "log");
+        write(&root.join("src/sub/b.txt"), "b");
+        write(&root.join("src/node_modules/x.js"), "x");
+
+        let base = root.to_string_lossy();
+        let items = [item("/files/src", "/dst")];
+        let sources = list_sources(&base, &items, &mut filter(&["*.log", "node_modules/"]));
+        assert_eq!(sources.files, set(&["dst/a.txt", "dst/sub/b.txt"]));
+        assert_eq!(sources.skipped, set(&["dst/debug.log", "dst/node_modules"]));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn extraneous_files_exclude_sources_and_skipped_entries() {
+        let entry = || DestinationEntry {
+            size: 0,
+            mtime: 0,
+            hash: None,
+        };
+        let listing: DestinationListing = [
+            "dst/a.txt",
+            "dst/old.txt",
+            "dst/sub/old.txt",
+            "dst/debug.log",
+            "dst/trace.log",
+            "dst/node_modules/y.js",
+        ]
+        .iter()
+        .map(|p| (p.to_string(), entry()))
+        .collect();
+        let sources = SourceList {
+            files: set(&["dst/a.txt"]),
+            skipped: set(&["dst/debug.log", "dst/node_modules"]),
+        };
+        let items = [item("/files/src", "/dst")];
+
+        // trace.log is not among the sources but matches the skip rules
+        assert_eq!(
+            get_extraneous_files(&listing, &sources, &items, &filter(&["*.log"])),
+            ["dst/old.txt", "dst/sub/old.txt"]
+        );
+    }
+
+    #[test]
+    fn list_destination_stays_within_the_root() {
+        let root = temp_dir("listing");
+        write(&root.join("dst/dir/a.txt"), "abc");
+        write(&root.join("secret"), "s");
+
+        let base = root.join("dst").to_string_lossy().into_owned();
+        let items = [item("/files/src", "/dir"), item("/files/x", "/../secret")];
+        let listing = list_destination(&base, &items, true);
+        assert_eq!(listing.len(), 1);
+        let entry = &listing["dir/a.txt"];
+        assert_eq!(entry.size, 3);
+        assert_eq!(entry.hash.as_deref(), Some(sha256::digest("abc").as_str()));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn delete_files_refuses_paths_outside_the_root() {
+        let root = temp_dir("delete");
+        let destination = root.join("dst");
+        write(&destination.join("a.txt"), "a");
+        write(&destination.join("dir/b.txt"), "b");
+        write(&root.join("outside/c.txt"), "c");
+        symlink(root.join("outside"), destination.join("link")).unwrap();
+        symlink(root.join("outside/c.txt"), destination.join("file-link")).unwrap();
+
+        let paths: Vec<String> = [
+            "a.txt",
+            "missing.txt",
+            "../outside/c.txt",
+            "dir/../../outside/c.txt",
+            "/outside/c.txt",
+            "link/c.txt",
+            "dir",
+            "file-link",
+        ]
+        .iter()
+        .map(|p| p.to_string())
+        .collect();
+        let errors = delete_files(&destination.to_string_lossy(), &paths).unwrap_err();
+        assert_eq!(
+            errors,
+            [
+                "Invalid path ../outside/c.txt",
+                "Invalid path dir/../../outside/c.txt",
+                "Invalid path /outside/c.txt",
+                "Path outside of destination link/c.txt",
+                "Not a regular file dir",
+                "Not a regular file file-link",
+            ]
+        );
+        assert!(!destination.join("a.txt").exists());
+        assert!(destination.join("dir/b.txt").exists());
+        assert!(root.join("outside/c.txt").exists());
+
+        let paths = ["dir/b.txt".to_string()];
+        assert_eq!(delete_files(&destination.to_string_lossy(), &paths), Ok(1));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
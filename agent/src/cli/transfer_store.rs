@@ -0,0 +1,376 @@
+use rocket::serde::{json::serde_json, Deserialize, Serialize};
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fs,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{client::ClientError, conflict::ConflictResolution, files_api::Transfer};
+
+/// Finished transfers are kept in the store for this long.
+const FINISHED_TRANSFER_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Progress-only changes are written to disk at most this often.
+const PROGRESS_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum TransferPhase {
+    Queued,
+    Archiving,
+    Uploading,
+    Extracting,
+    Complete,
+    Failed,
+    Cancelled,
+}
+
+impl TransferPhase {
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            TransferPhase::Complete | TransferPhase::Failed | TransferPhase::Cancelled
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct TransferRecord {
+    pub transfer: Transfer,
+    pub phase: TransferPhase,
+    pub started_at: u64,
+    pub updated_at: u64,
+    #[serde(default)]
+    pub phase_started_at: u64,
+    #[serde(default)]
+    pub items_processed: usize,
+    #[serde(default)]
+    pub items_total: usize,
+    #[serde(default)]
+    pub files_processed: usize,
+    #[serde(default)]
+    pub files_skipped: usize,
+    #[serde(default)]
+    pub files_deleted: usize,
+    #[serde(default)]
+    pub files_excluded: usize,
+    #[serde(default)]
+    pub items_moved: usize,
+    #[serde(default)]
+    pub bytes_sent: u64,
+    /// the strategies applied to the files which existed in the destination
+    #[serde(default)]
+    pub conflicts: Vec<ConflictResolution>,
+    pub error: Option<String>,
+}
+
+impl TransferRecord {
+    /// Average number of bytes sent per second since the upload (or
+    /// download) of the archive has started.
+    pub fn throughput(&self) -> u64 {
+        if self.bytes_sent == 0 {
+            return 0;
+        }
+        let elapsed = self.updated_at.saturating_sub(self.phase_started_at).max(1);
+
+        self.bytes_sent / elapsed
+    }
+
+    /// Estimated number of seconds until the archive has been sent, based
+    /// on the average throughput.
+    pub fn eta(&self) -> Option<u64> {
+        let throughput = self.throughput();
+        if self.phase != TransferPhase::Uploading || throughput == 0 {
+            return None;
+        }
+
+        Some(self.transfer.size.saturating_sub(self.bytes_sent) / throughput)
+    }
+
+    fn record_progress(&mut self, progress: &str) {
+        // progress::stats::<bytes sent>/<size>,
+        // progress::<compressed|archived>::<items added>/<items total>/<files added> or
+        // progress::<skipped|deleted|excluded>::<files skipped, deleted or excluded>,
+        // progress::moved::<items whose sources were deleted> or
+        // progress::conflicts::<JSON list of the conflict resolutions>
+        let (kind, values) = match progress.split_once("::") {
+            Some(p) => p,
+            None => return,
+        };
+        if kind == "conflicts" {
+            if let Ok(conflicts) = serde_json::from_str(values) {
+                self.conflicts = conflicts;
+            }
+            return;
+        }
+        let values: Vec<u64> = values
+            .split('/')
+            .map(|v| v.parse::<u64>().unwrap_or(0))
+            .collect();
+
+        match (kind, values.as_slice()) {
+            ("stats", [sent, size]) => {
+                self.bytes_sent = *sent;
+                self.transfer.size = *size;
+            }
+            ("skipped", [skipped]) => self.files_skipped = *skipped as usize,
+            ("deleted", [deleted]) => self.files_deleted = *deleted as usize,
+            ("excluded", [excluded]) => self.files_excluded = *excluded as usize,
+            ("moved", [moved]) => self.items_moved = *moved as usize,
+            (_, [added, total, files]) => {
+                self.items_processed = *added as usize;
+                self.items_total = *total as usize;
+                self.files_processed = *files as usize;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// TransferStore keeps track of the transfers executed by this agent and
+/// persists them to a JSON file so that transfers interrupted by a restart
+/// of the webserver can be detected and cleaned up on the next startup.
+#[derive(Clone)]
+pub struct TransferStore {
+    file_path: String,
+    records: Arc<Mutex<HashMap<String, TransferRecord>>>,
+    last_saved_at: Arc<Mutex<Instant>>,
+}
+
+impl TransferStore {
+    /// Loads the store from `file_path`, dropping finished transfers older
+    /// than the retention period. A missing file results in an empty store,
+    /// an unreadable one is moved aside to `<file_path>.corrupt-<timestamp>`
+    /// for inspection before it is replaced.
+    pub fn open(file_path: &str) -> Self {
+        let mut records: HashMap<String, TransferRecord> = match fs::read_to_string(file_path) {
+            Ok(content) => match serde_json::from_str(&content) {
+                Ok(records) => records,
+                Err(e) => {
+                    let corrupt_path = format!("{file_path}.corrupt-{}", now());
+                    match fs::rename(file_path, &corrupt_path) {
+                        Ok(_) => eprintln!(
+                            "Invalid transfer store {file_path}, moved to {corrupt_path}: {e}"
+                        ),
+                        Err(re) => eprintln!(
+                            "Invalid transfer store {file_path}, cannot move it aside: {re}: {e}"
+                        ),
+                    }
+                    HashMap::new()
+                }
+            },
+            Err(_) => HashMap::new(),
+        };
+
+        let now = now();
+        let retention = FINISHED_TRANSFER_RETENTION.as_secs();
+        records.retain(|_, r| !r.phase.is_finished() || now - r.updated_at.min(now) < retention);
+
+        let store = Self {
+            file_path: file_path.to_string(),
+            records: Arc::new(Mutex::new(records)),
+            last_saved_at: Arc::new(Mutex::new(Instant::now())),
+        };
+        store.save();
+
+        store
+    }
+
+    /// Adds a new transfer to the store in the archiving phase.
+    pub fn register(&self, transfer: &Transfer) {
+        let now = now();
+        self.records.lock().unwrap().insert(
+            transfer.transfer_id.clone(),
+            TransferRecord {
+                transfer: transfer.clone(),
+                phase: TransferPhase::Archiving,
+                started_at: now,
+                updated_at: now,
+                phase_started_at: now,
+                items_processed: 0,
+                items_total: 0,
+                files_processed: 0,
+                files_skipped: 0,
+                files_deleted: 0,
+                files_excluded: 0,
+                items_moved: 0,
+                bytes_sent: 0,
+                conflicts: Vec::new(),
+                error: None,
+            },
+        );
+        self.save();
+    }
+
+    /// Updates the phase or the last progress of a transfer from a status
+    /// update message sent to Files on its behalf.
+    pub fn record(&self, transfer_id: &str, message: &str) {
+        let phase = match message {
+            "queued" => Some(TransferPhase::Queued),
+            "comparing" | "compressing" | "archiving" => Some(TransferPhase::Archiving),
+            "starting upload" | "resuming upload" | "uploading" | "starting download"
+            | "downloading" => Some(TransferPhase::Uploading),
+            "extracting" | "deleting" => Some(TransferPhase::Extracting),
+            "complete" => Some(TransferPhase::Complete),
+            _ => None,
+        };
+
+        let phase_changed = {
+            let mut records = self.records.lock().unwrap();
+            let record = match records.get_mut(transfer_id) {
+                Some(r) => r,
+                None => return,
+            };
+            record.updated_at = now();
+
+            if let Some(progress) = message.strip_prefix("progress::") {
+                record.record_progress(progress);
+                false
+            } else if let Some(phase) = phase {
+                if record.phase != phase {
+                    record.phase = phase;
+                    record.phase_started_at = record.updated_at;
+                }
+                true
+            } else {
+                // any other message is an error reported about the transfer
+                record.phase = TransferPhase::Failed;
+                record.error = Some(message.to_string());
+                true
+            }
+        };
+
+        let save_due = self.last_saved_at.lock().unwrap().elapsed() > PROGRESS_SAVE_INTERVAL;
+        if phase_changed || save_due {
+            self.save();
+        }
+    }
+
+    /// Records the final phase of a transfer based on its result.
+    pub fn finish(&self, transfer_id: &str, result: &Result<(), ClientError>) {
+        {
+            let mut records = self.records.lock().unwrap();
+            let record = match records.get_mut(transfer_id) {
+                Some(r) => r,
+                None => return,
+            };
+            record.updated_at = now();
+
+            match result {
+                Ok(_) => record.phase = TransferPhase::Complete,
+                // aborted by user request - not an error
+                Err(e) if e.code == 997 => record.phase = TransferPhase::Cancelled,
+                Err(e) => {
+                    record.phase = TransferPhase::Failed;
+                    if !e.message.trim().is_empty() {
+                        record.error = Some(e.message.clone());
+                    }
+                }
+            }
+        }
+        self.save();
+    }
+
+    /// Marks an unfinished transfer as failed with `error`.
+    pub fn fail(&self, transfer_id: &str, error: &str) {
+        self.finish(
+            transfer_id,
+            &Err(ClientError {
+                code: 0,
+                message: error.to_string(),
+                http_code: None,
+            }),
+        );
+    }
+
+    /// Returns the transfer with the id `transfer_id` if it is in the store.
+    pub fn get(&self, transfer_id: &str) -> Option<TransferRecord> {
+        self.records.lock().unwrap().get(transfer_id).cloned()
+    }
+
+    /// Returns the transfers of the agent with the id `agent_id`, most
+    /// recently started first.
+    pub fn list(&self, agent_id: u32) -> Vec<TransferRecord> {
+        let mut records: Vec<TransferRecord> = self
+            .records
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|r| r.transfer.agent_id == agent_id)
+            .cloned()
+            .collect();
+        records.sort_by_key(|r| Reverse(r.started_at));
+
+        records
+    }
+
+    /// Returns the transfers that were not finished, e.g. because the
+    /// webserver was restarted while they were running.
+    pub fn unfinished(&self) -> Vec<TransferRecord> {
+        self.records
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|r| !r.phase.is_finished())
+            .cloned()
+            .collect()
+    }
+
+    fn save(&self) {
+        // the lock is held while writing so concurrent saves do not interleave
+        let records = self.records.lock().unwrap();
+        let content = match serde_json::to_string(&*records) {
+            Ok(c) => c,
+            Err(_) => return,
+        };
+
+        // write to a temp file first so that a crash cannot leave a truncated store behind
+        let temp_path = format!("{}.tmp", self.file_path);
+        if fs::write(&temp_path, content).is_ok() {
+            let _ = fs::rename(&temp_path, &self.file_path);
+        }
+        *self.last_saved_at.lock().unwrap() = Instant::now();
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corrupt_store_is_moved_aside() {
+        let dir =
+            std::env::temp_dir().join(format!("webscp-transfer-store-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let file_path = dir.join("transfers.json");
+        fs::write(&file_path, "{\"truncated\": ").unwrap();
+
+        let store = TransferStore::open(file_path.to_str().unwrap());
+        assert!(store.unfinished().is_empty());
+        assert_eq!(fs::read_to_string(&file_path).unwrap(), "{}");
+
+        let moved: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| name.starts_with("transfers.json.corrupt-"))
+            .collect();
+        assert_eq!(moved.len(), 1);
+        assert_eq!(
+            fs::read_to_string(dir.join(&moved[0])).unwrap(),
+            "{\"truncated\": "
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,540 @@
+use rocket::tokio::{
+    runtime::Handle,
+    sync::mpsc::{self, UnboundedSender},
+    task,
+};
+use std::{
+    fs,
+    io::{Error, ErrorKind, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    archive::{
+        choose_compression, get_compression_ratio, get_conflicts_name, get_manifest_name,
+        get_sources_size, sample_sources, ArchiveCompression, ArchiveError, ArchiveItem,
+        ArchiveOptions, ArchiveWriter, Codec, ProgressSink,
+    },
+    client::{send_stats, Client, ClientError, Throttle},
+    config::CONFIG,
+    files_api::Transfer,
+    sync::DestinationListing,
+};
+
+/// Runs the upload pipeline of a transfer: archives the items, uploads the
+/// archive to the remote and extracts it there. Status updates are sent to
+/// `progress` so the pipeline can run both in the webserver, reporting to
+/// Files, and in the cli on behalf of another agent, reporting to stdout.
+pub async fn archive_and_upload(
+    transfer: &mut Transfer,
+    items: Vec<ArchiveItem>,
+    progress: &ProgressSink,
+    cancel_requested: &Arc<Mutex<bool>>,
+    bandwidth_limit: &Arc<Mutex<u64>>,
+    options: ArchiveOptions,
+) -> Result<(), ClientError> {
+    resolve_compression(transfer, &items).await?;
+
+    // send progress update
+    let msg = match transfer.compress.is_compressed() {
+        true => "compressing",
+        false => "archiving",
+    };
+    progress.send(msg).await;
+    task::yield_now().await;
+
+    // create archive of files
+    let archive_path = &*get_upload_archive_path(&transfer.transfer_id, transfer.compress.codec);
+    let mut archive_writer = match ArchiveWriter::new(
+        archive_path,
+        transfer.compress,
+        &transfer.local_path,
+        cancel_requested.clone(),
+        progress.clone(),
+        options,
+    ) {
+        Ok(w) => w,
+        Err(e) => {
+            return Err(ClientError {
+                code: e.code,
+                message: e.message,
+                http_code: Some(500),
+            });
+        }
+    };
+    task::yield_now().await;
+
+    let manifest_name = get_manifest_name(&transfer.transfer_id);
+    let items_archived = items.clone();
+    let archive_result = match archive_writer.crate_archive(items).await {
+        Ok(_) => archive_writer.write_manifest(&manifest_name),
+        Err(e) => Err(e),
+    };
+    let archive_result =
+        archive_result.and_then(|_| write_conflict_rules(&mut archive_writer, transfer));
+    set_moved_items(transfer, &items_archived, &archive_writer);
+    send_files_skipped(
+        progress,
+        archive_writer.files_skipped(),
+        archive_writer.files_excluded(),
+    )
+    .await;
+    if let Err(e) = archive_result {
+        return Err(ClientError {
+            code: e.code,
+            message: format!("{} (code:{})", e.message, e.code),
+            http_code: Some(500),
+        });
+    };
+    task::yield_now().await;
+
+    // ensure the encoder has flushed
+    drop(archive_writer);
+
+    task::yield_now().await;
+
+    // do not proceed to starting the upload if the 'cancel requested' flag is set
+    if *cancel_requested.lock().unwrap() {
+        let _ = fs::remove_file(archive_path);
+        return Err(ClientError {
+            code: 997,
+            message: "Operation aborted by user request".to_string(),
+            http_code: None,
+        });
+    }
+
+    transfer.size = fs::metadata(archive_path).unwrap().len();
+
+    progress.send("starting upload").await;
+
+    // execute file upload
+    Client::remote_do_copy_async(progress, transfer, cancel_requested, bandwidth_limit, false).await
+}
+
+/// Returns the path in the temp data dir of the archive created for the
+/// upload `transfer_id`, with the extension of `codec`.
+pub fn get_upload_archive_path(transfer_id: &str, codec: Codec) -> String {
+    format!(
+        "{}{}.agent.{}",
+        CONFIG.temp_data_dir,
+        transfer_id,
+        codec.get_extension()
+    )
+}
+
+/// Returns the codec of the archive created for the upload `transfer_id`
+/// which is left in the temp data dir, if any. The codec of an interrupted
+/// transfer may not have been recorded, see `resolve_compression`.
+pub fn find_upload_archive(transfer_id: &str) -> Option<Codec> {
+    [Codec::None, Codec::Gzip, Codec::Zstd, Codec::Xz]
+        .into_iter()
+        .find(|codec| Path::new(&get_upload_archive_path(transfer_id, *codec)).exists())
+}
+
+/// Resolves the `auto` compression of a transfer to the codec suiting the
+/// latency of the remote and, if given, how well a sample of the local
+/// sources of `items` compresses. Fails if the remote cannot be reached.
+pub async fn resolve_compression(
+    transfer: &mut Transfer,
+    items: &[ArchiveItem],
+) -> Result<(), ClientError> {
+    if transfer.compress.codec != Codec::Auto {
+        return Ok(());
+    }
+    let host = transfer.host.clone();
+    let port: i16 = transfer.port.to_string().parse::<i16>().unwrap();
+    let agent_id = transfer.agent_id;
+    let source_base_path = transfer.local_path.clone();
+    let items = items.to_vec();
+
+    let compression = task::spawn_blocking(move || {
+        let (connect_ms, exec_ms) = Client::for_agent(&host, port, Some(agent_id)).ping()?;
+        // the time of running a command on the established session
+        let latency = match (connect_ms.parse::<u64>(), exec_ms.parse::<u64>()) {
+            (Ok(connect), Ok(exec)) => Some(exec.saturating_sub(connect)),
+            _ => None,
+        };
+        let sample = sample_sources(&source_base_path, &items);
+        let ratio = get_compression_ratio(&sample, ArchiveCompression::new(Codec::Zstd, 1));
+
+        Ok::<_, ClientError>(choose_compression(latency, ratio))
+    })
+    .await;
+
+    transfer.compress = match compression {
+        Ok(result) => result?,
+        Err(e) => {
+            return Err(ClientError {
+                code: 369,
+                message: e.to_string(),
+                http_code: Some(500),
+            })
+        }
+    };
+
+    Ok(())
+}
+
+/// Lists the destination of a sync transfer on the remote, so that the files
+/// which are unchanged there can be left out of the archive.
+pub async fn list_destination_async(
+    transfer: &Transfer,
+    items: String,
+    checksum: bool,
+) -> Result<DestinationListing, ClientError> {
+    let host = transfer.host.clone();
+    let port: i16 = transfer.port.to_string().parse::<i16>().unwrap();
+    let agent_id = transfer.agent_id;
+    let destination_root = transfer.remote_path.clone();
+
+    match task::spawn_blocking(move || {
+        Client::for_agent(&host, port, Some(agent_id)).list_remote_destination(
+            &destination_root,
+            checksum,
+            &items,
+        )
+    })
+    .await
+    {
+        Ok(result) => result,
+        Err(e) => Err(ClientError {
+            code: 361,
+            message: e.to_string(),
+            http_code: Some(500),
+        }),
+    }
+}
+
+/// Deletes the files at `paths`, relative to the destination of a mirror
+/// transfer, on the remote. Returns the number of files deleted.
+pub async fn delete_remote_files_async(
+    transfer: &Transfer,
+    paths: Vec<String>,
+) -> Result<usize, ClientError> {
+    let host = transfer.host.clone();
+    let port: i16 = transfer.port.to_string().parse::<i16>().unwrap();
+    let agent_id = transfer.agent_id;
+    let destination_root = transfer.remote_path.clone();
+
+    match task::spawn_blocking(move || {
+        Client::for_agent(&host, port, Some(agent_id))
+            .delete_remote_files(&destination_root, &paths)
+    })
+    .await
+    {
+        Ok(result) => result,
+        Err(e) => Err(ClientError {
+            code: 363,
+            message: e.to_string(),
+            http_code: Some(500),
+        }),
+    }
+}
+
+/// Runs the upload pipeline of a transfer in streaming mode: the archive is
+/// written straight into the stdin of the extract script on the remote, so
+/// archiving, sending and extracting overlap and no temp file is created.
+pub async fn stream_and_extract(
+    transfer: &mut Transfer,
+    items: Vec<ArchiveItem>,
+    progress: &ProgressSink,
+    cancel_requested: &Arc<Mutex<bool>>,
+    bandwidth_limit: &Arc<Mutex<u64>>,
+    options: ArchiveOptions,
+) -> Result<(), ClientError> {
+    resolve_compression(transfer, &items).await?;
+
+    // send progress update
+    let msg = match transfer.compress.is_compressed() {
+        true => "compressing",
+        false => "archiving",
+    };
+    progress.send(msg).await;
+    task::yield_now().await;
+
+    // the size of the archive is only known once complete, until then the
+    // size of the sources stands for it
+    transfer.size = get_sources_size(&transfer.local_path, &items);
+
+    // the ssh channel and the throttle block the thread, so the archive is
+    // created into the remote in a blocking task, like the sftp uploads,
+    // which reports the number of bytes written through a channel
+    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+    let runtime = Handle::current();
+    let mut streamed = transfer.clone();
+    let progress_sink = progress.clone();
+    let cancel = cancel_requested.clone();
+    let limit = bandwidth_limit.clone();
+    let stream = task::spawn_blocking(move || {
+        let result = runtime.block_on(stream_archive(
+            &mut streamed,
+            items,
+            &progress_sink,
+            &cancel,
+            &limit,
+            options,
+            tx,
+        ));
+        (streamed.moved_items, result)
+    });
+
+    // send upload status updates with the reported progress
+    while let Some(line) = rx.recv().await {
+        progress.send(&format!("progress::{}", line)).await;
+    }
+
+    let (moved_items, result) = stream.await.map_err(|e| ClientError {
+        code: 367,
+        message: e.to_string(),
+        http_code: Some(500),
+    })?;
+    transfer.moved_items = moved_items;
+
+    result
+}
+
+/// Starts extracting on the remote and writes the archive of the items into
+/// it while it is being created. Blocks the thread while writing.
+async fn stream_archive(
+    transfer: &mut Transfer,
+    items: Vec<ArchiveItem>,
+    progress: &ProgressSink,
+    cancel_requested: &Arc<Mutex<bool>>,
+    bandwidth_limit: &Arc<Mutex<u64>>,
+    options: ArchiveOptions,
+    stats: UnboundedSender<String>,
+) -> Result<(), ClientError> {
+    // start extracting on the remote before the archive is created
+    let port: i16 = transfer.port.to_string().parse::<i16>().unwrap();
+    let client = Client::for_agent(&transfer.host, port, Some(transfer.agent_id));
+    let manifest_name = get_manifest_name(&transfer.transfer_id);
+    let conflicts_name = get_conflicts_name(&transfer.transfer_id);
+    let channel = client.open_extract_stream(
+        &transfer.remote_path,
+        &manifest_name,
+        &conflicts_name,
+        transfer.overwrite && transfer.conflicts.is_empty(),
+        &transfer.metadata,
+    )?;
+    // the archive is uploaded while it is being created
+    progress.send("uploading").await;
+    let sent = Arc::new(AtomicU64::new(0));
+    let output = CancellableOutput {
+        inner: channel.stream(0),
+        cancel_requested: cancel_requested.clone(),
+        throttle: Throttle::new(bandwidth_limit.clone()),
+        sent: sent.clone(),
+        size: transfer.size,
+        stats: stats.clone(),
+        last_update_sent_at: Instant::now(),
+    };
+
+    // create archive of files into the channel
+    let mut archive_writer = match ArchiveWriter::from_output(
+        Box::new(output),
+        transfer.compress,
+        &transfer.local_path,
+        cancel_requested.clone(),
+        progress.clone(),
+        options,
+    ) {
+        Ok(w) => w,
+        Err(e) => {
+            Client::abort_extract_stream(channel);
+            return Err(ClientError {
+                code: e.code,
+                message: e.message,
+                http_code: Some(500),
+            });
+        }
+    };
+    let items_archived = items.clone();
+    let archive_result = match archive_writer.crate_archive(items).await {
+        Ok(_) => archive_writer.write_manifest(&manifest_name),
+        Err(e) => Err(e),
+    };
+    let archive_result =
+        archive_result.and_then(|_| write_conflict_rules(&mut archive_writer, transfer));
+    set_moved_items(transfer, &items_archived, &archive_writer);
+    send_files_skipped(
+        progress,
+        archive_writer.files_skipped(),
+        archive_writer.files_excluded(),
+    )
+    .await;
+
+    // ensure the encoder has flushed the end of the archive
+    drop(archive_writer);
+    task::yield_now().await;
+
+    // close the channel without completing the extraction if the 'cancel requested' flag is set
+    if *cancel_requested.lock().unwrap() {
+        Client::abort_extract_stream(channel);
+        return Err(ClientError {
+            code: 997,
+            message: "Operation aborted by user request".to_string(),
+            http_code: None,
+        });
+    }
+
+    if let Err(e) = archive_result {
+        Client::abort_extract_stream(channel);
+        return Err(ClientError {
+            code: e.code,
+            message: format!("{} (code:{})", e.message, e.code),
+            http_code: Some(500),
+        });
+    }
+
+    // the archive is complete, so its size is known
+    let sent = sent.load(Ordering::Relaxed);
+    send_stats(&stats, sent, sent);
+    drop(stats);
+
+    progress.send("extracting").await;
+
+    let output = Client::finish_extract_stream(channel)?;
+    send_conflicts(progress, &output).await;
+
+    Ok(())
+}
+
+/// Adds the conflict strategies of the items to the archive, if the transfer
+/// has any, for the remote to apply file by file.
+fn write_conflict_rules(
+    archive_writer: &mut ArchiveWriter,
+    transfer: &Transfer,
+) -> Result<(), ArchiveError> {
+    match transfer.conflicts.is_empty() {
+        true => Ok(()),
+        false => archive_writer.write_conflict_rules(
+            &get_conflicts_name(&transfer.transfer_id),
+            &transfer.conflicts,
+        ),
+    }
+}
+
+/// Records the items of a move whose sources were archived completely, only
+/// these are deleted once delivered.
+fn set_moved_items(transfer: &mut Transfer, items: &[ArchiveItem], archive_writer: &ArchiveWriter) {
+    if !transfer.move_sources {
+        return;
+    }
+    let incomplete = archive_writer.incomplete_items();
+    transfer.moved_items = Some(
+        items
+            .iter()
+            .filter(|item| !incomplete.contains(&item.source))
+            .cloned()
+            .collect(),
+    );
+}
+
+/// Reports the conflict strategies applied to the existing files, listed in
+/// the `conflicts::` line of the output of the extract script.
+pub async fn send_conflicts(progress: &ProgressSink, output: &str) {
+    for line in output.lines().filter(|l| l.starts_with("conflicts::")) {
+        progress.send(&format!("progress::{}", line)).await;
+    }
+}
+
+/// Reports the number of files left out of the archive of a sync transfer
+/// and the number of entries left out by the skip rules.
+async fn send_files_skipped(
+    progress: &ProgressSink,
+    files_skipped: Option<usize>,
+    files_excluded: Option<usize>,
+) {
+    if let Some(count) = files_skipped {
+        progress
+            .send(&format!("progress::skipped::{}", count))
+            .await;
+    }
+    if let Some(count) = files_excluded {
+        progress
+            .send(&format!("progress::excluded::{}", count))
+            .await;
+    }
+}
+
+/// Writer passing the archive through to `inner` which refuses any more
+/// writes once the 'cancel requested' flag is set, so that an aborted archive
+/// is not completed and extracted on the remote. The rate of writing is
+/// limited by `throttle` and the number of bytes written out of the
+/// expected `size` is reported to `stats` at most once a second.
+struct CancellableOutput<W: Write> {
+    inner: W,
+    cancel_requested: Arc<Mutex<bool>>,
+    throttle: Throttle,
+    sent: Arc<AtomicU64>,
+    size: u64,
+    stats: UnboundedSender<String>,
+    last_update_sent_at: Instant,
+}
+
+impl<W: Write> Write for CancellableOutput<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if *self.cancel_requested.lock().unwrap() {
+            // not `Interrupted`, which `write_all` would retry indefinitely
+            return Err(Error::new(
+                ErrorKind::BrokenPipe,
+                "Operation aborted by user request",
+            ));
+        }
+        let count = self.inner.write(buf)?;
+        self.throttle.wait(count as u64, &self.cancel_requested);
+
+        let sent = self.sent.fetch_add(count as u64, Ordering::Relaxed) + count as u64;
+        if self.last_update_sent_at.elapsed() > Duration::from_secs(1) {
+            self.last_update_sent_at = Instant::now();
+            // compressed archives may end up smaller than the sources, but
+            // the archive is never reported to be larger than it is
+            send_stats(&self.stats, sent, self.size.max(sent));
+        }
+
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Sends the final status update of a transfer based on its result.
+pub async fn send_transfer_result(
+    progress: &ProgressSink,
+    result: Result<(), ClientError>,
+) -> Result<(), ClientError> {
+    match result {
+        Ok(_) => {
+            // send transfer status update indicating normal completion
+            progress.send("complete").await;
+            Ok(())
+        }
+        Err(e) => {
+            // decide what message to send as transfer status update
+            let err_msg = match e.code {
+                // interrupted (SIGUSR1)
+                346 => Some(format!("signal::interrupt::{}", e.message)),
+                // aborted by user request - not an error
+                997 => None,
+                // all other errors
+                _ => Some(e.message.clone()),
+            };
+
+            // send transfer status update about the error
+            match err_msg {
+                None => Ok(()),
+                Some(message) => {
+                    progress.send(&message).await;
+                    Err(e)
+                }
+            }
+        }
+    }
+}
@@ -1,12 +1,17 @@
 use reqwest::{blocking::Response, Response as AsyncResponse, StatusCode};
 use rocket::{http::Cookie, serde::json::serde_json};
-use std::{env, io::Read, time::Duration};
+use std::{io::Read, time::Duration};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use crate::client::ClientError;
+use crate::{
+    archive::{ArchiveCompression, ArchiveItem, MetadataPolicy},
+    client::ClientError,
+    conflict::ConflictRule,
+    filter::SkipRules,
+};
 
-use crate::constants::DEFAULTS;
+use crate::config::CONFIG;
 
 #[derive(Deserialize, Debug)]
 pub struct Agent {
@@ -16,6 +21,8 @@ pub struct Agent {
     pub host: String,
     pub port: String,
     pub remote_user: RemoteUser,
+    #[serde(default)]
+    pub bandwidth_limit: u64,
 }
 
 #[derive(Deserialize, Debug)]
@@ -30,6 +37,16 @@ pub struct FilesUser {
     pub id: u32,
     pub username: String,
     pub scope: String,
+    #[serde(default)]
+    pub perm: FilesPermissions,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct FilesPermissions {
+    #[serde(default)]
+    pub admin: bool,
+    #[serde(default)]
+    pub delete: bool,
 }
 
 #[derive(Debug)]
@@ -39,7 +56,7 @@ pub struct RequestError {
     pub http_code: Option<u16>,
 }
 
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Transfer {
     pub agent_id: u32,
     pub host: String,
@@ -47,9 +64,28 @@ pub struct Transfer {
     pub transfer_id: String,
     pub local_path: String,
     pub remote_path: String,
-    pub compress: bool,
+    pub compress: ArchiveCompression,
     pub overwrite: bool,
     pub size: u64,
+    #[serde(default)]
+    pub metadata: MetadataPolicy,
+    /// number of parallel streams the archive is uploaded over
+    #[serde(default)]
+    pub streams: usize,
+    #[serde(default)]
+    pub skip_rules: SkipRules,
+    /// conflict strategies of the items, empty if the transfer applies
+    /// `overwrite` to all of them
+    #[serde(default)]
+    pub conflicts: Vec<ConflictRule>,
+    /// delete the sources once the remote confirmed their delivery
+    #[serde(default)]
+    pub move_sources: bool,
+    /// the items archived completely, whose sources a move deletes, known
+    /// once the archive has been created
+    #[serde(default)]
+    pub moved_items: Option<Vec<ArchiveItem>>,
+    #[serde(skip)]
     pub rc_auth: String,
 }
 
@@ -65,6 +101,12 @@ impl Clone for Transfer {
             compress: self.compress,
             overwrite: self.overwrite,
             size: self.size,
+            metadata: self.metadata.clone(),
+            streams: self.streams,
+            skip_rules: self.skip_rules.clone(),
+            conflicts: self.conflicts.clone(),
+            move_sources: self.move_sources,
+            moved_items: self.moved_items.clone(),
             rc_auth: self.rc_auth.clone(),
         }
     }
@@ -249,6 +291,62 @@ impl FilesApi {
         Ok(user)
     }
 
+    /// Exchanges the JWT token `auth_token` for a new one valid for the full
+    /// lifetime of a Files session.
+    pub async fn renew_token(&self, auth_token: &str) -> Result<String, RequestError> {
+        let response = self
+            .make_async_request(
+                "POST",
+                "/api/renew",
+                None,
+                Some(auth_token.to_string()),
+                None,
+            )
+            .await
+            .map_err(|e| RequestError {
+                code: 429,
+                message: e.message,
+                http_code: Some(500),
+            })?;
+
+        // fail if response is not 2xx
+        if !response.status().is_success() {
+            return Err(RequestError {
+                code: 430,
+                message: response.status().to_string(),
+                http_code: Some(response.status().as_u16()),
+            });
+        }
+
+        Ok(response.text().await.unwrap_or_default())
+    }
+
+    /// Deletes the resource at `path`, as in a Files URL and with its
+    /// components URL encoded, on behalf of the user with the JWT token
+    /// `auth_token`. Files checks the user's permission to delete it.
+    pub async fn delete_resource(&self, path: &str, auth_token: &str) -> Result<(), RequestError> {
+        let uri = format!("/api/resources{}", path.replacen("/files", "", 1));
+        let response = self
+            .make_async_request("DELETE", &uri, None, Some(auth_token.to_string()), None)
+            .await
+            .map_err(|e| RequestError {
+                code: 427,
+                message: e.message,
+                http_code: Some(500),
+            })?;
+
+        // fail if response is not 2xx
+        if !response.status().is_success() {
+            return Err(RequestError {
+                code: 428,
+                message: response.status().to_string(),
+                http_code: Some(response.status().as_u16()),
+            });
+        }
+
+        Ok(())
+    }
+
     pub async fn send_upload_status_update_async(&self, transfer: &Transfer, message: &str) {
         let uri = format!(
             "/api/agent/{}/transfers/{}/update/{message}",
@@ -327,8 +425,29 @@ impl FilesApi {
         token: String,
         items: String,
     ) -> Result<String, ClientError> {
-        // send the local-before-copy request to Files api
-        let uri = format!("/api/agent/{user_id}?action=remote-copy");
+        self.local_before_action(user_id, token, items, "remote-copy")
+    }
+
+    /// Asks Files to verify that the user can read all `items` and returns
+    /// the user's scope root, which is the base path of the download sources.
+    pub fn local_before_download(
+        &self,
+        user_id: u32,
+        token: String,
+        items: String,
+    ) -> Result<String, ClientError> {
+        self.local_before_action(user_id, token, items, "remote-download")
+    }
+
+    fn local_before_action(
+        &self,
+        user_id: u32,
+        token: String,
+        items: String,
+        action: &str,
+    ) -> Result<String, ClientError> {
+        // send the local-before-copy (or -download) request to Files api
+        let uri = format!("/api/agent/{user_id}?action={action}");
         let mut response = match self.make_request("PATCH", &uri, Some(items), None, Some(token)) {
             Ok(r) => r,
             Err(e) => {
@@ -440,7 +559,7 @@ impl FilesApi {
         );
 
         let client = reqwest::blocking::Client::builder()
-            .timeout(Duration::from_secs(10))
+            .timeout(Duration::from_secs(CONFIG.request_timeout))
             .build()
             .unwrap();
 
@@ -510,7 +629,7 @@ impl FilesApi {
         );
 
         let client = reqwest::Client::builder()
-            .timeout(Duration::from_secs(10))
+            .timeout(Duration::from_secs(CONFIG.request_timeout))
             .build()
             .unwrap();
 
@@ -569,9 +688,6 @@ impl FilesApi {
     }
 
     fn get_base_url() -> String {
-        let default_fb_api_address = DEFAULTS.default_fb_api_address;
-        let fb_api_address_result = env::var(DEFAULTS.env_name_fb_api_address);
-
-        fb_api_address_result.unwrap_or(default_fb_api_address.to_string())
+        CONFIG.files_address.trim_end_matches('/').to_string()
     }
 }
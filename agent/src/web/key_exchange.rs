@@ -1,10 +1,35 @@
 use rocket::{
     http::{CookieJar, Status},
-    serde::{json::Json, Deserialize, Serialize},
+    serde::{
+        json::{serde_json, Json},
+        Deserialize, Serialize,
+    },
     State,
 };
+use std::{
+    fs,
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    command_runner::run_command_async,
+    config::CONFIG,
+    constants::{
+        COMMAND_EXCHANGE_KEYS, COMMAND_GET_REMOTE_HOST_KEY, COMMAND_REMOTE_ADD_KEY,
+        COMMAND_REMOTE_REMOVE_KEY, COMMAND_REPIN_REMOTE_HOST_KEY,
+    },
+    keys::{
+        forget_connection, get_connection_key_files, get_own_key_fingerprint,
+        get_previous_key_files, get_previous_key_fingerprint, list_connections, record_connection,
+        remove_connection_key, Connection,
+    },
+    Files,
+};
 
-use crate::{command_runner::run_command_async, constants::COMMAND_EXCHANGE_KEYS, Files};
+/// Set while the key pair of this agent is being rotated, a single rotation
+/// may run at a time.
+static ROTATING_KEY: AtomicBool = AtomicBool::new(false);
 
 #[derive(Deserialize, Debug)]
 #[serde(crate = "rocket::serde")]
@@ -12,6 +37,9 @@ pub struct HostInfo<'r> {
     host: &'r str,
     port: &'r str,
     secret: Option<&'r str>,
+    /// id of the agent in Files, a key pair is generated for the connection
+    /// if given
+    agent_id: Option<u32>,
 }
 
 #[derive(Serialize, Debug)]
@@ -19,6 +47,79 @@ pub struct RegisterPublicKeyResponse {
     success: Option<bool>,
     error: Option<String>,
     code: Option<i32>,
+    /// fingerprint of the key the remote agent was given
+    fingerprint: Option<String>,
+}
+
+/// HostKey is the key presented by a remote agent. Its status is "match"
+/// if it is the pinned key, "mismatch" if another key is pinned for the
+/// host and "not-found" if none is.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct HostKey {
+    status: String,
+    #[serde(rename = "type")]
+    key_type: String,
+    fingerprint: String,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct HostKeyResponse {
+    code: i32,
+    host_key: Option<HostKey>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct RepinHostKeyRequest {
+    /// fingerprint of the new key, verified by the user out of band
+    fingerprint: String,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct KeysResponse {
+    code: i32,
+    /// fingerprint of the current key of this agent
+    fingerprint: Option<String>,
+    /// fingerprint of the key before the last rotation, if it is still kept
+    /// for remote agents which were not moved on to the current key
+    previous_fingerprint: Option<String>,
+    connections: Vec<Connection>,
+    error: Option<String>,
+}
+
+/// RemoteRotation is the outcome of a rotation on a remote agent. The new
+/// key is trusted by all of them once the rotation succeeds, `error` tells
+/// why the old key could not be removed.
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct RemoteRotation {
+    host: String,
+    port: i16,
+    error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct RotateKeyResponse {
+    code: i32,
+    fingerprint: Option<String>,
+    remotes: Vec<RemoteRotation>,
+    /// fingerprint of the previous key, which is kept as remote agents paired
+    /// before the connections were recorded, and idle since, may still trust
+    /// only it. They are moved on to the new key when next connected.
+    previous_fingerprint: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct RevokeKeyResponse {
+    code: i32,
+    error: Option<String>,
 }
 
 #[post("/users/<user_id>/connections", data = "<host_info>")]
@@ -41,23 +142,31 @@ pub async fn register_public_key(
                 code: Some(err.code),
                 success: None,
                 error: Some(err.message),
+                fingerprint: None,
             }),
         );
     }
 
-    let args: Vec<&str> = vec![
+    let agent_id = host_info.agent_id.map(|id| id.to_string());
+    let user_id = user_id.to_string();
+    let mut args: Vec<&str> = vec![
         host_info.host,
         host_info.port,
         host_info.secret.unwrap_or(""),
     ];
+    if let Some(agent_id) = &agent_id {
+        args.push(agent_id);
+        args.push(&user_id);
+    }
 
     match run_command_async(201, true, false, COMMAND_EXCHANGE_KEYS, args).await {
-        Ok(_) => (
+        Ok(fingerprint) => (
             Status::Ok,
             Json(RegisterPublicKeyResponse {
                 success: Some(true),
                 error: None,
                 code: None,
+                fingerprint: Some(fingerprint.trim().to_string()),
             }),
         ),
         Err(err) => (
@@ -66,7 +175,434 @@ pub async fn register_public_key(
                 code: Some(err.code),
                 success: None,
                 error: Some(err.message),
+                fingerprint: None,
+            }),
+        ),
+    }
+}
+
+#[get("/agents/<agent_id>/host-key")]
+pub async fn get_host_key(
+    agent_id: u32,
+    files: &State<Files>,
+    cookies: &CookieJar<'_>,
+) -> (Status, Json<HostKeyResponse>) {
+    // verify that the requester has a valid session in Files and owns the referred agent
+    let (agent, _) = match files.api.get_agent(agent_id, cookies.get("rc_auth")).await {
+        Ok(a) => a,
+        Err(e) => {
+            return (
+                Status::Forbidden,
+                Json(HostKeyResponse {
+                    code: 934,
+                    host_key: None,
+                    error: Some(e.message),
+                }),
+            )
+        }
+    };
+
+    let args: Vec<&str> = vec![&agent.host, &agent.port];
+    let output = match run_command_async(205, true, false, COMMAND_GET_REMOTE_HOST_KEY, args).await
+    {
+        Ok(o) => o,
+        Err(err) => {
+            return (
+                err.status,
+                Json(HostKeyResponse {
+                    code: err.code,
+                    host_key: None,
+                    error: Some(err.message),
+                }),
+            )
+        }
+    };
+
+    match serde_json::from_str::<HostKey>(&output) {
+        Ok(host_key) => (
+            Status::Ok,
+            Json(HostKeyResponse {
+                code: 0,
+                host_key: Some(host_key),
+                error: None,
+            }),
+        ),
+        Err(e) => (
+            Status::InternalServerError,
+            Json(HostKeyResponse {
+                code: 936,
+                host_key: None,
+                error: Some(e.to_string()),
+            }),
+        ),
+    }
+}
+
+/// Pins the key a remote agent presents after a legitimate rotation of its
+/// host key. The fingerprint of the new key has to be given to confirm that
+/// it is the key verified by the user.
+#[put("/agents/<agent_id>/host-key", data = "<request>")]
+pub async fn repin_host_key(
+    agent_id: u32,
+    request: Json<RepinHostKeyRequest>,
+    files: &State<Files>,
+    cookies: &CookieJar<'_>,
+) -> (Status, Json<HostKeyResponse>) {
+    // verify that the requester has a valid session in Files and owns the referred agent
+    let (agent, _) = match files.api.get_agent(agent_id, cookies.get("rc_auth")).await {
+        Ok(a) => a,
+        Err(e) => {
+            return (
+                Status::Forbidden,
+                Json(HostKeyResponse {
+                    code: 935,
+                    host_key: None,
+                    error: Some(e.message),
+                }),
+            )
+        }
+    };
+
+    let args: Vec<&str> = vec![&agent.host, &agent.port, &request.fingerprint];
+    match run_command_async(206, true, false, COMMAND_REPIN_REMOTE_HOST_KEY, args).await {
+        Ok(_) => (
+            Status::Ok,
+            Json(HostKeyResponse {
+                code: 0,
+                host_key: None,
+                error: None,
+            }),
+        ),
+        Err(err) => (
+            err.status,
+            Json(HostKeyResponse {
+                code: err.code,
+                host_key: None,
+                error: Some(err.message),
             }),
         ),
     }
 }
+
+/// Lists the remote agents trusting a key of this agent and the fingerprint
+/// of the key each of them trusts.
+#[get("/users/<user_id>/keys")]
+pub async fn list_keys(
+    user_id: u32,
+    files: &State<Files>,
+    cookies: &CookieJar<'_>,
+) -> (Status, Json<KeysResponse>) {
+    // the key of the agent is shared by all users, only admins may manage it
+    let (status, code) = match files
+        .api
+        .get_auth_user(user_id, cookies.get("rc_auth"))
+        .await
+    {
+        Ok(user) if user.perm.admin => (Status::Ok, 0),
+        Ok(_) => (Status::Forbidden, 939),
+        Err(_) => (Status::Unauthorized, 937),
+    };
+    if code != 0 {
+        return (
+            status,
+            Json(KeysResponse {
+                code,
+                fingerprint: None,
+                previous_fingerprint: None,
+                connections: Vec::new(),
+                error: None,
+            }),
+        );
+    }
+
+    (
+        Status::Ok,
+        Json(KeysResponse {
+            code: 0,
+            fingerprint: get_own_key_fingerprint().ok(),
+            previous_fingerprint: get_previous_key_fingerprint(),
+            connections: list_connections(),
+            error: None,
+        }),
+    )
+}
+
+/// Replaces the key pair of this agent. The new public key is added on every
+/// remote agent connected with it before it is used, the rotation is rolled back if
+/// any of them does not accept it. The old key is then removed from the
+/// remote agents with a session authenticated by the new key, but kept by
+/// this agent for the remotes which are not recorded. A rotation replacing
+/// a key kept that way is refused unless `discard_previous` confirms that
+/// no remote relies on it anymore.
+#[post("/users/<user_id>/keys/rotate?<discard_previous>")]
+pub async fn rotate_key(
+    user_id: u32,
+    discard_previous: Option<bool>,
+    files: &State<Files>,
+    cookies: &CookieJar<'_>,
+) -> (Status, Json<RotateKeyResponse>) {
+    // the key of the agent is shared by all users, only admins may manage it
+    let (status, code) = match files
+        .api
+        .get_auth_user(user_id, cookies.get("rc_auth"))
+        .await
+    {
+        Ok(user) if user.perm.admin => (Status::Ok, 0),
+        Ok(_) => (Status::Forbidden, 939),
+        Err(_) => (Status::Unauthorized, 938),
+    };
+    if code != 0 {
+        return (status, Json(rotation_error(code, None)));
+    }
+
+    if ROTATING_KEY
+        .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        return (
+            Status::Conflict,
+            Json(rotation_error(
+                942,
+                Some("A key rotation is already running".to_string()),
+            )),
+        );
+    }
+    let result = rotate(discard_previous.unwrap_or(false)).await;
+    ROTATING_KEY.store(false, Ordering::SeqCst);
+
+    match result {
+        Ok((fingerprint, remotes)) => (
+            Status::Ok,
+            Json(RotateKeyResponse {
+                code: 0,
+                fingerprint: Some(fingerprint),
+                remotes,
+                previous_fingerprint: get_previous_key_fingerprint(),
+                error: None,
+            }),
+        ),
+        Err((status, code, message)) => (status, Json(rotation_error(code, Some(message)))),
+    }
+}
+
+/// Removes the key of the connection from the remote agent, which then no
+/// longer accepts connections with it until paired again. The key of a
+/// connection paired for the agent is deleted, otherwise the key of this
+/// agent is removed from the remote, which requires an admin as it may be
+/// trusted for other users' agents too.
+#[delete("/agents/<agent_id>/key")]
+pub async fn revoke_key(
+    agent_id: u32,
+    files: &State<Files>,
+    cookies: &CookieJar<'_>,
+) -> (Status, Json<RevokeKeyResponse>) {
+    // verify that the requester has a valid session in Files and owns the referred agent
+    let (agent, _) = match files.api.get_agent(agent_id, cookies.get("rc_auth")).await {
+        Ok(a) => a,
+        Err(e) => {
+            return (
+                Status::Forbidden,
+                Json(RevokeKeyResponse {
+                    code: 940,
+                    error: Some(e.message),
+                }),
+            )
+        }
+    };
+
+    let (_, connection_public_key_file) = get_connection_key_files(agent_id);
+    let own_key = Path::new(&connection_public_key_file).is_file();
+    let public_key_file = match own_key {
+        true => connection_public_key_file.as_str(),
+        false => CONFIG.public_key_file.as_str(),
+    };
+
+    // the key of this agent is shared by all users, only admins may revoke it
+    if !own_key {
+        let is_admin = match files
+            .api
+            .get_auth_user(agent.user_id, cookies.get("rc_auth"))
+            .await
+        {
+            Ok(user) => user.perm.admin,
+            Err(_) => false,
+        };
+        if !is_admin {
+            return (
+                Status::Forbidden,
+                Json(RevokeKeyResponse {
+                    code: 945,
+                    error: Some(
+                        "The agent authenticates with the shared key, only admins may revoke it"
+                            .to_string(),
+                    ),
+                }),
+            );
+        }
+    }
+
+    let args: Vec<&str> = vec![&agent.host, &agent.port, public_key_file];
+    if let Err(err) = run_command_async(209, true, false, COMMAND_REMOTE_REMOVE_KEY, args).await {
+        return (
+            err.status,
+            Json(RevokeKeyResponse {
+                code: err.code,
+                error: Some(err.message),
+            }),
+        );
+    }
+
+    let port = agent.port.parse::<i16>().unwrap_or(0);
+    if own_key {
+        remove_connection_key(agent_id);
+        let _ = forget_connection(&agent.host, port, Some(agent_id));
+    } else {
+        let _ = forget_connection(&agent.host, port, None);
+    }
+
+    (
+        Status::Ok,
+        Json(RevokeKeyResponse {
+            code: 0,
+            error: None,
+        }),
+    )
+}
+
+fn rotation_error(code: i32, error: Option<String>) -> RotateKeyResponse {
+    RotateKeyResponse {
+        code,
+        fingerprint: None,
+        remotes: Vec::new(),
+        previous_fingerprint: None,
+        error,
+    }
+}
+
+/// Rotates the key pair of this agent and returns the fingerprint of the new
+/// key and the outcome on each remote agent.
+async fn rotate(
+    discard_previous: bool,
+) -> Result<(String, Vec<RemoteRotation>), (Status, i32, String)> {
+    let private_key_file = CONFIG.private_key_file.as_str();
+    let public_key_file = CONFIG.public_key_file.as_str();
+    let new_private_key_file = format!("{private_key_file}.new");
+    let new_public_key_file = format!("{new_private_key_file}.pub");
+    let (old_private_key_file, old_public_key_file) = get_previous_key_files();
+
+    // the remotes which were not moved on to the current key yet would be
+    // locked out if the key kept for them was replaced
+    if let Some(previous_fingerprint) = get_previous_key_fingerprint() {
+        if !discard_previous {
+            return Err((
+                Status::Conflict,
+                946,
+                format!(
+                    "The previous key {previous_fingerprint} is kept for remotes not \
+                    migrated to the current key yet, confirm discarding it to rotate again"
+                ),
+            ));
+        }
+    }
+
+    // leftovers of an interrupted rotation would make ssh-keygen prompt
+    let _ = fs::remove_file(&new_private_key_file);
+    let _ = fs::remove_file(&new_public_key_file);
+    let args: Vec<&str> = vec![
+        "-q",
+        "-t",
+        "rsa",
+        "-b",
+        "4096",
+        "-N",
+        "",
+        "-f",
+        &new_private_key_file,
+    ];
+    if let Err(err) = run_command_async(207, false, false, "ssh-keygen", args).await {
+        return Err((err.status, err.code, err.message));
+    }
+
+    // add the new key on all remote agents before using it, the connections
+    // with a key pair of their own are not affected
+    let connections: Vec<Connection> = list_connections()
+        .into_iter()
+        .filter(|c| c.agent_id.is_none())
+        .collect();
+    let mut added: Vec<&Connection> = Vec::new();
+    for connection in &connections {
+        let port = connection.port.to_string();
+        let args: Vec<&str> = vec![
+            &connection.host,
+            &port,
+            &new_public_key_file,
+            &new_private_key_file,
+        ];
+        if let Err(err) = run_command_async(208, true, false, COMMAND_REMOTE_ADD_KEY, args).await {
+            discard_key(&added, &new_private_key_file, &new_public_key_file).await;
+            return Err((
+                Status::BadGateway,
+                941,
+                format!(
+                    "Rotation rolled back, {}:{} did not accept the new key: {}",
+                    connection.host, connection.port, err.message
+                ),
+            ));
+        }
+        added.push(connection);
+    }
+
+    // switch to the new key, keeping the old one to remove it from the remotes
+    // and for the remotes which are not recorded
+    let _ = fs::remove_file(&old_private_key_file);
+    let _ = fs::remove_file(&old_public_key_file);
+    let switched = fs::rename(private_key_file, &old_private_key_file)
+        .and_then(|_| fs::rename(public_key_file, &old_public_key_file))
+        .and_then(|_| fs::rename(&new_private_key_file, private_key_file))
+        .and_then(|_| fs::rename(&new_public_key_file, public_key_file));
+    if let Err(e) = switched {
+        // put back whichever of the old files were moved already
+        if fs::metadata(private_key_file).is_err() {
+            let _ = fs::rename(&old_private_key_file, private_key_file);
+        }
+        if fs::metadata(public_key_file).is_err() {
+            let _ = fs::rename(&old_public_key_file, public_key_file);
+        }
+        discard_key(&added, &new_private_key_file, &new_public_key_file).await;
+        return Err((
+            Status::InternalServerError,
+            941,
+            format!("Rotation rolled back, cannot switch to the new key: {e}"),
+        ));
+    }
+    let fingerprint = get_own_key_fingerprint().unwrap_or_default();
+
+    // remove the old key, the sessions are authenticated with the new one
+    let mut remotes = Vec::new();
+    for connection in &connections {
+        let port = connection.port.to_string();
+        let args: Vec<&str> = vec![&connection.host, &port, &old_public_key_file];
+        let result = run_command_async(209, true, false, COMMAND_REMOTE_REMOVE_KEY, args).await;
+        let _ = record_connection(&connection.host, connection.port, None, None, &fingerprint);
+        remotes.push(RemoteRotation {
+            host: connection.host.clone(),
+            port: connection.port,
+            error: result.err().map(|err| err.message),
+        });
+    }
+
+    Ok((fingerprint, remotes))
+}
+
+/// Removes the new key from the remote agents in `connections`, which were
+/// given it, and deletes its files.
+async fn discard_key(connections: &[&Connection], private_key_file: &str, public_key_file: &str) {
+    for connection in connections {
+        let port = connection.port.to_string();
+        let args: Vec<&str> = vec![&connection.host, &port, public_key_file];
+        let _ = run_command_async(209, true, false, COMMAND_REMOTE_REMOVE_KEY, args).await;
+    }
+
+    let _ = fs::remove_file(private_key_file);
+    let _ = fs::remove_file(public_key_file);
+}
@@ -0,0 +1,205 @@
+use rocket::serde::Serialize;
+use std::{fs, fs::File, os::unix::fs::FileTypeExt, path::Path};
+use urlencoding::decode;
+
+use crate::{
+    archive::{
+        get_compression_ratio, read_sample, ArchiveCompression, ArchiveItem, MetadataPolicy,
+        SymlinkPolicy,
+    },
+    filter::{join_path, SkipFilter},
+    sync::DestinationListing,
+};
+
+/// Size of a tar header, archive entries are padded to a multiple of it.
+const TAR_BLOCK_SIZE: u64 = 512;
+
+/// Longest path a tar header holds without an extra GNU long name entry.
+const TAR_NAME_SIZE: usize = 100;
+
+/// Length of a manifest line without the path: digest, separator and newline.
+const MANIFEST_LINE_SIZE: u64 = 64 + 2 + 1;
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct SkippedPath {
+    path: String,
+    reason: String,
+}
+
+/// CopyPlan describes what copying a set of items would do without
+/// creating an archive.
+#[derive(Serialize, Debug, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct CopyPlan {
+    files: usize,
+    bytes: u64,
+    conflicts: Vec<String>,
+    skipped: Vec<SkippedPath>,
+    excluded: usize,
+    compression: String,
+    estimated_archive_size: u64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct PlanResponse {
+    pub code: i32,
+    pub plan: Option<CopyPlan>,
+    pub error: Option<String>,
+}
+
+struct PlanWalker<'a> {
+    plan: CopyPlan,
+    metadata: &'a MetadataPolicy,
+    filter: SkipFilter,
+    listing: &'a DestinationListing,
+    tar_size: u64,
+    sample: Vec<u8>,
+}
+
+/// Walks the sources of `items` the way `ArchiveWriter` does and returns
+/// the plan of copying them under the `metadata` policy, leaving out the
+/// entries skipped by `filter`. Files which are present in the destination
+/// `listing` are reported as conflicts.
+pub fn build_plan(
+    source_base_path: &str,
+    items: &[ArchiveItem],
+    compression: ArchiveCompression,
+    metadata: &MetadataPolicy,
+    filter: SkipFilter,
+    listing: &DestinationListing,
+) -> CopyPlan {
+    let mut walker = PlanWalker {
+        plan: CopyPlan::default(),
+        metadata,
+        filter,
+        listing,
+        tar_size: 0,
+        sample: Vec::new(),
+    };
+
+    for item in items.iter() {
+        let src_ = decode(&item.source).unwrap().into_owned();
+        let dst_ = decode(&item.destination).unwrap().into_owned();
+        let src = src_.replacen("/files", source_base_path, 1);
+        let dst = String::from(dst_.trim_start_matches('/'));
+
+        walker.add(Path::new(&src), dst, "");
+    }
+
+    // the archive ends with the header of the manifest and two empty blocks
+    let mut plan = walker.plan;
+    plan.estimated_archive_size = walker.tar_size + 3 * TAR_BLOCK_SIZE;
+    plan.compression = compression.to_string();
+    if compression.is_compressed() {
+        plan.estimated_archive_size =
+            estimate_compressed_size(plan.estimated_archive_size, &walker.sample, compression);
+    }
+
+    plan
+}
+
+impl PlanWalker<'_> {
+    fn add(&mut self, src_path: &Path, path: String, item_path: &str) {
+        let src_meta = match self.metadata.symlinks {
+            SymlinkPolicy::Keep => src_path.symlink_metadata(),
+            SymlinkPolicy::Follow => src_path.metadata(),
+        };
+        let src_meta = match src_meta {
+            Ok(m) => m,
+            Err(e) => return self.skip(path, e.to_string()),
+        };
+
+        if !item_path.is_empty() && self.filter.is_skipped(item_path, src_meta.is_dir()) {
+            self.plan.excluded += 1;
+            return;
+        }
+
+        if src_meta.is_dir() {
+            if self.metadata.empty_dirs {
+                self.tar_size += get_tar_entry_size(&path, 0);
+            }
+            let entries = match fs::read_dir(src_path) {
+                Ok(e) => e,
+                Err(e) => return self.skip(path, e.to_string()),
+            };
+            self.filter.enter_dir(src_path, item_path);
+            for entry in entries {
+                match entry {
+                    Ok(entry) => {
+                        let file_name = entry.file_name().to_string_lossy().into_owned();
+                        self.add(
+                            &entry.path(),
+                            format!("{}/{}", path, file_name),
+                            &join_path(item_path, &file_name),
+                        );
+                    }
+                    Err(e) => self.skip(path.clone(), e.to_string()),
+                }
+            }
+            self.filter.leave_dir();
+            return;
+        }
+
+        // links and special files are archived as entries without data if
+        // the policy keeps them, other non regular files are not archived
+        let file_type = src_meta.file_type();
+        let is_special =
+            file_type.is_fifo() || file_type.is_char_device() || file_type.is_block_device();
+        if file_type.is_symlink() || (is_special && self.metadata.special_files) {
+            self.tar_size += get_tar_entry_size(&path, 0);
+            self.plan.files += 1;
+            return;
+        }
+        if !file_type.is_file() {
+            return self.skip(path, "not a regular file".to_string());
+        }
+
+        let mut file = match File::open(src_path) {
+            Ok(f) => f,
+            Err(e) => return self.skip(path, e.to_string()),
+        };
+        read_sample(&mut self.sample, &mut file);
+
+        self.tar_size += get_tar_entry_size(&path, src_meta.len());
+        self.tar_size += MANIFEST_LINE_SIZE + path.len() as u64;
+        self.plan.files += 1;
+        self.plan.bytes += src_meta.len();
+        if self.listing.contains_key(&path) {
+            self.plan.conflicts.push(path);
+        }
+    }
+
+    fn skip(&mut self, path: String, reason: String) {
+        self.plan.skipped.push(SkippedPath { path, reason });
+    }
+}
+
+/// Returns the size of the tar entry of a file, including the extra entry
+/// holding a long path.
+fn get_tar_entry_size(path: &str, size: u64) -> u64 {
+    let mut entry_size = TAR_BLOCK_SIZE + pad_to_block(size);
+    if path.len() > TAR_NAME_SIZE {
+        entry_size += TAR_BLOCK_SIZE + pad_to_block(path.len() as u64 + 1);
+    }
+
+    entry_size
+}
+
+/// Rounds `size` up to a multiple of the tar block size.
+fn pad_to_block(size: u64) -> u64 {
+    match size % TAR_BLOCK_SIZE {
+        0 => size,
+        rest => size + TAR_BLOCK_SIZE - rest,
+    }
+}
+
+/// Estimates the compressed size of an archive of `size` bytes from the
+/// compression ratio of `sample` with the codec and level of the transfer.
+fn estimate_compressed_size(size: u64, sample: &[u8], compression: ArchiveCompression) -> u64 {
+    match get_compression_ratio(sample, compression) {
+        Some(ratio) => (size as f64 * ratio) as u64,
+        None => size,
+    }
+}
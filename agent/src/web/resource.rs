@@ -1,42 +1,170 @@
 use rocket::{
-    http::{CookieJar, Status},
-    serde::{json::Json, Deserialize, Serialize},
-    tokio::{task, time},
+    http::{Cookie, CookieJar, Status},
+    serde::{
+        json::{serde_json, Json},
+        Deserialize, Serialize,
+    },
+    tokio::{task, task::JoinHandle, time},
     State,
 };
 use std::{
     collections::HashMap,
-    fs,
     sync::{Arc, Mutex},
     time::Duration,
 };
-use urlencoding::encode;
+use urlencoding::{decode, encode};
 
-use crate::client::Client;
+use crate::client::{Client, ClientError};
 
 use crate::{
-    archive::{ArchiveItem, ArchiveWriter},
+    archive::{ArchiveCompression, ArchiveItem, ArchiveOptions, MetadataPolicy, ProgressSink},
     command_runner::run_command_async,
-    constants::{COMMAND_GET_REMOTE_RESOURCE, COMMAND_REMOTE_BEFORE_COPY, DEFAULTS},
-    files_api::{FilesApi, Transfer},
-    CancelTransferRequests, Files,
+    conflict::{ConflictRule, ConflictStrategy},
+    constants::{
+        COMMAND_GET_REMOTE_RESOURCE, COMMAND_LOCAL_BEFORE_COPY, COMMAND_REMOTE_BEFORE_COPY,
+        COMMAND_REMOTE_BEFORE_DOWNLOAD, COMMAND_REMOTE_CHECK_PAIRING,
+        COMMAND_REMOTE_CREATE_ARCHIVE, COMMAND_REMOTE_DO_COPY,
+    },
+    files_api::{Agent, FilesApi, Transfer},
+    filter::{SkipFilter, SkipRules},
+    plan::{build_plan, PlanResponse},
+    sync::{get_extraneous_files, list_sources, DestinationListing},
+    transfer::{delete_moved_sources, end_transfer, keep_resumable},
+    transfer_queue::{QueueTicket, TransferQueue},
+    transfer_store::TransferStore,
+    upload::{
+        archive_and_upload, delete_remote_files_async, list_destination_async, resolve_compression,
+        send_transfer_result, stream_and_extract,
+    },
+    BandwidthLimits, CancelRequests, CancelTransferRequests, Files, ResumableTransfers,
+    TransferLimits, TransferRegistry, TransferScheduler,
 };
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(crate = "rocket::serde")]
 pub struct ResourceItem {
     source: String,
     destination: String,
     overwrite: bool,
     keep: bool,
+    /// strategy for the files of the item which exist in the destination,
+    /// `overwrite` and `keep` apply if not given
+    #[serde(default)]
+    conflict: Option<ConflictStrategy>,
 }
 
-#[derive(Deserialize, Debug)]
+impl ResourceItem {
+    /// Returns the conflict strategy of the item, replacing the existing
+    /// files if `overwrite` is set and keeping them otherwise.
+    fn get_conflict_strategy(&self) -> ConflictStrategy {
+        match (self.conflict, self.overwrite) {
+            (Some(strategy), _) => strategy,
+            (None, true) => ConflictStrategy::Overwrite,
+            (None, false) => ConflictStrategy::Skip,
+        }
+    }
+
+    /// Returns the `overwrite` and `keep` flags checked by Files before the
+    /// copy. Strategies which replace files require the permission to modify
+    /// them, failing ones reject an existing destination right away.
+    fn get_check_flags(&self) -> (bool, bool) {
+        match self.conflict {
+            None => (self.overwrite, self.keep),
+            Some(ConflictStrategy::Overwrite) | Some(ConflictStrategy::OverwriteIfNewer) => {
+                (true, false)
+            }
+            Some(ConflictStrategy::Skip) | Some(ConflictStrategy::Rename) => (false, true),
+            Some(ConflictStrategy::Fail) => (false, false),
+        }
+    }
+}
+
+/// CopyMode determines which files of the items of a copy request are sent.
+/// In sync mode, files which are unchanged in the destination are skipped.
+/// Mirror mode works like sync mode and additionally deletes the files in
+/// the destination which are missing from the sources once extracted.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum CopyMode {
+    #[default]
+    Copy,
+    Sync,
+    Mirror,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(crate = "rocket::serde")]
 pub struct CopyRequest {
-    items: Vec<ResourceItem>,
-    compress: bool,
+    pub items: Vec<ResourceItem>,
+    compress: ArchiveCompression,
     source_root: String,
+    #[serde(default)]
+    stream: bool,
+    #[serde(default)]
+    mode: CopyMode,
+    #[serde(default)]
+    checksum: bool,
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    metadata: MetadataPolicy,
+    #[serde(default)]
+    streams: usize,
+    #[serde(default, flatten)]
+    pub skip_rules: SkipRules,
+    /// delete the sources once delivered
+    #[serde(default, rename = "move")]
+    move_sources: bool,
+    #[serde(default)]
+    bandwidth_limit: Option<u64>,
+    #[serde(default)]
+    priority: i32,
+}
+
+impl CopyRequest {
+    /// Rejects conflict strategies and copy modes the metadata policy of the
+    /// request does not allow: the extracted files are given the time of the
+    /// extraction unless mtimes are preserved, so they cannot be compared.
+    pub fn check_conflict_strategies(&self) -> Result<(), String> {
+        let compares_mtimes = self
+            .items
+            .iter()
+            .any(|item| item.conflict == Some(ConflictStrategy::OverwriteIfNewer));
+        if compares_mtimes && !self.metadata.mtimes {
+            return Err(
+                "The overwrite-if-newer conflict strategy requires mtimes to be preserved"
+                    .to_string(),
+            );
+        }
+        // sync and mirror transfers compare the mtimes of the files unless
+        // they compare their checksums
+        if self.mode != CopyMode::Copy && !self.checksum && !self.metadata.mtimes {
+            return Err(
+                "Sync and mirror transfers require mtimes to be preserved unless checksum is set"
+                    .to_string(),
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct DownloadRequest {
+    items: Vec<ResourceItem>,
+    compress: ArchiveCompression,
+    #[serde(default)]
+    priority: i32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct RelayRequest {
+    items: Vec<ResourceItem>,
+    compress: ArchiveCompression,
+    #[serde(default)]
+    priority: i32,
 }
 
 #[derive(Serialize, Debug)]
@@ -50,6 +178,8 @@ pub struct ResourcesResponse {
 pub struct CopyResponse {
     code: i32,
     message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deletions: Option<Vec<String>>,
 }
 
 #[get("/agents/<agent_id>/resources/<path>")]
@@ -104,6 +234,7 @@ pub async fn resources(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 #[patch("/agents/<agent_id>/resources/<archive_name>", data = "<request>")]
 pub async fn copy(
     agent_id: u32,
@@ -111,6 +242,10 @@ pub async fn copy(
     request: Json<CopyRequest>,
     files: &State<Files>,
     cancel_requests_state: &State<CancelTransferRequests>,
+    resumable_state: &State<ResumableTransfers>,
+    bandwidth_limits_state: &State<BandwidthLimits>,
+    registry: &State<TransferRegistry>,
+    scheduler: &State<TransferScheduler>,
     cookies: &CookieJar<'_>,
 ) -> (Status, Json<CopyResponse>) {
     // verify that the requester has a valid session in Files and owns the referred agent
@@ -122,70 +257,67 @@ pub async fn copy(
                 Json(CopyResponse {
                     code: 912,
                     message: None,
+                    deletions: None,
                 }),
             )
         }
     };
 
-    // create arguments for 'remote-before-copy' command
-    let remote_user_id = &agent.remote_user.id.clone().to_string();
-    let items_json = get_items_json(&request.items);
-    let before_copy_args: Vec<&str> = vec![
-        &agent.host,
-        &agent.port,
-        remote_user_id,
-        &agent.remote_user.token,
-        &items_json,
-    ];
-
-    // execute command
-    let destination_root = match run_command_async(
-        204,
-        true,
-        false,
-        COMMAND_REMOTE_BEFORE_COPY,
-        before_copy_args,
-    )
-    .await
+    let transfer = match prepare_copy(&files.api, &agent, &auth_token, archive_name, &request).await
     {
-        Ok(root) => root,
-        Err(err) => {
-            // abort with error if copy pre-checks failed
+        Ok(t) => t,
+        Err(e) => {
             return (
-                err.status,
+                Status::new(e.http_code.unwrap_or(500) as u16),
                 Json(CopyResponse {
-                    code: err.code,
-                    message: Some(err.message),
+                    code: e.code,
+                    message: Some(e.message),
+                    deletions: None,
                 }),
             );
         }
     };
 
-    let transfer = Transfer {
-        agent_id,
-        host: agent.host,
-        port: agent.port,
-        transfer_id: archive_name.to_string(),
-        local_path: String::from(&request.source_root),
-        remote_path: destination_root,
-        compress: request.compress,
-        overwrite: request.items[0].overwrite,
-        size: 0,
-        rc_auth: auth_token.to_string(),
-    };
-
-    // register a 'cancel requested' flag for this transfer
-    let mut cancel_requests = cancel_requests_state.transfers.lock().unwrap();
-    cancel_requests.insert(archive_name.to_string(), Arc::new(Mutex::new(false)));
+    // in a dry run, only report the files a mirror transfer would delete
+    if request.dry_run {
+        let deletions = match request.mode {
+            CopyMode::Mirror => {
+                match get_mirror_deletions(&transfer, &request.items, request.checksum).await {
+                    Ok((_, deletions)) => deletions,
+                    Err(e) => {
+                        return (
+                            Status::new(e.http_code.unwrap_or(500) as u16),
+                            Json(CopyResponse {
+                                code: e.code,
+                                message: Some(e.message),
+                                deletions: None,
+                            }),
+                        );
+                    }
+                }
+            }
+            _ => Vec::new(),
+        };
+        return (
+            Status::Ok,
+            Json(CopyResponse {
+                code: 0,
+                message: None,
+                deletions: Some(deletions),
+            }),
+        );
+    }
 
     /*<alt:async execution of tar and scp> */
     // run remaining tasks asynchronously in a future
-    let items_copy = request.items.to_vec();
-    let _future = task::spawn(finish_upload_in_background(
-        transfer,
-        items_copy,
-        cancel_requests.clone(),
-    ));
+    let handles = TransferHandles {
+        cancel_requests: cancel_requests_state.transfers.clone(),
+        bandwidth_limits: bandwidth_limits_state.transfers.clone(),
+        resumable: resumable_state.transfers.clone(),
+        store: registry.store.clone(),
+        queue: scheduler.queue.clone(),
+    };
+    let _future = launch_copy(transfer, &request, agent.bandwidth_limit, &handles);
 
     /* The task has started execution at this point and
      * .await-ing it will be non-blocking. The task will
@@ -223,142 +355,1088 @@ pub async fn copy(
         Json(CopyResponse {
             code: 0,
             message: Some(archive_name.to_string()),
+            deletions: None,
         }),
     )
 }
 
-pub struct FutureError {
-    pub code: i32,
-    pub message: String,
-}
-
-async fn finish_upload_in_background(
-    mut transfer: Transfer,
-    req_items: Vec<ResourceItem>,
-    cancel_requests: HashMap<String, Arc<Mutex<bool>>>,
-) -> Result<(), FutureError> {
-    // allow some time for the upload state poll to initialize
-    time::sleep(Duration::from_millis(50)).await;
-
-    // extract the 'cancel requested' flag
-    let cancel_requested = cancel_requests.get(&transfer.transfer_id).unwrap().clone();
-
-    // send progress update
-    let files_api = FilesApi::new();
-    let msg = match transfer.compress {
-        true => "compressing",
-        false => "archiving",
+#[post("/agents/<agent_id>/resources/plan", data = "<request>")]
+pub async fn plan_copy(
+    agent_id: u32,
+    request: Json<CopyRequest>,
+    files: &State<Files>,
+    cookies: &CookieJar<'_>,
+) -> (Status, Json<PlanResponse>) {
+    // verify that the requester has a valid session in Files and owns the referred agent
+    let (agent, auth_token) = match files.api.get_agent(agent_id, cookies.get("rc_auth")).await {
+        Ok(a) => a,
+        Err(e) => {
+            return (
+                Status::new(e.http_code.unwrap_or(401)),
+                Json(PlanResponse {
+                    code: 920,
+                    plan: None,
+                    error: Some(e.message),
+                }),
+            )
+        }
     };
-    files_api
-        .send_upload_status_update_async(&transfer, msg)
-        .await;
 
-    // create list of files to archive
-    let mut items = Vec::new();
-    for item in req_items.iter() {
-        items.push(ArchiveItem {
-            source: (item.source).parse().unwrap(),
-            destination: (item.destination).parse().unwrap(),
-        })
+    if request.items.is_empty() {
+        return (
+            Status::BadRequest,
+            Json(PlanResponse {
+                code: 948,
+                plan: None,
+                error: Some("A copy needs at least one item".to_string()),
+            }),
+        );
     }
-    task::yield_now().await;
 
-    // create archive of files
-    let archive_path = &*format!(
-        "{}{}.agent.tar.gz",
-        DEFAULTS.temp_data_dir, transfer.transfer_id
-    );
-    let mut archive_writer = match ArchiveWriter::new(
-        archive_path,
-        transfer.compress,
-        &transfer.local_path,
-        cancel_requested,
-    ) {
-        Ok(w) => w,
+    // reject invalid skip patterns before walking the sources
+    let filter = match SkipFilter::new(&request.skip_rules) {
+        Ok(f) => f,
         Err(e) => {
-            files_api
-                .send_upload_status_update_async(&transfer, &e.message)
-                .await;
-            return Err(FutureError {
-                code: e.code,
-                message: e.message,
-            });
+            return (
+                Status::BadRequest,
+                Json(PlanResponse {
+                    code: 921,
+                    plan: None,
+                    error: Some(e),
+                }),
+            );
         }
     };
-    task::yield_now().await;
+    if let Err(e) = request.check_conflict_strategies() {
+        return (
+            Status::BadRequest,
+            Json(PlanResponse {
+                code: 947,
+                plan: None,
+                error: Some(e),
+            }),
+        );
+    }
 
-    if let Err(e) = archive_writer.crate_archive(items, &transfer).await {
-        // send progress update and abort with error
-        let err_msg = format!("{} (code:{})", e.message, e.code);
-        files_api
-            .send_upload_status_update_async(&transfer, &err_msg)
-            .await;
-        return Err(FutureError {
-            code: e.code,
-            message: e.message,
-        });
+    // run the copy pre-checks, existing destinations are reported as conflicts
+    // in the plan rather than failing the checks
+    let check_items: Vec<ResourceItem> = request
+        .items
+        .iter()
+        .map(|item| {
+            let (overwrite, keep) = item.get_check_flags();
+            ResourceItem {
+                overwrite,
+                keep: keep || !overwrite,
+                conflict: None,
+                ..item.clone()
+            }
+        })
+        .collect();
+    let remote_user_id = &agent.remote_user.id.clone().to_string();
+    let items_json = get_items_json(&check_items);
+    let before_copy_args: Vec<&str> = vec![
+        &agent.host,
+        &agent.port,
+        remote_user_id,
+        &agent.remote_user.token,
+        &items_json,
+    ];
+    let destination_root = match run_command_async(
+        210,
+        true,
+        false,
+        COMMAND_REMOTE_BEFORE_COPY,
+        before_copy_args,
+    )
+    .await
+    {
+        Ok(root) => root,
+        Err(err) => {
+            return (
+                err.status,
+                Json(PlanResponse {
+                    code: err.code,
+                    plan: None,
+                    error: Some(err.message),
+                }),
+            );
+        }
     };
-    task::yield_now().await;
 
-    // ensure the gzip encoder has flushed
-    drop(archive_writer);
+    // list the destination to find the conflicting files
+    let mut transfer = Transfer {
+        agent_id,
+        host: agent.host,
+        port: agent.port,
+        transfer_id: String::new(),
+        local_path: String::from(&request.source_root),
+        remote_path: destination_root,
+        compress: request.compress,
+        overwrite: request.items[0].overwrite,
+        size: 0,
+        metadata: request.metadata.clone(),
+        streams: request.streams,
+        skip_rules: request.skip_rules.clone(),
+        conflicts: Vec::new(),
+        move_sources: false,
+        moved_items: None,
+        rc_auth: auth_token.to_string(),
+    };
+    let items_json = get_items_json(&request.items);
+    let listing = match list_destination_async(&transfer, items_json, false).await {
+        Ok(l) => l,
+        Err(e) => {
+            return (
+                Status::new(e.http_code.unwrap_or(500) as u16),
+                Json(PlanResponse {
+                    code: e.code,
+                    plan: None,
+                    error: Some(e.message),
+                }),
+            );
+        }
+    };
 
-    task::yield_now().await;
+    // walk the local sources without blocking the server
+    let items = get_archive_items(&request.items);
+    if let Err(e) = resolve_compression(&mut transfer, &items).await {
+        return (
+            Status::new(e.http_code.unwrap_or(500) as u16),
+            Json(PlanResponse {
+                code: e.code,
+                plan: None,
+                error: Some(e.message),
+            }),
+        );
+    }
+    let plan = task::spawn_blocking(move || {
+        build_plan(
+            &transfer.local_path,
+            &items,
+            transfer.compress,
+            &transfer.metadata,
+            filter,
+            &listing,
+        )
+    })
+    .await;
 
-    // do not proceed to starting the upload if the 'cancel requested' flag is set
-    if *cancel_requests
-        .get(&transfer.transfer_id)
-        .unwrap()
-        .clone()
-        .lock()
-        .unwrap()
-    {
-        return Ok(());
+    match plan {
+        Ok(plan) => (
+            Status::Ok,
+            Json(PlanResponse {
+                code: 0,
+                plan: Some(plan),
+                error: None,
+            }),
+        ),
+        Err(e) => (
+            Status::InternalServerError,
+            Json(PlanResponse {
+                code: 364,
+                plan: None,
+                error: Some(e.to_string()),
+            }),
+        ),
     }
+}
 
-    transfer.size = fs::metadata(archive_path).unwrap().len();
-
-    files_api
-        .send_upload_status_update_async(&transfer, "starting upload")
-        .await;
-
-    // execute file upload
-    let cancel_requested = cancel_requests.get(&transfer.transfer_id).unwrap();
-    match Client::remote_do_copy_async(&files_api, &transfer, cancel_requested).await {
-        Ok(_) => {
-            // send transfer status update indicating normal completion
-            files_api
-                .send_upload_status_update_async(&transfer, "complete")
-                .await;
-            Ok(())
-        }
+#[allow(clippy::too_many_arguments)]
+#[patch("/agents/<agent_id>/downloads/<archive_name>", data = "<request>")]
+pub async fn download(
+    agent_id: u32,
+    archive_name: &str,
+    request: Json<DownloadRequest>,
+    files: &State<Files>,
+    cancel_requests_state: &State<CancelTransferRequests>,
+    registry: &State<TransferRegistry>,
+    scheduler: &State<TransferScheduler>,
+    cookies: &CookieJar<'_>,
+) -> (Status, Json<CopyResponse>) {
+    // verify that the requester has a valid session in Files and owns the referred agent
+    let (agent, auth_token) = match files.api.get_agent(agent_id, cookies.get("rc_auth")).await {
+        Ok(a) => a,
         Err(e) => {
-            let error = e.message;
-            // decide what message to send as transfer status update
-            let err_msg = match e.code {
-                // interrupted (SIGUSR1)
-                346 => Some(format!("signal::interrupt::{}", error)),
-                // aborted by user request - not an error
-                997 => None,
-                // all other errors
-                _ => Some(error.clone()),
-            };
-
-            // send transfer status update about the error
-            match err_msg {
-                None => Ok(()),
-                Some(message) => {
-                    files_api
-                        .send_upload_status_update_async(&transfer, &message)
-                        .await;
-                    Err(FutureError {
-                        code: e.code,
-                        message: error,
-                    })
-                }
-            }
+            return (
+                Status::new(e.http_code.unwrap_or(401)),
+                Json(CopyResponse {
+                    code: 913,
+                    message: None,
+                    deletions: None,
+                }),
+            )
         }
+    };
+    if request.items.is_empty() {
+        return (
+            Status::BadRequest,
+            Json(CopyResponse {
+                code: 948,
+                message: Some("A download needs at least one item".to_string()),
+                deletions: None,
+            }),
+        );
     }
+
+    // create arguments for the local 'local-before-copy' command, the items
+    // are passed without shell quoting as there is no ssh session involved
+    let local_user_id = &agent.user_id.to_string();
+    let items_json = get_items_json(&request.items);
+    let local_items_json = items_json.trim_matches('\'');
+    let before_copy_args: Vec<&str> = vec![local_user_id, &auth_token, local_items_json];
+
+    // execute command to check the local destination for conflicts
+    let destination_root = match run_command_async(
+        206,
+        true,
+        false,
+        COMMAND_LOCAL_BEFORE_COPY,
+        before_copy_args,
+    )
+    .await
+    {
+        Ok(root) => root,
+        Err(err) => {
+            // abort with error if download pre-checks failed
+            return (
+                err.status,
+                Json(CopyResponse {
+                    code: err.code,
+                    message: Some(err.message),
+                    deletions: None,
+                }),
+            );
+        }
+    };
+
+    // create arguments for 'remote-before-download' command
+    let remote_user_id = &agent.remote_user.id.clone().to_string();
+    let before_download_args: Vec<&str> = vec![
+        &agent.host,
+        &agent.port,
+        remote_user_id,
+        &agent.remote_user.token,
+        &items_json,
+    ];
+
+    // execute command to check that the remote sources are readable
+    let source_root = match run_command_async(
+        207,
+        true,
+        false,
+        COMMAND_REMOTE_BEFORE_DOWNLOAD,
+        before_download_args,
+    )
+    .await
+    {
+        Ok(root) => root,
+        Err(err) => {
+            // abort with error if download pre-checks failed
+            return (
+                err.status,
+                Json(CopyResponse {
+                    code: err.code,
+                    message: Some(err.message),
+                    deletions: None,
+                }),
+            );
+        }
+    };
+
+    let transfer = Transfer {
+        agent_id,
+        host: agent.host,
+        port: agent.port,
+        transfer_id: archive_name.to_string(),
+        local_path: get_root_path(&destination_root),
+        remote_path: get_root_path(&source_root),
+        compress: request.compress,
+        overwrite: request.items[0].overwrite,
+        size: 0,
+        metadata: MetadataPolicy::default(),
+        streams: 1,
+        skip_rules: SkipRules::default(),
+        conflicts: get_conflict_rules(&request.items, false),
+        move_sources: false,
+        moved_items: None,
+        rc_auth: auth_token.to_string(),
+    };
+
+    // register a 'cancel requested' flag for this transfer and record it in the store
+    let cancel_requested = Arc::new(Mutex::new(false));
+    cancel_requests_state
+        .transfers
+        .lock()
+        .unwrap()
+        .insert(archive_name.to_string(), cancel_requested.clone());
+    registry.store.register(&transfer);
+
+    // add the transfer to the queue, it starts running when the limits allow
+    let ticket = scheduler.queue.enqueue(&transfer, request.priority);
+
+    // run remaining tasks asynchronously in a future
+    let _future = task::spawn(finish_download_in_background(
+        transfer,
+        ticket,
+        items_json,
+        cancel_requested,
+        cancel_requests_state.transfers.clone(),
+        registry.store.clone(),
+    ));
+
+    // return success response
+    (
+        Status::Ok,
+        Json(CopyResponse {
+            code: 0,
+            message: Some(archive_name.to_string()),
+            deletions: None,
+        }),
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+#[patch(
+    "/agents/<agent_id>/relays/<target_agent_id>/<archive_name>",
+    data = "<request>"
+)]
+pub async fn relay(
+    agent_id: u32,
+    target_agent_id: u32,
+    archive_name: &str,
+    request: Json<RelayRequest>,
+    files: &State<Files>,
+    cancel_requests_state: &State<CancelTransferRequests>,
+    registry: &State<TransferRegistry>,
+    scheduler: &State<TransferScheduler>,
+    cookies: &CookieJar<'_>,
+) -> (Status, Json<CopyResponse>) {
+    // verify that the requester has a valid session in Files and owns both referred agents
+    let (source, auth_token) = match files.api.get_agent(agent_id, cookies.get("rc_auth")).await {
+        Ok(a) => a,
+        Err(e) => {
+            return (
+                Status::new(e.http_code.unwrap_or(401)),
+                Json(CopyResponse {
+                    code: 914,
+                    message: None,
+                    deletions: None,
+                }),
+            )
+        }
+    };
+    let (target, _) = match files
+        .api
+        .get_agent(target_agent_id, cookies.get("rc_auth"))
+        .await
+    {
+        Ok(a) => a,
+        Err(e) => {
+            return (
+                Status::new(e.http_code.unwrap_or(401)),
+                Json(CopyResponse {
+                    code: 915,
+                    message: None,
+                    deletions: None,
+                }),
+            )
+        }
+    };
+    if request.items.is_empty() {
+        return (
+            Status::BadRequest,
+            Json(CopyResponse {
+                code: 948,
+                message: Some("A relay needs at least one item".to_string()),
+                deletions: None,
+            }),
+        );
+    }
+
+    // the source agent uploads to the target agent on its own, with the key
+    // pair it was paired with the target for and its pinned host key
+    let target_agent_id_arg = target_agent_id.to_string();
+    let check_pairing_args: Vec<&str> = vec![
+        &source.host,
+        &source.port,
+        &target.host,
+        &target.port,
+        &target_agent_id_arg,
+    ];
+    if let Err(err) = run_command_async(
+        211,
+        true,
+        false,
+        COMMAND_REMOTE_CHECK_PAIRING,
+        check_pairing_args,
+    )
+    .await
+    {
+        return (
+            err.status,
+            Json(CopyResponse {
+                code: err.code,
+                message: Some(format!(
+                    "Agent {agent_id} is not paired with agent {target_agent_id}: {}",
+                    err.message
+                )),
+                deletions: None,
+            }),
+        );
+    }
+
+    // create arguments for the 'remote-before-copy' command on the target agent
+    let items_json = get_items_json(&request.items);
+    let target_user_id = &target.remote_user.id.clone().to_string();
+    let before_copy_args: Vec<&str> = vec![
+        &target.host,
+        &target.port,
+        target_user_id,
+        &target.remote_user.token,
+        &items_json,
+    ];
+
+    // execute command to check the target destination for conflicts
+    let destination_root = match run_command_async(
+        208,
+        true,
+        false,
+        COMMAND_REMOTE_BEFORE_COPY,
+        before_copy_args,
+    )
+    .await
+    {
+        Ok(root) => root,
+        Err(err) => {
+            // abort with error if relay pre-checks failed
+            return (
+                err.status,
+                Json(CopyResponse {
+                    code: err.code,
+                    message: Some(err.message),
+                    deletions: None,
+                }),
+            );
+        }
+    };
+
+    // create arguments for the 'remote-before-download' command on the source agent
+    let source_user_id = &source.remote_user.id.clone().to_string();
+    let before_download_args: Vec<&str> = vec![
+        &source.host,
+        &source.port,
+        source_user_id,
+        &source.remote_user.token,
+        &items_json,
+    ];
+
+    // execute command to check that the sources are readable
+    let source_root = match run_command_async(
+        209,
+        true,
+        false,
+        COMMAND_REMOTE_BEFORE_DOWNLOAD,
+        before_download_args,
+    )
+    .await
+    {
+        Ok(root) => root,
+        Err(err) => {
+            // abort with error if relay pre-checks failed
+            return (
+                err.status,
+                Json(CopyResponse {
+                    code: err.code,
+                    message: Some(err.message),
+                    deletions: None,
+                }),
+            );
+        }
+    };
+
+    // the transfer as seen by the source agent which will execute the upload,
+    // status updates are reported to Files on behalf of the source agent
+    let transfer = Transfer {
+        agent_id,
+        host: target.host,
+        port: target.port,
+        transfer_id: archive_name.to_string(),
+        local_path: get_root_path(&source_root),
+        remote_path: get_root_path(&destination_root),
+        compress: request.compress,
+        overwrite: request.items[0].overwrite,
+        size: 0,
+        metadata: MetadataPolicy::default(),
+        streams: 1,
+        skip_rules: SkipRules::default(),
+        conflicts: Vec::new(),
+        move_sources: false,
+        moved_items: None,
+        rc_auth: auth_token.to_string(),
+    };
+
+    // register a 'cancel requested' flag for this transfer and record it in the store
+    let cancel_requested = Arc::new(Mutex::new(false));
+    cancel_requests_state
+        .transfers
+        .lock()
+        .unwrap()
+        .insert(archive_name.to_string(), cancel_requested.clone());
+    registry.store.register(&transfer);
+
+    // add the transfer to the queue, it starts running when the limits allow
+    let ticket = scheduler.queue.enqueue(&transfer, request.priority);
+
+    // run remaining tasks asynchronously in a future
+    let _future = task::spawn(finish_relay_in_background(
+        transfer,
+        ticket,
+        source.host,
+        source.port,
+        target_agent_id,
+        items_json,
+        cancel_requested,
+        cancel_requests_state.transfers.clone(),
+        registry.store.clone(),
+    ));
+
+    // return success response
+    (
+        Status::Ok,
+        Json(CopyResponse {
+            code: 0,
+            message: Some(archive_name.to_string()),
+            deletions: None,
+        }),
+    )
+}
+
+pub struct FutureError {
+    pub code: i32,
+    pub message: String,
+}
+
+/// CopyOptions are the settings of a copy request applied once its
+/// upload starts running.
+struct CopyOptions {
+    stream: bool,
+    mode: CopyMode,
+    checksum: bool,
+}
+
+/// TransferHandles holds the shared state which a transfer registers itself
+/// in while it is running, see the states managed by the webserver.
+#[derive(Clone)]
+pub struct TransferHandles {
+    pub cancel_requests: CancelRequests,
+    pub bandwidth_limits: TransferLimits,
+    pub resumable: Arc<Mutex<HashMap<String, Transfer>>>,
+    pub store: TransferStore,
+    pub queue: TransferQueue,
+}
+
+/// Checks that the copy requested by the owner of `agent`, authenticated by
+/// `auth_token`, is allowed and runs the copy pre-checks on the remote.
+/// Returns the transfer to run with the id `archive_name`.
+pub async fn prepare_copy(
+    files_api: &FilesApi,
+    agent: &Agent,
+    auth_token: &str,
+    archive_name: &str,
+    request: &CopyRequest,
+) -> Result<Transfer, ClientError> {
+    if request.items.is_empty() {
+        return Err(ClientError {
+            code: 948,
+            message: "A copy needs at least one item".to_string(),
+            http_code: Some(400),
+        });
+    }
+
+    // reject invalid skip patterns before starting anything
+    if let Err(e) = SkipFilter::new(&request.skip_rules) {
+        return Err(ClientError {
+            code: 921,
+            message: e,
+            http_code: Some(400),
+        });
+    }
+    if let Err(e) = request.check_conflict_strategies() {
+        return Err(ClientError {
+            code: 947,
+            message: e,
+            http_code: Some(400),
+        });
+    }
+
+    // moving requires the permission to delete the sources
+    if request.move_sources && !request.dry_run {
+        let auth_cookie = Cookie::new("rc_auth", auth_token.to_string());
+        let allowed = match files_api
+            .get_auth_user(agent.user_id, Some(&auth_cookie))
+            .await
+        {
+            Ok(user) => user.perm.delete,
+            Err(_) => false,
+        };
+        if !allowed {
+            return Err(ClientError {
+                code: 923,
+                message: "Not allowed to delete the sources".to_string(),
+                http_code: Some(403),
+            });
+        }
+    }
+
+    // create arguments for 'remote-before-copy' command
+    let remote_user_id = &agent.remote_user.id.clone().to_string();
+    let items_json = get_items_json(&request.items);
+    let before_copy_args: Vec<&str> = vec![
+        &agent.host,
+        &agent.port,
+        remote_user_id,
+        &agent.remote_user.token,
+        &items_json,
+    ];
+
+    // execute command, abort with error if copy pre-checks failed
+    let destination_root = run_command_async(
+        204,
+        true,
+        false,
+        COMMAND_REMOTE_BEFORE_COPY,
+        before_copy_args,
+    )
+    .await
+    .map_err(|err| ClientError {
+        code: err.code,
+        message: err.message,
+        http_code: Some(err.status.code as i32),
+    })?;
+
+    Ok(Transfer {
+        agent_id: agent.id,
+        host: agent.host.clone(),
+        port: agent.port.clone(),
+        transfer_id: archive_name.to_string(),
+        local_path: String::from(&request.source_root),
+        remote_path: destination_root,
+        compress: request.compress,
+        overwrite: request.items[0].overwrite,
+        size: 0,
+        metadata: request.metadata.clone(),
+        streams: request.streams,
+        skip_rules: request.skip_rules.clone(),
+        conflicts: get_conflict_rules(&request.items, request.move_sources),
+        move_sources: request.move_sources,
+        moved_items: None,
+        rc_auth: auth_token.to_string(),
+    })
+}
+
+/// Registers `transfer` in `handles` and runs it in the background once the
+/// queue lets it start. The bandwidth limit of the request applies, or
+/// `default_bandwidth_limit` of the agent if not given.
+pub fn launch_copy(
+    transfer: Transfer,
+    request: &CopyRequest,
+    default_bandwidth_limit: u64,
+    handles: &TransferHandles,
+) -> JoinHandle<Result<(), FutureError>> {
+    // register a 'cancel requested' flag for this transfer and record it in the store
+    let cancel_requested = Arc::new(Mutex::new(false));
+    handles
+        .cancel_requests
+        .lock()
+        .unwrap()
+        .insert(transfer.transfer_id.clone(), cancel_requested.clone());
+    handles.store.register(&transfer);
+
+    // register the bandwidth limit of this transfer, the agent's default applies if not given
+    let bandwidth_limit = Arc::new(Mutex::new(
+        request.bandwidth_limit.unwrap_or(default_bandwidth_limit),
+    ));
+    handles
+        .bandwidth_limits
+        .lock()
+        .unwrap()
+        .insert(transfer.transfer_id.clone(), bandwidth_limit.clone());
+
+    // add the transfer to the queue, it starts running when the limits allow
+    let ticket = handles.queue.enqueue(&transfer, request.priority);
+
+    task::spawn(finish_upload_in_background(
+        transfer,
+        ticket,
+        request.items.to_vec(),
+        CopyOptions {
+            stream: request.stream,
+            mode: request.mode,
+            checksum: request.checksum,
+        },
+        cancel_requested,
+        bandwidth_limit,
+        handles.cancel_requests.clone(),
+        handles.bandwidth_limits.clone(),
+        handles.resumable.clone(),
+        handles.store.clone(),
+    ))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn finish_upload_in_background(
+    mut transfer: Transfer,
+    ticket: QueueTicket,
+    req_items: Vec<ResourceItem>,
+    copy_options: CopyOptions,
+    cancel_requested: Arc<Mutex<bool>>,
+    bandwidth_limit: Arc<Mutex<u64>>,
+    cancel_requests: CancelRequests,
+    bandwidth_limits: TransferLimits,
+    resumable: Arc<Mutex<HashMap<String, Transfer>>>,
+    store: TransferStore,
+) -> Result<(), FutureError> {
+    // allow some time for the upload state poll to initialize
+    time::sleep(Duration::from_millis(50)).await;
+
+    // wait for the transfer's turn in the queue
+    let progress = ProgressSink::Files(transfer.clone(), store.clone());
+    let _slot = match ticket.wait(&progress).await {
+        Ok(slot) => slot,
+        Err(e) => {
+            let result = Err(e);
+            bandwidth_limits
+                .lock()
+                .unwrap()
+                .remove(&transfer.transfer_id);
+            end_transfer(&transfer.transfer_id, &result, &store, &cancel_requests);
+            return result.map_err(|e| FutureError {
+                code: e.code,
+                message: e.message,
+            });
+        }
+    };
+
+    // create list of files to archive
+    let items = get_archive_items(&req_items);
+    task::yield_now().await;
+
+    // in sync and mirror mode, list the destination so that unchanged files are
+    // skipped, in mirror mode also the files missing from the sources are found
+    let mut options = ArchiveOptions {
+        metadata: transfer.metadata.clone(),
+        skip_rules: transfer.skip_rules.clone(),
+        ..Default::default()
+    };
+    let mut deletions = None;
+    let mut result = Ok(());
+    match copy_options.mode {
+        CopyMode::Copy => {}
+        CopyMode::Sync => {
+            progress.send("comparing").await;
+            let items_json = get_items_json(&req_items);
+            match list_destination_async(&transfer, items_json, copy_options.checksum).await {
+                Ok(listing) => options.destination_listing = Some(listing),
+                Err(e) => result = Err(e),
+            }
+        }
+        CopyMode::Mirror => {
+            progress.send("comparing").await;
+            match get_mirror_deletions(&transfer, &req_items, copy_options.checksum).await {
+                Ok((listing, paths)) => {
+                    options.destination_listing = Some(listing);
+                    deletions = Some(paths);
+                }
+                Err(e) => result = Err(e),
+            }
+        }
+    }
+
+    // archive, upload and extract files and report the result, either
+    // through a temp archive or streaming the archive into the remote
+    if result.is_ok() {
+        result = match copy_options.stream {
+            true => {
+                stream_and_extract(
+                    &mut transfer,
+                    items,
+                    &progress,
+                    &cancel_requested,
+                    &bandwidth_limit,
+                    options,
+                )
+                .await
+            }
+            false => {
+                archive_and_upload(
+                    &mut transfer,
+                    items,
+                    &progress,
+                    &cancel_requested,
+                    &bandwidth_limit,
+                    options,
+                )
+                .await
+            }
+        };
+    }
+    // in mirror mode, delete the files missing from the sources once extracted
+    if let (Ok(_), Some(paths)) = (&result, deletions) {
+        progress.send("deleting").await;
+        result = match delete_remote_files_async(&transfer, paths).await {
+            Ok(count) => {
+                progress
+                    .send(&format!("progress::deleted::{}", count))
+                    .await;
+                Ok(())
+            }
+            Err(e) => Err(e),
+        };
+    }
+    // when moving, delete the sources once the remote confirmed their delivery
+    if result.is_ok() && transfer.move_sources {
+        result = delete_moved_sources(&transfer, &progress, &store).await;
+    }
+    if let Err(e) = &result {
+        keep_resumable(&transfer, e, &resumable);
+    }
+    bandwidth_limits
+        .lock()
+        .unwrap()
+        .remove(&transfer.transfer_id);
+    end_transfer(&transfer.transfer_id, &result, &store, &cancel_requests);
+    match send_transfer_result(&progress, result).await {
+        Ok(_) => Ok(()),
+        Err(e) => Err(FutureError {
+            code: e.code,
+            message: e.message,
+        }),
+    }
+}
+
+async fn finish_download_in_background(
+    mut transfer: Transfer,
+    ticket: QueueTicket,
+    items_json: String,
+    cancel_requested: Arc<Mutex<bool>>,
+    cancel_requests: CancelRequests,
+    store: TransferStore,
+) -> Result<(), FutureError> {
+    // allow some time for the download state poll to initialize
+    time::sleep(Duration::from_millis(50)).await;
+
+    // wait for the transfer's turn in the queue
+    let progress = ProgressSink::Files(transfer.clone(), store.clone());
+    let _slot = match ticket.wait(&progress).await {
+        Ok(slot) => slot,
+        Err(e) => {
+            let result = Err(e);
+            end_transfer(&transfer.transfer_id, &result, &store, &cancel_requests);
+            return result.map_err(|e| FutureError {
+                code: e.code,
+                message: e.message,
+            });
+        }
+    };
+
+    // the sources are remote, so 'auto' compression goes by the latency only
+    if let Err(e) = resolve_compression(&mut transfer, &[]).await {
+        let result = Err(e);
+        end_transfer(&transfer.transfer_id, &result, &store, &cancel_requests);
+        return send_transfer_result(&progress, result)
+            .await
+            .map_err(|e| FutureError {
+                code: e.code,
+                message: e.message,
+            });
+    }
+
+    // send progress update
+    let msg = match transfer.compress.is_compressed() {
+        true => "compressing",
+        false => "archiving",
+    };
+    progress.send(msg).await;
+
+    // create archive of files on the remote
+    let port = transfer.port.to_string();
+    let compress = transfer.compress.to_string();
+    let conflicts_json = get_conflicts_json(&transfer.conflicts);
+    let mut create_archive_args: Vec<&str> = vec![
+        COMMAND_REMOTE_CREATE_ARCHIVE,
+        &transfer.host,
+        &port,
+        &transfer.transfer_id,
+        &transfer.remote_path,
+        &compress,
+        &items_json,
+    ];
+    // the remote adds the conflict strategies to the archive, see `upload::write_conflict_rules`
+    if !transfer.conflicts.is_empty() {
+        create_archive_args.push(&conflicts_json);
+    }
+    let result =
+        Client::relay_cli_command_async(&progress, create_archive_args, &cancel_requested).await;
+    let result = match result {
+        Ok(size) => {
+            transfer.size = size.trim().parse::<u64>().unwrap_or(0);
+
+            progress.send("starting download").await;
+
+            // execute file download and extract
+            Client::remote_do_download_async(&progress, &transfer, &cancel_requested).await
+        }
+        Err(e) => Err(e),
+    };
+    end_transfer(&transfer.transfer_id, &result, &store, &cancel_requests);
+
+    // report the result
+    match send_transfer_result(&progress, result).await {
+        Ok(_) => Ok(()),
+        Err(e) => Err(FutureError {
+            code: e.code,
+            message: e.message,
+        }),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn finish_relay_in_background(
+    transfer: Transfer,
+    ticket: QueueTicket,
+    source_host: String,
+    source_port: String,
+    target_agent_id: u32,
+    items_json: String,
+    cancel_requested: Arc<Mutex<bool>>,
+    cancel_requests: CancelRequests,
+    store: TransferStore,
+) -> Result<(), FutureError> {
+    // allow some time for the upload state poll to initialize
+    time::sleep(Duration::from_millis(50)).await;
+
+    // wait for the transfer's turn in the queue
+    let progress = ProgressSink::Files(transfer.clone(), store.clone());
+    let _slot = match ticket.wait(&progress).await {
+        Ok(slot) => slot,
+        Err(e) => {
+            let result = Err(e);
+            end_transfer(&transfer.transfer_id, &result, &store, &cancel_requests);
+            return result.map_err(|e| FutureError {
+                code: e.code,
+                message: e.message,
+            });
+        }
+    };
+
+    // make the source agent run its upload pipeline towards the target agent,
+    // its status updates (including the final one) are forwarded to Files
+    let compress = transfer.compress.to_string();
+    let overwrite = transfer.overwrite.to_string();
+    let target_agent_id = target_agent_id.to_string();
+    let do_copy_args: Vec<&str> = vec![
+        COMMAND_REMOTE_DO_COPY,
+        &source_host,
+        &source_port,
+        &transfer.transfer_id,
+        &transfer.local_path,
+        &transfer.host,
+        &transfer.port,
+        &target_agent_id,
+        &transfer.remote_path,
+        &compress,
+        &overwrite,
+        &items_json,
+    ];
+    let result = Client::relay_cli_command_async(&progress, do_copy_args, &cancel_requested)
+        .await
+        .map(|_| ());
+    end_transfer(&transfer.transfer_id, &result, &store, &cancel_requests);
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            // errors of the source agent's upload pipeline have been forwarded
+            // already, only report errors of the relay itself
+            if e.code != 997 && !e.message.trim().is_empty() {
+                progress.send(&e.message).await;
+            }
+            Err(FutureError {
+                code: e.code,
+                message: e.message,
+            })
+        }
+    }
+}
+
+/// Lists the destination of a mirror transfer on the remote and returns it
+/// along with the files in it which are missing from the local sources.
+async fn get_mirror_deletions(
+    transfer: &Transfer,
+    req_items: &[ResourceItem],
+    checksum: bool,
+) -> Result<(DestinationListing, Vec<String>), ClientError> {
+    let items_json = get_items_json(req_items);
+    let listing = list_destination_async(transfer, items_json, checksum).await?;
+    let items = get_archive_items(req_items);
+    let mut filter = SkipFilter::new(&transfer.skip_rules).map_err(|e| ClientError {
+        code: 921,
+        message: e,
+        http_code: Some(400),
+    })?;
+    let sources = list_sources(&transfer.local_path, &items, &mut filter);
+    let deletions = get_extraneous_files(&listing, &sources, &items, &filter);
+
+    Ok((listing, deletions))
+}
+
+fn get_archive_items(req_items: &[ResourceItem]) -> Vec<ArchiveItem> {
+    let mut items = Vec::new();
+    for item in req_items.iter() {
+        items.push(ArchiveItem {
+            source: (item.source).parse().unwrap(),
+            destination: (item.destination).parse().unwrap(),
+        })
+    }
+
+    items
+}
+
+/// Returns the conflict strategies of the items to be applied by the remote
+/// file by file, or none if no item has a strategy of its own, in which case
+/// `overwrite` of the transfer applies to all of them. The existing files a
+/// move does not overwrite are always resolved file by file, so that they
+/// are reported as kept and their sources are not deleted.
+fn get_conflict_rules(req_items: &[ResourceItem], move_sources: bool) -> Vec<ConflictRule> {
+    let keeps_existing = req_items.iter().any(|item| !item.overwrite);
+    if req_items.iter().all(|item| item.conflict.is_none()) && !(move_sources && keeps_existing) {
+        return Vec::new();
+    }
+
+    req_items
+        .iter()
+        .map(|item| ConflictRule {
+            path: decode(&item.destination)
+                .unwrap()
+                .trim_start_matches('/')
+                .to_string(),
+            strategy: item.get_conflict_strategy(),
+        })
+        .collect()
+}
+
+/// Returns the conflict rules as the JSON argument of a command run by the
+/// shell of a remote agent.
+fn get_conflicts_json(rules: &[ConflictRule]) -> String {
+    let json = serde_json::to_string(rules).unwrap();
+
+    format!("'{}'", json.replace('\'', "'\\''"))
+}
+
+/// Strips the JSON string quotes from a scope root returned by Files.
+fn get_root_path(root: &str) -> String {
+    root.trim().trim_matches('"').to_string()
 }
 
 fn get_items_json(items: &[ResourceItem]) -> String {
@@ -367,9 +1445,10 @@ fn get_items_json(items: &[ResourceItem]) -> String {
     let mut first = true;
     json_str.push(String::from("'["));
     items.iter().for_each(|item| {
+        let (overwrite, keep) = item.get_check_flags();
         let source = format!(
             "{{\"source\": \"{}\", \"destination\": \"{}\", \"overwrite\": {}, \"keep\": {}}}",
-            item.source, item.destination, item.overwrite, item.keep
+            item.source, item.destination, overwrite, keep
         );
         if first {
             first = false;
@@ -0,0 +1,589 @@
+use rocket::{
+    http::{Cookie, CookieJar, Status},
+    serde::{json::Json, Deserialize, Serialize},
+    tokio::{task, task::JoinHandle, time},
+    State,
+};
+use std::time::Duration;
+
+use crate::{
+    client::{Client, ClientError},
+    files_api::FilesApi,
+    filter::SkipFilter,
+    resource::{launch_copy, prepare_copy, CopyRequest, FutureError, TransferHandles},
+    schedule_store::{add_run, now, parse_cron, Schedule, ScheduleRun, ScheduleStore},
+    transfer_store::TransferPhase,
+    BandwidthLimits, CancelTransferRequests, Files, ResumableTransfers, Schedules,
+    TransferRegistry, TransferScheduler,
+};
+
+/// How often the scheduler checks for due schedules.
+const SCHEDULER_TICK: Duration = Duration::from_secs(30);
+
+/// The Files token of a schedule is renewed when older than this, well
+/// within the lifetime of a Files session.
+const TOKEN_RENEWAL_INTERVAL: u64 = 60 * 60;
+
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ScheduleRequest {
+    #[serde(default)]
+    name: String,
+    cron: String,
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+    request: CopyRequest,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// ScheduleStatus is a schedule as reported to its owner, without the token
+/// it runs with.
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ScheduleStatus {
+    id: String,
+    name: String,
+    cron: String,
+    enabled: bool,
+    request: CopyRequest,
+    created_at: u64,
+    next_run_at: Option<u64>,
+    last_run: Option<ScheduleRun>,
+    history: Vec<ScheduleRun>,
+}
+
+impl From<Schedule> for ScheduleStatus {
+    fn from(schedule: Schedule) -> Self {
+        Self {
+            last_run: schedule.history.last().cloned(),
+            id: schedule.id,
+            name: schedule.name,
+            cron: schedule.cron,
+            enabled: schedule.enabled,
+            request: schedule.request,
+            created_at: schedule.created_at,
+            next_run_at: schedule.next_run_at,
+            history: schedule.history,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct SchedulesResponse {
+    code: i32,
+    schedules: Vec<ScheduleStatus>,
+    error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ScheduleResponse {
+    code: i32,
+    schedule: Option<ScheduleStatus>,
+    error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ScheduleRunResponse {
+    code: i32,
+    transfer_id: Option<String>,
+    error: Option<String>,
+}
+
+#[get("/agents/<agent_id>/schedules")]
+pub async fn list_schedules(
+    agent_id: u32,
+    files: &State<Files>,
+    schedules: &State<Schedules>,
+    cookies: &CookieJar<'_>,
+) -> (Status, Json<SchedulesResponse>) {
+    // verify that the requester has a valid session in Files and owns the referred agent
+    if let Err(e) = files.api.get_agent(agent_id, cookies.get("rc_auth")).await {
+        return (
+            Status::Forbidden,
+            Json(SchedulesResponse {
+                code: 924,
+                schedules: vec![],
+                error: Some(e.message),
+            }),
+        );
+    }
+
+    let schedules = schedules
+        .store
+        .list(agent_id)
+        .into_iter()
+        .map(ScheduleStatus::from)
+        .collect();
+
+    (
+        Status::Ok,
+        Json(SchedulesResponse {
+            code: 0,
+            schedules,
+            error: None,
+        }),
+    )
+}
+
+#[post("/agents/<agent_id>/schedules", data = "<request>")]
+pub async fn create_schedule(
+    agent_id: u32,
+    request: Json<ScheduleRequest>,
+    files: &State<Files>,
+    schedules: &State<Schedules>,
+    cookies: &CookieJar<'_>,
+) -> (Status, Json<ScheduleResponse>) {
+    // verify that the requester has a valid session in Files and owns the referred agent
+    let (agent, auth_token) = match files.api.get_agent(agent_id, cookies.get("rc_auth")).await {
+        Ok(a) => a,
+        Err(e) => {
+            return (
+                Status::Forbidden,
+                Json(ScheduleResponse {
+                    code: 925,
+                    schedule: None,
+                    error: Some(e.message),
+                }),
+            )
+        }
+    };
+
+    if let Err(e) = validate_schedule(&request) {
+        return (
+            Status::BadRequest,
+            Json(ScheduleResponse {
+                code: e.code,
+                schedule: None,
+                error: Some(e.message),
+            }),
+        );
+    }
+
+    let request = request.into_inner();
+    let now = now();
+    let mut schedule = Schedule {
+        id: Client::random_hex(),
+        agent_id,
+        user_id: agent.user_id,
+        name: request.name,
+        cron: request.cron,
+        enabled: request.enabled,
+        request: request.request,
+        created_at: now,
+        next_run_at: None,
+        history: vec![],
+        auth_token,
+        token_renewed_at: now,
+    };
+    schedule.update_next_run(now);
+    schedules.store.insert(schedule.clone());
+
+    (
+        Status::Ok,
+        Json(ScheduleResponse {
+            code: 0,
+            schedule: Some(ScheduleStatus::from(schedule)),
+            error: None,
+        }),
+    )
+}
+
+#[get("/agents/<agent_id>/schedules/<schedule_id>")]
+pub async fn get_schedule(
+    agent_id: u32,
+    schedule_id: &str,
+    files: &State<Files>,
+    schedules: &State<Schedules>,
+    cookies: &CookieJar<'_>,
+) -> (Status, Json<ScheduleResponse>) {
+    // verify that the requester has a valid session in Files and owns the referred agent
+    if let Err(e) = files.api.get_agent(agent_id, cookies.get("rc_auth")).await {
+        return (
+            Status::Forbidden,
+            Json(ScheduleResponse {
+                code: 928,
+                schedule: None,
+                error: Some(e.message),
+            }),
+        );
+    }
+
+    match schedules.store.get(agent_id, schedule_id) {
+        Some(schedule) => (
+            Status::Ok,
+            Json(ScheduleResponse {
+                code: 0,
+                schedule: Some(ScheduleStatus::from(schedule)),
+                error: None,
+            }),
+        ),
+        None => schedule_not_found(schedule_id),
+    }
+}
+
+/// Replaces the settings of a schedule. The schedule runs as the requester
+/// from then on, with the token of the request.
+#[put("/agents/<agent_id>/schedules/<schedule_id>", data = "<request>")]
+pub async fn update_schedule(
+    agent_id: u32,
+    schedule_id: &str,
+    request: Json<ScheduleRequest>,
+    files: &State<Files>,
+    schedules: &State<Schedules>,
+    cookies: &CookieJar<'_>,
+) -> (Status, Json<ScheduleResponse>) {
+    // verify that the requester has a valid session in Files and owns the referred agent
+    let (agent, auth_token) = match files.api.get_agent(agent_id, cookies.get("rc_auth")).await {
+        Ok(a) => a,
+        Err(e) => {
+            return (
+                Status::Forbidden,
+                Json(ScheduleResponse {
+                    code: 930,
+                    schedule: None,
+                    error: Some(e.message),
+                }),
+            )
+        }
+    };
+
+    if let Err(e) = validate_schedule(&request) {
+        return (
+            Status::BadRequest,
+            Json(ScheduleResponse {
+                code: e.code,
+                schedule: None,
+                error: Some(e.message),
+            }),
+        );
+    }
+
+    if schedules.store.get(agent_id, schedule_id).is_none() {
+        return schedule_not_found(schedule_id);
+    }
+
+    let request = request.into_inner();
+    let now = now();
+    let updated = schedules.store.update(schedule_id, |schedule| {
+        schedule.user_id = agent.user_id;
+        schedule.name = request.name;
+        schedule.cron = request.cron;
+        schedule.enabled = request.enabled;
+        schedule.request = request.request;
+        schedule.auth_token = auth_token;
+        schedule.token_renewed_at = now;
+        schedule.update_next_run(now);
+    });
+
+    match updated {
+        Some(schedule) => (
+            Status::Ok,
+            Json(ScheduleResponse {
+                code: 0,
+                schedule: Some(ScheduleStatus::from(schedule)),
+                error: None,
+            }),
+        ),
+        None => schedule_not_found(schedule_id),
+    }
+}
+
+/// Deletes a schedule. A run in progress is not cancelled, see
+/// `cancel_transfer` for that.
+#[delete("/agents/<agent_id>/schedules/<schedule_id>")]
+pub async fn delete_schedule(
+    agent_id: u32,
+    schedule_id: &str,
+    files: &State<Files>,
+    schedules: &State<Schedules>,
+    cookies: &CookieJar<'_>,
+) -> Status {
+    // verify that the requester has a valid session in Files and owns the referred agent
+    if files
+        .api
+        .get_agent(agent_id, cookies.get("rc_auth"))
+        .await
+        .is_err()
+    {
+        return Status::Forbidden;
+    }
+
+    match schedules.store.remove(agent_id, schedule_id) {
+        true => Status::Ok,
+        false => Status::NotFound,
+    }
+}
+
+/// Runs a schedule immediately, as the user who created it. The time of the
+/// next scheduled run is not affected.
+#[allow(clippy::too_many_arguments)]
+#[post("/agents/<agent_id>/schedules/<schedule_id>/run")]
+pub async fn run_schedule(
+    agent_id: u32,
+    schedule_id: &str,
+    files: &State<Files>,
+    schedules: &State<Schedules>,
+    cancel_requests_state: &State<CancelTransferRequests>,
+    resumable_state: &State<ResumableTransfers>,
+    bandwidth_limits_state: &State<BandwidthLimits>,
+    registry: &State<TransferRegistry>,
+    scheduler: &State<TransferScheduler>,
+    cookies: &CookieJar<'_>,
+) -> (Status, Json<ScheduleRunResponse>) {
+    // verify that the requester has a valid session in Files and owns the referred agent
+    if let Err(e) = files.api.get_agent(agent_id, cookies.get("rc_auth")).await {
+        return (
+            Status::Forbidden,
+            Json(ScheduleRunResponse {
+                code: 931,
+                transfer_id: None,
+                error: Some(e.message),
+            }),
+        );
+    }
+
+    let schedule = match schedules.store.get(agent_id, schedule_id) {
+        Some(s) => s,
+        None => {
+            return (
+                Status::NotFound,
+                Json(ScheduleRunResponse {
+                    code: 929,
+                    transfer_id: None,
+                    error: Some(format!("Schedule {schedule_id} not found")),
+                }),
+            )
+        }
+    };
+
+    // a schedule runs one transfer at a time
+    if schedule.is_running() {
+        return (
+            Status::Conflict,
+            Json(ScheduleRunResponse {
+                code: 933,
+                transfer_id: None,
+                error: Some(format!("Schedule {schedule_id} is already running")),
+            }),
+        );
+    }
+
+    let handles = TransferHandles {
+        cancel_requests: cancel_requests_state.transfers.clone(),
+        bandwidth_limits: bandwidth_limits_state.transfers.clone(),
+        resumable: resumable_state.transfers.clone(),
+        store: registry.store.clone(),
+        queue: scheduler.queue.clone(),
+    };
+
+    match start_run(&files.api, &schedule, &schedules.store, &handles).await {
+        Ok(transfer_id) => (
+            Status::Ok,
+            Json(ScheduleRunResponse {
+                code: 0,
+                transfer_id: Some(transfer_id),
+                error: None,
+            }),
+        ),
+        Err(e) => (
+            Status::new(e.http_code.unwrap_or(500) as u16),
+            Json(ScheduleRunResponse {
+                code: e.code,
+                transfer_id: None,
+                error: Some(e.message),
+            }),
+        ),
+    }
+}
+
+/// Runs the due schedules and keeps the Files tokens of the enabled
+/// schedules renewed, for as long as the webserver runs.
+pub async fn run_scheduler(store: ScheduleStore, handles: TransferHandles) {
+    let files_api = FilesApi::new();
+    loop {
+        let now = now();
+
+        for schedule in store.renewal_due(now.saturating_sub(TOKEN_RENEWAL_INTERVAL)) {
+            // an expired token cannot be renewed, the runs fail until the
+            // schedule is updated by its owner
+            if let Ok(token) = files_api.renew_token(&schedule.auth_token).await {
+                store.update(&schedule.id, |s| {
+                    s.auth_token = token;
+                    s.token_renewed_at = now;
+                });
+            }
+        }
+
+        for schedule in store.due(now) {
+            store.update(&schedule.id, |s| s.update_next_run(now));
+            // failures to start are recorded in the history of the schedule
+            let _ = start_run(&files_api, &schedule, &store, &handles).await;
+        }
+
+        time::sleep(SCHEDULER_TICK).await;
+    }
+}
+
+/// Starts a transfer of the copy request of `schedule` as the user who
+/// created it and records the run in the history of the schedule. Returns
+/// the id of the transfer.
+async fn start_run(
+    files_api: &FilesApi,
+    schedule: &Schedule,
+    store: &ScheduleStore,
+    handles: &TransferHandles,
+) -> Result<String, ClientError> {
+    let transfer_id = Client::random_hex();
+    let started_at = now();
+
+    let launched = launch_run(files_api, schedule, &transfer_id, handles).await;
+    let (run, result) = match launched {
+        Ok(future) => {
+            task::spawn(watch_run(
+                future,
+                schedule.id.clone(),
+                transfer_id.clone(),
+                store.clone(),
+                handles.clone(),
+            ));
+            let run = ScheduleRun {
+                transfer_id: transfer_id.clone(),
+                started_at,
+                finished_at: None,
+                phase: TransferPhase::Queued,
+                error: None,
+            };
+            (run, Ok(transfer_id))
+        }
+        Err(e) => {
+            let run = ScheduleRun {
+                transfer_id,
+                started_at,
+                finished_at: Some(started_at),
+                phase: TransferPhase::Failed,
+                error: Some(format!("{} (code:{})", e.message, e.code)),
+            };
+            (run, Err(e))
+        }
+    };
+    store.update(&schedule.id, |s| add_run(&mut s.history, run));
+
+    result
+}
+
+async fn launch_run(
+    files_api: &FilesApi,
+    schedule: &Schedule,
+    transfer_id: &str,
+    handles: &TransferHandles,
+) -> Result<JoinHandle<Result<(), FutureError>>, ClientError> {
+    // the owner must still have access to the agent
+    let auth_cookie = Cookie::new("rc_auth", schedule.auth_token.clone());
+    let (agent, auth_token) = files_api
+        .get_agent(schedule.agent_id, Some(&auth_cookie))
+        .await
+        .map_err(|e| ClientError {
+            code: e.code,
+            message: e.message,
+            http_code: e.http_code.map(|c| c as i32),
+        })?;
+
+    let transfer = prepare_copy(
+        files_api,
+        &agent,
+        &auth_token,
+        transfer_id,
+        &schedule.request,
+    )
+    .await?;
+
+    Ok(launch_copy(
+        transfer,
+        &schedule.request,
+        agent.bandwidth_limit,
+        handles,
+    ))
+}
+
+/// Waits for the transfer of a run to end and records its result.
+async fn watch_run(
+    future: JoinHandle<Result<(), FutureError>>,
+    schedule_id: String,
+    transfer_id: String,
+    store: ScheduleStore,
+    handles: TransferHandles,
+) {
+    let joined = future.await;
+
+    let (phase, error) = match handles.store.get(&transfer_id) {
+        Some(record) if record.phase.is_finished() => (record.phase, record.error),
+        _ => match joined {
+            Ok(Ok(_)) => (TransferPhase::Complete, None),
+            Ok(Err(e)) => (
+                TransferPhase::Failed,
+                Some(format!("{} (code:{})", e.message, e.code)),
+            ),
+            Err(e) => (TransferPhase::Failed, Some(e.to_string())),
+        },
+    };
+
+    store.update(&schedule_id, |s| {
+        if let Some(run) = s
+            .history
+            .iter_mut()
+            .find(|run| run.transfer_id == transfer_id)
+        {
+            run.finished_at = Some(now());
+            run.phase = phase;
+            run.error = error;
+        }
+    });
+}
+
+/// Checks the settings of a schedule before storing them.
+fn validate_schedule(request: &ScheduleRequest) -> Result<(), ClientError> {
+    if let Err(e) = parse_cron(&request.cron) {
+        return Err(ClientError {
+            code: 926,
+            message: e,
+            http_code: Some(400),
+        });
+    }
+
+    let copy = &request.request;
+    let invalid = if copy.dry_run {
+        Some("A scheduled copy cannot be a dry run".to_string())
+    } else if copy.items.is_empty() {
+        Some("A scheduled copy needs at least one item".to_string())
+    } else {
+        SkipFilter::new(&copy.skip_rules)
+            .err()
+            .or_else(|| copy.check_conflict_strategies().err())
+    };
+
+    match invalid {
+        Some(message) => Err(ClientError {
+            code: 927,
+            message,
+            http_code: Some(400),
+        }),
+        None => Ok(()),
+    }
+}
+
+fn schedule_not_found(schedule_id: &str) -> (Status, Json<ScheduleResponse>) {
+    (
+        Status::NotFound,
+        Json(ScheduleResponse {
+            code: 929,
+            schedule: None,
+            error: Some(format!("Schedule {schedule_id} not found")),
+        }),
+    )
+}
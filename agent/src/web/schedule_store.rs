@@ -0,0 +1,400 @@
+use chrono::{TimeZone, Utc};
+use cron::Schedule as CronSchedule;
+use rocket::serde::{json::serde_json, Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    fs::OpenOptions,
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    constants::MAX_SCHEDULE_HISTORY, resource::CopyRequest, transfer_store::TransferPhase,
+};
+
+/// ScheduleRun records a run of a schedule: the transfer it started and,
+/// once finished, its result.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ScheduleRun {
+    pub transfer_id: String,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    pub phase: TransferPhase,
+    pub error: Option<String>,
+}
+
+/// Schedule runs the copy `request` at the times matching `cron`, on behalf
+/// of the user who created it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Schedule {
+    pub id: String,
+    pub agent_id: u32,
+    pub user_id: u32,
+    pub name: String,
+    pub cron: String,
+    pub enabled: bool,
+    pub request: CopyRequest,
+    pub created_at: u64,
+    pub next_run_at: Option<u64>,
+    pub history: Vec<ScheduleRun>,
+    /// JWT token of the user in Files, renewed while the schedule is enabled so
+    /// the runs are authorized as the user
+    pub auth_token: String,
+    pub token_renewed_at: u64,
+}
+
+impl Schedule {
+    /// Returns true if the last run has not finished yet.
+    pub fn is_running(&self) -> bool {
+        matches!(self.history.last(), Some(run) if run.finished_at.is_none())
+    }
+
+    /// Computes the next time the schedule is due after `after`.
+    pub fn update_next_run(&mut self, after: u64) {
+        self.next_run_at = match self.enabled {
+            true => get_next_run(&self.cron, after),
+            false => None,
+        };
+    }
+}
+
+/// ScheduleStore keeps the schedules of this agent and persists them to a
+/// JSON file, including the runs in progress, which are reported as failed
+/// on the next startup if the webserver was restarted in the meantime.
+#[derive(Clone)]
+pub struct ScheduleStore {
+    file_path: String,
+    schedules: Arc<Mutex<HashMap<String, Schedule>>>,
+}
+
+impl ScheduleStore {
+    /// Loads the store from `file_path`. A missing or unreadable file
+    /// results in an empty store.
+    pub fn open(file_path: &str) -> Self {
+        let mut schedules: HashMap<String, Schedule> = match fs::read_to_string(file_path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_default(),
+            Err(_) => HashMap::new(),
+        };
+
+        // runs cannot have survived a restart, runs missed since then are
+        // skipped to the next time the schedules are due
+        let now = now();
+        for schedule in schedules.values_mut() {
+            if let Some(run) = schedule.history.last_mut() {
+                if run.finished_at.is_none() {
+                    run.finished_at = Some(now);
+                    run.phase = TransferPhase::Failed;
+                    run.error = Some("Run interrupted by agent restart (code:932)".to_string());
+                }
+            }
+            schedule.update_next_run(now);
+        }
+
+        let store = Self {
+            file_path: file_path.to_string(),
+            schedules: Arc::new(Mutex::new(schedules)),
+        };
+        store.save();
+
+        store
+    }
+
+    pub fn insert(&self, schedule: Schedule) {
+        self.schedules
+            .lock()
+            .unwrap()
+            .insert(schedule.id.clone(), schedule);
+        self.save();
+    }
+
+    /// Returns the schedule with the id `schedule_id` of the agent with the
+    /// id `agent_id`, if it is in the store.
+    pub fn get(&self, agent_id: u32, schedule_id: &str) -> Option<Schedule> {
+        match self.schedules.lock().unwrap().get(schedule_id) {
+            Some(s) if s.agent_id == agent_id => Some(s.clone()),
+            _ => None,
+        }
+    }
+
+    /// Returns the schedules of the agent with the id `agent_id`, oldest
+    /// first.
+    pub fn list(&self, agent_id: u32) -> Vec<Schedule> {
+        let mut schedules: Vec<Schedule> = self
+            .schedules
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|s| s.agent_id == agent_id)
+            .cloned()
+            .collect();
+        schedules.sort_by_key(|s| s.created_at);
+
+        schedules
+    }
+
+    /// Applies `update` to the schedule with the id `schedule_id` and
+    /// returns the updated schedule, or `None` if it is not in the store.
+    pub fn update<F: FnOnce(&mut Schedule)>(
+        &self,
+        schedule_id: &str,
+        update: F,
+    ) -> Option<Schedule> {
+        let schedule = {
+            let mut schedules = self.schedules.lock().unwrap();
+            let schedule = schedules.get_mut(schedule_id)?;
+            update(schedule);
+            schedule.clone()
+        };
+        self.save();
+
+        Some(schedule)
+    }
+
+    pub fn remove(&self, agent_id: u32, schedule_id: &str) -> bool {
+        let removed = {
+            let mut schedules = self.schedules.lock().unwrap();
+            match schedules.get(schedule_id) {
+                Some(s) if s.agent_id == agent_id => schedules.remove(schedule_id).is_some(),
+                _ => false,
+            }
+        };
+        if removed {
+            self.save();
+        }
+
+        removed
+    }
+
+    /// Returns the enabled schedules due at `now` which are not running.
+    pub fn due(&self, now: u64) -> Vec<Schedule> {
+        self.schedules
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|s| matches!(s.next_run_at, Some(t) if t <= now) && !s.is_running())
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the enabled schedules whose token was last renewed before
+    /// `before`. The tokens of disabled schedules are left to expire.
+    pub fn renewal_due(&self, before: u64) -> Vec<Schedule> {
+        self.schedules
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|s| s.enabled && s.token_renewed_at < before)
+            .cloned()
+            .collect()
+    }
+
+    fn save(&self) {
+        // the lock is held while writing so concurrent saves do not interleave
+        let schedules = self.schedules.lock().unwrap();
+        let content = match serde_json::to_string(&*schedules) {
+            Ok(c) => c,
+            Err(_) => return,
+        };
+
+        // write to a temp file first so that a crash cannot leave a truncated store behind,
+        // readable by the agent only as it holds the tokens of the users
+        let temp_path = format!("{}.tmp", self.file_path);
+        let _ = fs::remove_file(&temp_path);
+        let written = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&temp_path)
+            .and_then(|mut file| file.write_all(content.as_bytes()));
+        if written.is_ok() {
+            let _ = fs::rename(&temp_path, &self.file_path);
+        }
+    }
+}
+
+/// Appends `run` to `history`, dropping the oldest runs over the limit.
+pub fn add_run(history: &mut Vec<ScheduleRun>, run: ScheduleRun) {
+    history.push(run);
+    if history.len() > MAX_SCHEDULE_HISTORY {
+        history.drain(..history.len() - MAX_SCHEDULE_HISTORY);
+    }
+}
+
+/// Parses a cron expression. The usual five fields (minute, hour, day of
+/// month, month, day of week) are accepted as well as a leading seconds
+/// field and a trailing year field. Days of the week are numbered as in a
+/// crontab, 0 or 7 being Sunday, or named. Times are in UTC.
+pub fn parse_cron(expression: &str) -> Result<CronSchedule, String> {
+    let mut fields: Vec<String> = expression
+        .split_whitespace()
+        .map(|f| f.to_string())
+        .collect();
+    if fields.len() == 5 {
+        fields.insert(0, "0".to_string());
+    }
+    // the cron crate numbers the days of the week from 1, Sunday, so they
+    // are passed on by name
+    if let Some(day_of_week) = fields.get_mut(5) {
+        *day_of_week = get_day_of_week_names(day_of_week)
+            .map_err(|e| format!("Invalid cron expression {}: {}", expression.trim(), e))?;
+    }
+    let expression = fields.join(" ");
+
+    CronSchedule::from_str(&expression)
+        .map_err(|e| format!("Invalid cron expression {}: {}", expression, e))
+}
+
+const DAY_NAMES: [&str; 7] = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+
+/// Rewrites the day of week field of a crontab as the list of the names of
+/// the days it selects, e.g. "1-5/2" as "MON,WED,FRI".
+fn get_day_of_week_names(field: &str) -> Result<String, String> {
+    if field == "*" || field == "?" {
+        return Ok(field.to_string());
+    }
+
+    let parse_day = |day: &str| -> Result<usize, String> {
+        match DAY_NAMES.iter().position(|n| n.eq_ignore_ascii_case(day)) {
+            Some(d) => Ok(d),
+            None => match day.parse::<usize>() {
+                Ok(d) if d <= 7 => Ok(d),
+                _ => Err(format!("invalid day of week {}", day)),
+            },
+        }
+    };
+
+    let mut days = [false; 7];
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => match step.parse::<usize>() {
+                Ok(s) if s > 0 => (range, Some(s)),
+                _ => return Err(format!("invalid step {}", step)),
+            },
+            None => (item, None),
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (0, 6),
+            Some((first, last)) => (parse_day(first)?, parse_day(last)?),
+            // a single day with a step runs up to the end of the week
+            None if step.is_some() => (parse_day(range)?, 6),
+            None => {
+                let day = parse_day(range)?;
+                (day, day)
+            }
+        };
+        if first > last {
+            return Err(format!("invalid range of days {}", range));
+        }
+        for day in (first..=last).step_by(step.unwrap_or(1)) {
+            days[day % 7] = true;
+        }
+    }
+
+    Ok(DAY_NAMES
+        .iter()
+        .zip(days)
+        .filter(|(_, selected)| *selected)
+        .map(|(name, _)| *name)
+        .collect::<Vec<&str>>()
+        .join(","))
+}
+
+/// Returns the first time after `after` matching the cron `expression`.
+pub fn get_next_run(expression: &str, after: u64) -> Option<u64> {
+    let schedule = parse_cron(expression).ok()?;
+    let after = Utc.timestamp_opt(after as i64, 0).single()?;
+
+    schedule
+        .after(&after)
+        .next()
+        .map(|t| t.timestamp().max(0) as u64)
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sunday, 7 January 2024 00:00 UTC
+    const SUNDAY: u64 = 1704585600;
+    const DAY: u64 = 24 * 3600;
+
+    #[test]
+    fn weekdays_are_numbered_as_in_a_crontab() {
+        assert_eq!(
+            get_next_run("0 2 * * 1", SUNDAY),
+            Some(SUNDAY + DAY + 2 * 3600)
+        );
+        assert_eq!(
+            get_next_run("0 2 * * MON", SUNDAY),
+            Some(SUNDAY + DAY + 2 * 3600)
+        );
+        assert_eq!(get_next_run("30 0 * * 0", SUNDAY), Some(SUNDAY + 1800));
+        assert_eq!(get_next_run("30 0 * * 7", SUNDAY), Some(SUNDAY + 1800));
+        assert_eq!(get_next_run("* * * * 0", SUNDAY), Some(SUNDAY + 60));
+        assert_eq!(get_next_run("0 0 * * 6", SUNDAY), Some(SUNDAY + 6 * DAY));
+    }
+
+    #[test]
+    fn weekday_ranges_and_steps() {
+        assert_eq!(get_day_of_week_names("1-5").unwrap(), "MON,TUE,WED,THU,FRI");
+        assert_eq!(get_day_of_week_names("5-7").unwrap(), "SUN,FRI,SAT");
+        assert_eq!(get_day_of_week_names("1-5/2").unwrap(), "MON,WED,FRI");
+        assert_eq!(get_day_of_week_names("*/3").unwrap(), "SUN,WED,SAT");
+        assert_eq!(get_day_of_week_names("4/1").unwrap(), "THU,FRI,SAT");
+        assert_eq!(get_day_of_week_names("0,sat").unwrap(), "SUN,SAT");
+        assert_eq!(get_day_of_week_names("*").unwrap(), "*");
+
+        // the next Friday, then Saturday and Sunday
+        let runs: Vec<u64> = parse_cron("0 0 * * 5-7")
+            .unwrap()
+            .after(&Utc.timestamp_opt(SUNDAY as i64, 0).unwrap())
+            .take(3)
+            .map(|t| t.timestamp() as u64)
+            .collect();
+        assert_eq!(runs, [SUNDAY + 5 * DAY, SUNDAY + 6 * DAY, SUNDAY + 7 * DAY]);
+    }
+
+    #[test]
+    fn seconds_and_year_fields() {
+        assert_eq!(
+            get_next_run("15 0 0 * * 1", SUNDAY),
+            Some(SUNDAY + DAY + 15)
+        );
+        assert_eq!(get_next_run("0 0 0 * * 1 2024", SUNDAY), Some(SUNDAY + DAY));
+        assert_eq!(get_next_run("0 0 0 * * 1 2023", SUNDAY), None);
+    }
+
+    #[test]
+    fn reject_invalid_expressions() {
+        for expression in [
+            "",
+            "* * * *",
+            "0 0 * * 8",
+            "0 0 * * 5-2",
+            "0 0 * * */0",
+            "0 0 * * FOO",
+            "60 * * * *",
+            "0 24 * * *",
+            "0 0 32 * *",
+        ] {
+            assert!(
+                parse_cron(expression).is_err(),
+                "{expression} should be rejected"
+            );
+            assert_eq!(get_next_run(expression, SUNDAY), None);
+        }
+    }
+}
@@ -1,3 +1,5 @@
+#[path = "../cli/access_token.rs"]
+pub mod access_token;
 #[path = "../cli/archive.rs"]
 pub mod archive;
 #[path = "../cli/client.rs"]
@@ -6,25 +8,57 @@ pub mod client;
 pub mod command;
 #[path = "../cli/command_runner.rs"]
 mod command_runner;
+#[path = "../cli/config.rs"]
+pub mod config;
+#[path = "../cli/conflict.rs"]
+pub mod conflict;
 #[path = "../cli/constants.rs"]
 pub mod constants;
 #[path = "../files_api.rs"]
 mod files_api;
+#[path = "../cli/filter.rs"]
+pub mod filter;
+#[path = "../cli/keys.rs"]
+pub mod keys;
+#[path = "../cli/sync.rs"]
+pub mod sync;
+#[path = "../cli/transfer_store.rs"]
+pub mod transfer_store;
+#[path = "../cli/upload.rs"]
+pub mod upload;
 
 mod key_exchange;
 mod miscellaneous;
+mod plan;
 mod remote_user;
 mod resource;
+mod schedule;
+mod schedule_store;
 mod temporary_access_token;
 mod transfer;
+mod transfer_queue;
 
 #[macro_use]
 extern crate rocket;
 
 use crate::{
-    files_api::FilesApi, key_exchange::*, miscellaneous::*, remote_user::*, resource::*,
-    temporary_access_token::*, transfer::cancel_transfer,
+    config::CONFIG,
+    files_api::{FilesApi, Transfer},
+    key_exchange::*,
+    miscellaneous::*,
+    remote_user::*,
+    resource::*,
+    schedule::*,
+    schedule_store::ScheduleStore,
+    temporary_access_token::*,
+    transfer::{
+        cancel_transfer, get_transfer, list_transfers, recover_interrupted_transfers,
+        report_interrupted_transfers, resume_transfer, update_transfer,
+    },
+    transfer_queue::{get_queue, update_queue, TransferQueue},
+    transfer_store::TransferStore,
 };
+use rocket::tokio::task;
 use std::{
     collections::HashMap,
     sync::{Arc, Mutex},
@@ -36,9 +70,47 @@ use std::{
 /// various task execution phases of each transfer so it can
 /// initiate a self abort whenever the flags state is flipped.
 /// A transfer's 'cancel requested' flag is is set via a user
-/// initiated request at an arbitrary phase of the transfer.
+/// initiated request at an arbitrary phase of the transfer and
+/// is removed from the list when the transfer has finished.
 pub struct CancelTransferRequests {
-    pub transfers: Arc<Mutex<HashMap<String, Arc<Mutex<bool>>>>>,
+    pub transfers: CancelRequests,
+}
+
+pub type CancelRequests = Arc<Mutex<HashMap<String, Arc<Mutex<bool>>>>>;
+
+/// BandwidthLimits holds the bandwidth limit, in bytes per second, of
+/// each running upload. The limits are read while the archive is being
+/// sent so a running transfer can be slowed down or sped up by changing
+/// its limit, 0 meaning unlimited.
+pub struct BandwidthLimits {
+    pub transfers: TransferLimits,
+}
+
+pub type TransferLimits = Arc<Mutex<HashMap<String, Arc<Mutex<u64>>>>>;
+
+/// ResumableTransfers holds the transfers whose upload failed after the
+/// archive was created. The local copy of their archive is kept so that
+/// the upload can be resumed from where it was interrupted.
+pub struct ResumableTransfers {
+    pub transfers: Arc<Mutex<HashMap<String, Transfer>>>,
+}
+
+/// TransferRegistry holds the persistent store of the transfers executed
+/// by this agent, see `TransferStore`.
+pub struct TransferRegistry {
+    pub store: TransferStore,
+}
+
+/// TransferScheduler holds the queue which decides when a transfer may
+/// start running, see `TransferQueue`.
+pub struct TransferScheduler {
+    pub queue: TransferQueue,
+}
+
+/// Schedules holds the schedules of the transfers run periodically on
+/// behalf of their owners, see `ScheduleStore`.
+pub struct Schedules {
+    pub store: ScheduleStore,
 }
 
 pub struct Files {
@@ -47,23 +119,79 @@ pub struct Files {
 
 #[rocket::main]
 async fn main() -> Result<(), rocket::Error> {
-    let transfers: Arc<Mutex<HashMap<String, Arc<Mutex<bool>>>>> =
-        Arc::new(Mutex::new(HashMap::new()));
+    // load the configuration, exiting if invalid, the scripts are given
+    // its paths by the processes running them
+    CONFIG.require_installation();
+
+    let transfers: CancelRequests = Arc::new(Mutex::new(HashMap::new()));
+    let limits: TransferLimits = Arc::new(Mutex::new(HashMap::new()));
+    let resumable: Arc<Mutex<HashMap<String, Transfer>>> = Arc::new(Mutex::new(HashMap::new()));
     let files = FilesApi::new();
 
+    // clean up after the transfers interrupted by a restart, Files is
+    // notified in the background so that an unreachable Files cannot delay
+    // the launch
+    let store = TransferStore::open(&CONFIG.transfer_store_file);
+    let interrupted = recover_interrupted_transfers(&store, &resumable);
+    task::spawn(report_interrupted_transfers(interrupted));
+    let queue = TransferQueue::new();
+
+    // run the scheduled transfers in the background
+    let schedules = ScheduleStore::open(&CONFIG.schedule_store_file);
+    task::spawn(run_scheduler(
+        schedules.clone(),
+        TransferHandles {
+            cancel_requests: transfers.clone(),
+            bandwidth_limits: limits.clone(),
+            resumable: resumable.clone(),
+            store: store.clone(),
+            queue: queue.clone(),
+        },
+    ));
+
     let api = "/api";
-    let _rocket = rocket::build()
+    let figment = rocket::Config::figment()
+        .merge(("address", &CONFIG.listen_address))
+        .merge(("port", CONFIG.listen_port));
+    let _rocket = rocket::custom(figment)
         .manage(Files { api: files })
         .manage(CancelTransferRequests { transfers })
+        .manage(BandwidthLimits { transfers: limits })
+        .manage(ResumableTransfers {
+            transfers: resumable,
+        })
+        .manage(TransferRegistry { store })
+        .manage(TransferScheduler { queue })
+        .manage(Schedules { store: schedules })
         .mount(api, routes![get_temporary_access_token])
         .mount(api, routes![register_public_key])
+        .mount(api, routes![get_host_key])
+        .mount(api, routes![repin_host_key])
+        .mount(api, routes![list_keys])
+        .mount(api, routes![rotate_key])
+        .mount(api, routes![revoke_key])
         .mount(api, routes![get_token_user])
         .mount(api, routes![get_remote_user])
         .mount(api, routes![ping])
         .mount(api, routes![resources])
         .mount(api, routes![copy])
+        .mount(api, routes![plan_copy])
+        .mount(api, routes![download])
+        .mount(api, routes![relay])
         .mount(api, routes![version])
+        .mount(api, routes![list_transfers])
+        .mount(api, routes![get_transfer])
+        .mount(api, routes![update_transfer])
         .mount(api, routes![cancel_transfer])
+        .mount(api, routes![resume_transfer])
+        .mount(api, routes![get_queue])
+        .mount(api, routes![update_queue])
+        .mount(api, routes![list_schedules])
+        .mount(api, routes![create_schedule])
+        .mount(api, routes![get_schedule])
+        .mount(api, routes![update_schedule])
+        .mount(api, routes![delete_schedule])
+        .mount(api, routes![run_schedule])
         .launch()
         .await?;
 
@@ -75,6 +203,8 @@ fn start_server() -> _ {
     let api = "/api";
     rocket::build()
         .mount(api, routes![register_public_key])
+        .mount(api, routes![get_host_key])
+        .mount(api, routes![repin_host_key])
         .mount(api, routes![ping])
         .mount(api, routes![resources])
         .mount(api, routes![before_copy])
@@ -1,17 +1,28 @@
 use rocket::{
     http::{CookieJar, Status},
-    serde::{json::Json, Serialize},
+    serde::{
+        json::{serde_json, Json},
+        Serialize,
+    },
     State,
 };
 use sha256::digest;
 use std::{
+    fs,
     ops::Add,
     path::Path,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tokio::{task, time::Instant};
 
-use crate::{client::Client, command_runner::run_command_async, constants::DEFAULTS, Files};
+use crate::{
+    access_token::{generate_access_token, generate_legacy_access_token, KeyAlgorithm},
+    client::Client,
+    command_runner::run_command_async,
+    config::CONFIG,
+    keys::{add_authorized_key, remove_authorized_key},
+    Files,
+};
 
 #[derive(Serialize, Debug)]
 pub struct TemporaryAccessTokenResponse {
@@ -21,9 +32,13 @@ pub struct TemporaryAccessTokenResponse {
     error: Option<String>,
 }
 
-#[get("/users/<user_id>/temporary-access-token")]
+/// Creates a temporary access token for pairing with this agent. With
+/// `legacy`, the token is in the format of older agents, for the remotes
+/// which report a version not parsing the others.
+#[get("/users/<user_id>/temporary-access-token?<legacy>")]
 pub async fn get_temporary_access_token(
     user_id: u32,
+    legacy: Option<bool>,
     files: &State<Files>,
     cookies: &CookieJar<'_>,
 ) -> (Status, Json<TemporaryAccessTokenResponse>) {
@@ -47,44 +62,58 @@ pub async fn get_temporary_access_token(
         }
     };
 
-    // create arguments for the generate temporary access token command
     let key_id = Client::random_hex();
-    let user_id = user.id.to_string();
     let instance_name;
     if let Some(branding_cookie) = cookies.get("rc_branding") {
         instance_name = branding_cookie.value();
     } else {
         instance_name = "WebSCP";
     }
-    let args: Vec<&str> = vec![
-        DEFAULTS.generate_key_pair_script_path,
-        &key_id,
-        &user_id,
-        &user.username,
-        instance_name,
-    ];
-
-    // execute command
-    let token = match run_command_async(280, false, false, "bash", args).await {
+
+    // generate the key pair of the token, the algorithm is validated with the configuration
+    let algorithm = KeyAlgorithm::from_name(&CONFIG.temporary_key_algorithm).unwrap();
+    let (valid_until_timestamp, valid_until_instant) = valid_until(300);
+    let comment = format!("webscp-temporary-{key_id}-user-{}", user.id);
+    let generated = match legacy {
+        Some(true) => generate_legacy_access_token(valid_until_timestamp, &comment),
+        _ => generate_access_token(algorithm, valid_until_timestamp, &comment),
+    };
+    let (token, public_key) = match generated {
         Ok(t) => t,
-        Err(err) => {
-            return (
-                err.status,
-                Json(TemporaryAccessTokenResponse {
-                    code: err.code,
-                    token: None,
-                    error: Some(err.message),
-                    valid_until: None,
-                }),
+        Err(e) => return token_error(Status::InternalServerError, 943, e),
+    };
+    let token = token.encode();
+
+    // authorize the key and leave the user info for the remote agent to read
+    // in the file named after the token, which is removed once paired
+    let (key_type, blob) = match public_key.split_whitespace().collect::<Vec<&str>>()[..] {
+        [key_type, blob, ..] => (key_type.to_string(), blob.to_string()),
+        _ => {
+            return token_error(
+                Status::InternalServerError,
+                943,
+                "Invalid public key".to_string(),
             )
         }
     };
+    let token_hash = digest(token.clone());
+    let user_info = serde_json::json!({
+        "id": user.id,
+        "name": user.username,
+        "branding": instance_name,
+    });
+    let user_info_path = format!("{}/{}", CONFIG.ssh_dir_path, token_hash);
+    if let Err(e) = fs::write(&user_info_path, user_info.to_string()) {
+        return token_error(Status::InternalServerError, 944, e.to_string());
+    }
+    if let Err(e) = add_authorized_key(&key_type, &blob, Some(&comment)) {
+        let _ = fs::remove_file(&user_info_path);
+        return token_error(Status::InternalServerError, 944, e);
+    }
 
     // schedule temporary access token revocation
-    let (valid_until_timestamp, valid_until_instant) = valid_until(300);
-    let token_hash = digest(token.clone());
     let _future = task::spawn(revoke_temporary_access_token(
-        key_id,
+        blob,
         token_hash,
         valid_until_instant,
     ));
@@ -101,6 +130,22 @@ pub async fn get_temporary_access_token(
     )
 }
 
+fn token_error(
+    status: Status,
+    code: i32,
+    error: String,
+) -> (Status, Json<TemporaryAccessTokenResponse>) {
+    (
+        status,
+        Json(TemporaryAccessTokenResponse {
+            code,
+            token: None,
+            valid_until: None,
+            error: Some(error),
+        }),
+    )
+}
+
 fn valid_until(seconds: u64) -> (u64, Instant) {
     let instant = Instant::now().add(Duration::from_secs(seconds));
     let systime = SystemTime::now().add(Duration::from_secs(seconds));
@@ -110,18 +155,12 @@ fn valid_until(seconds: u64) -> (u64, Instant) {
 }
 
 async fn revoke_temporary_access_token(
-    token_id: String,
+    public_key: String,
     token_hash: String,
     token_expires: Instant,
 ) -> Result<(), ()> {
     let one_sec = Duration::from_secs(1);
-    let lock_file_path = format!("{}/{}", DEFAULTS.ssh_dir_path, token_hash);
-
-    // create arguments for the revoke key command
-    let args: Vec<&str> = vec![DEFAULTS.revoke_key_pair_script_path, &token_id];
-
-    // initialize command
-    let revoke_key = run_command_async(281, false, false, "bash", args);
+    let lock_file_path = format!("{}/{}", CONFIG.ssh_dir_path, token_hash);
 
     // create arguments for the remove lock file command
     let args: Vec<&str> = vec!["-f", &lock_file_path];
@@ -147,8 +186,8 @@ async fn revoke_temporary_access_token(
         }
     }
 
-    // execute revoke key command
-    let _ = revoke_key.await;
+    // revoke key
+    let _ = remove_authorized_key(&public_key);
 
     Ok(())
 }
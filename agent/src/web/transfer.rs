@@ -1,9 +1,178 @@
 use rocket::{
     http::{CookieJar, Status},
+    serde::{json::Json, Deserialize, Serialize},
+    tokio::task,
     State,
 };
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use urlencoding::decode;
+
+use crate::{
+    archive::{ArchiveCompression, ProgressSink},
+    client::{Client, ClientError},
+    config::CONFIG,
+    conflict::ConflictResolution,
+    files_api::{FilesApi, Transfer},
+    transfer_queue::QueueTicket,
+    transfer_store::{TransferPhase, TransferRecord, TransferStore},
+    upload::{find_upload_archive, get_upload_archive_path, send_transfer_result},
+    BandwidthLimits, CancelRequests, CancelTransferRequests, Files, ResumableTransfers,
+    TransferLimits, TransferRegistry, TransferScheduler,
+};
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct TransferStatus {
+    transfer_id: String,
+    phase: TransferPhase,
+    local_path: String,
+    remote_path: String,
+    started_at: u64,
+    updated_at: u64,
+    items_processed: usize,
+    items_total: usize,
+    files_processed: usize,
+    files_skipped: usize,
+    files_deleted: usize,
+    files_excluded: usize,
+    items_moved: usize,
+    bytes_sent: u64,
+    size: u64,
+    throughput: u64,
+    eta: Option<u64>,
+    conflicts: Vec<ConflictResolution>,
+    error: Option<String>,
+}
+
+impl From<TransferRecord> for TransferStatus {
+    fn from(record: TransferRecord) -> Self {
+        Self {
+            throughput: record.throughput(),
+            eta: record.eta(),
+            transfer_id: record.transfer.transfer_id,
+            phase: record.phase,
+            local_path: record.transfer.local_path,
+            remote_path: record.transfer.remote_path,
+            started_at: record.started_at,
+            updated_at: record.updated_at,
+            items_processed: record.items_processed,
+            items_total: record.items_total,
+            files_processed: record.files_processed,
+            files_skipped: record.files_skipped,
+            files_deleted: record.files_deleted,
+            files_excluded: record.files_excluded,
+            items_moved: record.items_moved,
+            bytes_sent: record.bytes_sent,
+            size: record.transfer.size,
+            conflicts: record.conflicts,
+            error: record.error,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct TransfersResponse {
+    code: i32,
+    transfers: Vec<TransferStatus>,
+    error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct TransferResponse {
+    code: i32,
+    transfer: Option<TransferStatus>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct TransferUpdateRequest {
+    bandwidth_limit: u64,
+}
+
+#[get("/agents/<agent_id>/transfers")]
+pub async fn list_transfers(
+    agent_id: u32,
+    files: &State<Files>,
+    registry: &State<TransferRegistry>,
+    cookies: &CookieJar<'_>,
+) -> (Status, Json<TransfersResponse>) {
+    // verify that the requester has a valid session in Files and owns the referred agent
+    if let Err(e) = files.api.get_agent(agent_id, cookies.get("rc_auth")).await {
+        return (
+            Status::Forbidden,
+            Json(TransfersResponse {
+                code: 916,
+                transfers: vec![],
+                error: Some(e.message),
+            }),
+        );
+    }
+
+    let transfers = registry
+        .store
+        .list(agent_id)
+        .into_iter()
+        .map(TransferStatus::from)
+        .collect();
+
+    (
+        Status::Ok,
+        Json(TransfersResponse {
+            code: 0,
+            transfers,
+            error: None,
+        }),
+    )
+}
+
+#[get("/agents/<agent_id>/transfers/<transfer_id>")]
+pub async fn get_transfer(
+    agent_id: u32,
+    transfer_id: &str,
+    files: &State<Files>,
+    registry: &State<TransferRegistry>,
+    cookies: &CookieJar<'_>,
+) -> (Status, Json<TransferResponse>) {
+    // verify that the requester has a valid session in Files and owns the referred agent
+    if let Err(e) = files.api.get_agent(agent_id, cookies.get("rc_auth")).await {
+        return (
+            Status::Forbidden,
+            Json(TransferResponse {
+                code: 917,
+                transfer: None,
+                error: Some(e.message),
+            }),
+        );
+    }
 
-use crate::{CancelTransferRequests, Files};
+    // transfers of other agents are not disclosed
+    match registry.store.get(transfer_id) {
+        Some(record) if record.transfer.agent_id == agent_id => (
+            Status::Ok,
+            Json(TransferResponse {
+                code: 0,
+                transfer: Some(TransferStatus::from(record)),
+                error: None,
+            }),
+        ),
+        _ => (
+            Status::NotFound,
+            Json(TransferResponse {
+                code: 918,
+                transfer: None,
+                error: Some(format!("Transfer {transfer_id} not found")),
+            }),
+        ),
+    }
+}
 
 #[delete("/agents/<agent_id>/transfers/<transfer_id>")]
 pub async fn cancel_transfer(
@@ -11,6 +180,7 @@ pub async fn cancel_transfer(
     transfer_id: &str,
     files: &State<Files>,
     cancel_requests: &State<CancelTransferRequests>,
+    scheduler: &State<TransferScheduler>,
     cookies: &CookieJar<'_>,
 ) -> Status {
     // verify that the requester has a valid session in Files and owns the referred agent
@@ -21,6 +191,9 @@ pub async fn cancel_transfer(
         }
     };
 
+    // a transfer waiting in the queue is aborted by taking it off the queue
+    scheduler.queue.remove(agent_id, transfer_id);
+
     // get the shared state holding the list of cancel transfer request flags
     let transfers = cancel_requests.transfers.lock().unwrap();
 
@@ -42,3 +215,317 @@ pub async fn cancel_transfer(
     // success response
     Status::NotFound
 }
+
+#[patch("/agents/<agent_id>/transfers/<transfer_id>", data = "<request>")]
+pub async fn update_transfer(
+    agent_id: u32,
+    transfer_id: &str,
+    request: Json<TransferUpdateRequest>,
+    files: &State<Files>,
+    registry: &State<TransferRegistry>,
+    bandwidth_limits: &State<BandwidthLimits>,
+    cookies: &CookieJar<'_>,
+) -> Status {
+    // verify that the requester has a valid session in Files and owns the referred agent
+    let (_, _) = match files.api.get_agent(agent_id, cookies.get("rc_auth")).await {
+        Ok(a) => a,
+        Err(_) => {
+            return Status::Forbidden;
+        }
+    };
+
+    // transfers of other agents are not changed
+    match registry.store.get(transfer_id) {
+        Some(record) if record.transfer.agent_id == agent_id => {}
+        _ => return Status::NotFound,
+    }
+
+    // get the shared state holding the list of bandwidth limits
+    let transfers = bandwidth_limits.transfers.lock().unwrap();
+
+    // check if the referred transfer is registered in the list
+    if let Some(bandwidth_limit) = transfers.get(transfer_id) {
+        // lock the mutex and set the new limit, which the upload picks up with its next chunk
+        let mut limit = bandwidth_limit.lock().unwrap();
+
+        return if *limit != request.bandwidth_limit {
+            *limit = request.bandwidth_limit;
+
+            Status::Ok
+        } else {
+            Status::NotModified
+        };
+    }
+
+    Status::NotFound
+}
+
+#[allow(clippy::too_many_arguments)]
+#[post("/agents/<agent_id>/transfers/<transfer_id>/resume")]
+pub async fn resume_transfer(
+    agent_id: u32,
+    transfer_id: &str,
+    files: &State<Files>,
+    cancel_requests: &State<CancelTransferRequests>,
+    resumable_transfers: &State<ResumableTransfers>,
+    bandwidth_limits: &State<BandwidthLimits>,
+    registry: &State<TransferRegistry>,
+    scheduler: &State<TransferScheduler>,
+    cookies: &CookieJar<'_>,
+) -> Status {
+    // verify that the requester has a valid session in Files and owns the referred agent
+    let (agent, auth_token) = match files.api.get_agent(agent_id, cookies.get("rc_auth")).await {
+        Ok(a) => a,
+        Err(_) => {
+            return Status::Forbidden;
+        }
+    };
+
+    // take the referred transfer off the list of resumable transfers
+    let mut transfer = {
+        let mut transfers = resumable_transfers.transfers.lock().unwrap();
+        match transfers.get(transfer_id) {
+            Some(t) if t.agent_id == agent_id => transfers.remove(transfer_id).unwrap(),
+            _ => return Status::NotFound,
+        }
+    };
+    transfer.rc_auth = auth_token;
+
+    // register a new 'cancel requested' flag for the resumed transfer
+    let cancel_requested = Arc::new(Mutex::new(false));
+    cancel_requests
+        .transfers
+        .lock()
+        .unwrap()
+        .insert(transfer_id.to_string(), cancel_requested.clone());
+
+    // register the agent's default bandwidth limit for the resumed transfer
+    let bandwidth_limit = Arc::new(Mutex::new(agent.bandwidth_limit));
+    bandwidth_limits
+        .transfers
+        .lock()
+        .unwrap()
+        .insert(transfer_id.to_string(), bandwidth_limit.clone());
+
+    // resume the upload asynchronously in a future
+    registry.store.register(&transfer);
+    let ticket = scheduler.queue.enqueue(&transfer, 0);
+    let _future = task::spawn(finish_resume_in_background(
+        transfer,
+        ticket,
+        cancel_requested,
+        bandwidth_limit,
+        cancel_requests.transfers.clone(),
+        bandwidth_limits.transfers.clone(),
+        resumable_transfers.transfers.clone(),
+        registry.store.clone(),
+    ));
+
+    Status::Ok
+}
+
+/// Deletes the sources of the items of a move transfer through Files, which
+/// checks the user's permission to delete them, once the remote confirmed
+/// their delivery. Items which were not archived completely, or with files
+/// kept in the destination by their conflict strategy, are not deleted.
+pub async fn delete_moved_sources(
+    transfer: &Transfer,
+    progress: &ProgressSink,
+    store: &TransferStore,
+) -> Result<(), ClientError> {
+    // the items archived are not known, e.g. if the webserver was restarted
+    let items = match &transfer.moved_items {
+        Some(items) => items,
+        None => {
+            return Err(ClientError {
+                code: 365,
+                message: "Sources not deleted as their delivery is unconfirmed (code:365)"
+                    .to_string(),
+                http_code: Some(500),
+            })
+        }
+    };
+    let conflicts = store
+        .get(&transfer.transfer_id)
+        .map(|r| r.conflicts)
+        .unwrap_or_default();
+
+    progress.send("deleting").await;
+
+    let files_api = FilesApi::new();
+    let mut moved = 0;
+    let mut errors = Vec::new();
+    for item in items.iter() {
+        let destination = decode(&item.destination).unwrap().into_owned();
+        let destination = destination.trim_start_matches('/');
+        let is_kept =
+            |path: &str| path == destination || path.starts_with(&format!("{}/", destination));
+        if conflicts
+            .iter()
+            .any(|c| !c.replaced && c.renamed_to.is_none() && is_kept(&c.path))
+        {
+            continue;
+        }
+
+        match files_api
+            .delete_resource(&item.source, &transfer.rc_auth)
+            .await
+        {
+            Ok(_) => moved += 1,
+            Err(e) => errors.push(format!("{} {}", e.message, decode(&item.source).unwrap())),
+        }
+    }
+    progress.send(&format!("progress::moved::{}", moved)).await;
+
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(ClientError {
+            code: 366,
+            message: format!("Cannot delete sources: {} (code:366)", errors.join(", ")),
+            http_code: Some(500),
+        }),
+    }
+}
+
+/// Registers a failed transfer as resumable if the local copy of its
+/// archive is still available.
+pub fn keep_resumable(
+    transfer: &Transfer,
+    error: &ClientError,
+    resumable: &Arc<Mutex<HashMap<String, Transfer>>>,
+) {
+    // transfers aborted by user request are not resumed
+    if error.code == 997 {
+        return;
+    }
+
+    let archive_path = get_upload_archive_path(&transfer.transfer_id, transfer.compress.codec);
+    if Path::new(&archive_path).exists() {
+        resumable
+            .lock()
+            .unwrap()
+            .insert(transfer.transfer_id.clone(), transfer.clone());
+    }
+}
+
+/// Records the final state of a transfer in the transfer store and removes
+/// its 'cancel requested' flag which is not needed any more.
+pub fn end_transfer(
+    transfer_id: &str,
+    result: &Result<(), ClientError>,
+    store: &TransferStore,
+    cancel_requests: &CancelRequests,
+) {
+    store.finish(transfer_id, result);
+    cancel_requests.lock().unwrap().remove(transfer_id);
+}
+
+/// Fails the transfers left unfinished by a previous run of the webserver.
+/// Uploads interrupted after their archive was created are kept resumable,
+/// the temp files of all other transfers are removed. Returns the transfers
+/// with the status to report to Files, see `report_interrupted_transfers`.
+pub fn recover_interrupted_transfers(
+    store: &TransferStore,
+    resumable: &Arc<Mutex<HashMap<String, Transfer>>>,
+) -> Vec<(Transfer, &'static str)> {
+    let mut interrupted = Vec::new();
+    for record in store.unfinished() {
+        let mut transfer = record.transfer;
+        let download_path = format!("{}{}.dst.tar", CONFIG.temp_data_dir, transfer.transfer_id);
+        let archive_codec = find_upload_archive(&transfer.transfer_id);
+
+        let message = match archive_codec {
+            Some(codec) if record.phase == TransferPhase::Uploading => {
+                // the archive is resumed whichever codec 'auto' resolved to
+                if transfer.compress.codec != codec {
+                    transfer.compress = ArchiveCompression { codec, level: None };
+                }
+                resumable
+                    .lock()
+                    .unwrap()
+                    .insert(transfer.transfer_id.clone(), transfer.clone());
+                "Transfer interrupted by agent restart, it can be resumed (code:359)"
+            }
+            _ => {
+                while let Some(codec) = find_upload_archive(&transfer.transfer_id) {
+                    let archive_path = get_upload_archive_path(&transfer.transfer_id, codec);
+                    if fs::remove_file(&archive_path).is_err() {
+                        break;
+                    }
+                }
+                let _ = fs::remove_file(&download_path);
+                "Transfer interrupted by agent restart (code:359)"
+            }
+        };
+
+        store.fail(&transfer.transfer_id, message);
+        interrupted.push((transfer, message));
+    }
+
+    interrupted
+}
+
+/// Reports the transfers failed by `recover_interrupted_transfers` to Files.
+pub async fn report_interrupted_transfers(interrupted: Vec<(Transfer, &'static str)>) {
+    let files_api = FilesApi::new();
+    for (transfer, message) in interrupted {
+        files_api
+            .send_upload_status_update_async(&transfer, message)
+            .await;
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn finish_resume_in_background(
+    transfer: Transfer,
+    ticket: QueueTicket,
+    cancel_requested: Arc<Mutex<bool>>,
+    bandwidth_limit: Arc<Mutex<u64>>,
+    cancel_requests: CancelRequests,
+    bandwidth_limits: TransferLimits,
+    resumable: Arc<Mutex<HashMap<String, Transfer>>>,
+    store: TransferStore,
+) -> Result<(), ClientError> {
+    let progress = ProgressSink::Files(transfer.clone(), store.clone());
+
+    // wait for the transfer's turn in the queue
+    let _slot = match ticket.wait(&progress).await {
+        Ok(slot) => slot,
+        Err(e) => {
+            let result = Err(e);
+            bandwidth_limits
+                .lock()
+                .unwrap()
+                .remove(&transfer.transfer_id);
+            end_transfer(&transfer.transfer_id, &result, &store, &cancel_requests);
+            return result;
+        }
+    };
+
+    progress.send("resuming upload").await;
+
+    // send the part of the archive missing on the remote and extract it there
+    let result = Client::remote_do_copy_async(
+        &progress,
+        &transfer,
+        &cancel_requested,
+        &bandwidth_limit,
+        true,
+    )
+    .await;
+    // when moving, delete the sources once the remote confirmed their delivery
+    let result = match result {
+        Ok(_) if transfer.move_sources => delete_moved_sources(&transfer, &progress, &store).await,
+        result => result,
+    };
+    if let Err(e) = &result {
+        keep_resumable(&transfer, e, &resumable);
+    }
+    bandwidth_limits
+        .lock()
+        .unwrap()
+        .remove(&transfer.transfer_id);
+    end_transfer(&transfer.transfer_id, &result, &store, &cancel_requests);
+
+    send_transfer_result(&progress, result).await
+}
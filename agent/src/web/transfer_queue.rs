@@ -0,0 +1,452 @@
+use rocket::{
+    http::{CookieJar, Status},
+    serde::{json::Json, Deserialize, Serialize},
+    tokio::sync::oneshot,
+    State,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    archive::ProgressSink, client::ClientError, config::CONFIG, files_api::Transfer, Files,
+    TransferScheduler,
+};
+
+struct QueuedTransfer {
+    transfer_id: String,
+    agent_id: u32,
+    remote: String,
+    priority: i32,
+    paused: bool,
+    seq: u64,
+    start: oneshot::Sender<()>,
+}
+
+struct QueueState {
+    global_limit: usize,
+    per_remote_limit: usize,
+    running: usize,
+    running_per_remote: HashMap<String, usize>,
+    waiting: Vec<QueuedTransfer>,
+    next_seq: u64,
+}
+
+/// TransferQueue limits the number of transfers running at the same time,
+/// both in total and towards each remote agent. Transfers over the limits
+/// wait in a queue ordered by priority (higher first) and then by arrival.
+/// A queued transfer can be paused, in which case it is skipped until it is
+/// resumed, and its priority can be changed to reorder the queue.
+#[derive(Clone)]
+pub struct TransferQueue {
+    state: Arc<Mutex<QueueState>>,
+}
+
+/// QueueTicket is handed out for a transfer added to the queue. Dropping it
+/// before waiting on it gives back the slot if the transfer was started.
+pub struct QueueTicket {
+    queue: TransferQueue,
+    remote: String,
+    started: oneshot::Receiver<()>,
+    /// set once the slot of the started transfer is held by a `QueueSlot`
+    slot_taken: bool,
+}
+
+impl QueueTicket {
+    /// Waits until the transfer is allowed to start. A "queued" status
+    /// update is sent if the transfer cannot start right away. Removing the
+    /// transfer from the queue while it is waiting aborts it.
+    pub async fn wait(mut self, progress: &ProgressSink) -> Result<QueueSlot, ClientError> {
+        if self.started.try_recv().is_err() {
+            progress.send("queued").await;
+            if (&mut self.started).await.is_err() {
+                return Err(ClientError {
+                    code: 997,
+                    message: "Operation aborted by user request".to_string(),
+                    http_code: None,
+                });
+            }
+        }
+
+        self.slot_taken = true;
+        Ok(QueueSlot {
+            queue: self.queue.clone(),
+            remote: self.remote.clone(),
+        })
+    }
+}
+
+impl Drop for QueueTicket {
+    fn drop(&mut self) {
+        // the transfer was started but its slot is not going to be held
+        if !self.slot_taken && self.started.try_recv().is_ok() {
+            self.queue.release(&self.remote);
+        }
+    }
+}
+
+/// QueueSlot is held by a running transfer, dropping it lets the next
+/// queued transfer start.
+pub struct QueueSlot {
+    queue: TransferQueue,
+    remote: String,
+}
+
+impl Drop for QueueSlot {
+    fn drop(&mut self) {
+        self.queue.release(&self.remote);
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct QueueEntry {
+    transfer_id: String,
+    position: usize,
+    priority: i32,
+    paused: bool,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct QueueResponse {
+    code: i32,
+    transfers: Vec<QueueEntry>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct QueueUpdateRequest {
+    priority: Option<i32>,
+    paused: Option<bool>,
+}
+
+impl TransferQueue {
+    /// Creates the queue with the limits from the configuration. A limit of 0
+    /// means unlimited.
+    pub fn new() -> Self {
+        let global_limit = CONFIG.max_concurrent_transfers;
+        let per_remote_limit = CONFIG.max_concurrent_transfers_per_remote;
+
+        Self {
+            state: Arc::new(Mutex::new(QueueState {
+                global_limit,
+                per_remote_limit,
+                running: 0,
+                running_per_remote: HashMap::new(),
+                waiting: Vec::new(),
+                next_seq: 0,
+            })),
+        }
+    }
+
+    /// Adds a transfer to the queue, starting it right away if the limits
+    /// allow. The returned ticket is to be waited on before running it.
+    pub fn enqueue(&self, transfer: &Transfer, priority: i32) -> QueueTicket {
+        let remote = format!("{}:{}", transfer.host, transfer.port);
+        let (start, started) = oneshot::channel::<()>();
+
+        let mut state = self.state.lock().unwrap();
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.waiting.push(QueuedTransfer {
+            transfer_id: transfer.transfer_id.clone(),
+            agent_id: transfer.agent_id,
+            remote: remote.clone(),
+            priority,
+            paused: false,
+            seq,
+            start,
+        });
+        Self::dispatch(&mut state);
+
+        QueueTicket {
+            queue: self.clone(),
+            remote,
+            started,
+            slot_taken: false,
+        }
+    }
+
+    /// Removes a waiting transfer of the agent with the id `agent_id` from
+    /// the queue. Returns false if there is no such transfer in the queue.
+    pub fn remove(&self, agent_id: u32, transfer_id: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let count = state.waiting.len();
+        state
+            .waiting
+            .retain(|t| t.transfer_id != transfer_id || t.agent_id != agent_id);
+
+        state.waiting.len() != count
+    }
+
+    /// Changes the priority or the paused state of a waiting transfer of
+    /// the agent with the id `agent_id`. Returns false if there is no such
+    /// transfer in the queue.
+    pub fn update(
+        &self,
+        agent_id: u32,
+        transfer_id: &str,
+        priority: Option<i32>,
+        paused: Option<bool>,
+    ) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let entry = match state
+            .waiting
+            .iter_mut()
+            .find(|t| t.transfer_id == transfer_id && t.agent_id == agent_id)
+        {
+            Some(t) => t,
+            None => return false,
+        };
+
+        if let Some(priority) = priority {
+            entry.priority = priority;
+        }
+        if let Some(paused) = paused {
+            entry.paused = paused;
+        }
+        Self::dispatch(&mut state);
+
+        true
+    }
+
+    /// Returns the waiting transfers of the agent with the id `agent_id`
+    /// in the order they are going to be started.
+    pub fn list(&self, agent_id: u32) -> Vec<QueueEntry> {
+        let mut state = self.state.lock().unwrap();
+        Self::sort(&mut state.waiting);
+
+        state
+            .waiting
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| t.agent_id == agent_id)
+            .map(|(i, t)| QueueEntry {
+                transfer_id: t.transfer_id.clone(),
+                position: i + 1,
+                priority: t.priority,
+                paused: t.paused,
+            })
+            .collect()
+    }
+
+    fn release(&self, remote: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.running = state.running.saturating_sub(1);
+        if let Some(count) = state.running_per_remote.get_mut(remote) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                state.running_per_remote.remove(remote);
+            }
+        }
+        Self::dispatch(&mut state);
+    }
+
+    fn sort(waiting: &mut [QueuedTransfer]) {
+        waiting.sort_by(|a, b| b.priority.cmp(&a.priority).then(a.seq.cmp(&b.seq)));
+    }
+
+    /// Starts as many waiting transfers as the limits allow. Transfers towards
+    /// a remote which is at its limit do not hold up transfers to other remotes.
+    fn dispatch(state: &mut QueueState) {
+        Self::sort(&mut state.waiting);
+
+        let mut i = 0;
+        while i < state.waiting.len() {
+            if state.global_limit > 0 && state.running >= state.global_limit {
+                break;
+            }
+
+            let entry = &state.waiting[i];
+            let running_to_remote = *state.running_per_remote.get(&entry.remote).unwrap_or(&0);
+            if entry.paused
+                || (state.per_remote_limit > 0 && running_to_remote >= state.per_remote_limit)
+            {
+                i += 1;
+                continue;
+            }
+
+            let entry = state.waiting.remove(i);
+            // the transfer may have gone away in the meantime
+            if entry.start.send(()).is_ok() {
+                state.running += 1;
+                *state.running_per_remote.entry(entry.remote).or_insert(0) += 1;
+            }
+        }
+    }
+}
+
+impl Default for TransferQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[get("/agents/<agent_id>/queue")]
+pub async fn get_queue(
+    agent_id: u32,
+    files: &State<Files>,
+    scheduler: &State<TransferScheduler>,
+    cookies: &CookieJar<'_>,
+) -> (Status, Json<QueueResponse>) {
+    // verify that the requester has a valid session in Files and owns the referred agent
+    if let Err(e) = files.api.get_agent(agent_id, cookies.get("rc_auth")).await {
+        return (
+            Status::Forbidden,
+            Json(QueueResponse {
+                code: 919,
+                transfers: vec![],
+                error: Some(e.message),
+            }),
+        );
+    }
+
+    (
+        Status::Ok,
+        Json(QueueResponse {
+            code: 0,
+            transfers: scheduler.queue.list(agent_id),
+            error: None,
+        }),
+    )
+}
+
+#[patch("/agents/<agent_id>/queue/<transfer_id>", data = "<request>")]
+pub async fn update_queue(
+    agent_id: u32,
+    transfer_id: &str,
+    request: Json<QueueUpdateRequest>,
+    files: &State<Files>,
+    scheduler: &State<TransferScheduler>,
+    cookies: &CookieJar<'_>,
+) -> Status {
+    // verify that the requester has a valid session in Files and owns the referred agent
+    let (_, _) = match files.api.get_agent(agent_id, cookies.get("rc_auth")).await {
+        Ok(a) => a,
+        Err(_) => {
+            return Status::Forbidden;
+        }
+    };
+
+    match scheduler
+        .queue
+        .update(agent_id, transfer_id, request.priority, request.paused)
+    {
+        true => Status::Ok,
+        false => Status::NotFound,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        archive::{ArchiveCompression, MetadataPolicy},
+        filter::SkipRules,
+    };
+
+    fn queue(global_limit: usize, per_remote_limit: usize) -> TransferQueue {
+        TransferQueue {
+            state: Arc::new(Mutex::new(QueueState {
+                global_limit,
+                per_remote_limit,
+                running: 0,
+                running_per_remote: HashMap::new(),
+                waiting: Vec::new(),
+                next_seq: 0,
+            })),
+        }
+    }
+
+    fn transfer(transfer_id: &str, host: &str) -> Transfer {
+        Transfer {
+            agent_id: 1,
+            host: host.to_string(),
+            port: "22".to_string(),
+            transfer_id: transfer_id.to_string(),
+            local_path: String::new(),
+            remote_path: String::new(),
+            compress: ArchiveCompression::none(),
+            overwrite: false,
+            size: 0,
+            metadata: MetadataPolicy::default(),
+            streams: 1,
+            skip_rules: SkipRules::default(),
+            conflicts: Vec::new(),
+            move_sources: false,
+            moved_items: None,
+            rc_auth: String::new(),
+        }
+    }
+
+    fn waiting(queue: &TransferQueue) -> Vec<String> {
+        queue
+            .list(1)
+            .into_iter()
+            .map(|entry| entry.transfer_id)
+            .collect()
+    }
+
+    fn running(queue: &TransferQueue) -> usize {
+        queue.state.lock().unwrap().running
+    }
+
+    #[test]
+    fn global_limit() {
+        let queue = queue(2, 0);
+        let a = queue.enqueue(&transfer("a", "host1"), 0);
+        let _b = queue.enqueue(&transfer("b", "host2"), 0);
+        let _c = queue.enqueue(&transfer("c", "host3"), 0);
+        assert_eq!(running(&queue), 2);
+        assert_eq!(waiting(&queue), vec!["c"]);
+
+        // the slot of a started transfer is given back when its ticket is dropped
+        drop(a);
+        assert_eq!(running(&queue), 2);
+        assert!(waiting(&queue).is_empty());
+    }
+
+    #[test]
+    fn remote_at_its_limit_does_not_hold_up_others() {
+        let queue = queue(0, 1);
+        let _a = queue.enqueue(&transfer("a", "host1"), 0);
+        let _b = queue.enqueue(&transfer("b", "host1"), 5);
+        let _c = queue.enqueue(&transfer("c", "host2"), 0);
+        assert_eq!(running(&queue), 2);
+        assert_eq!(waiting(&queue), vec!["b"]);
+    }
+
+    #[test]
+    fn higher_priority_first_then_arrival() {
+        let queue = queue(1, 0);
+        let a = queue.enqueue(&transfer("a", "host1"), 0);
+        let _b = queue.enqueue(&transfer("b", "host1"), 0);
+        let _c = queue.enqueue(&transfer("c", "host1"), 5);
+        let _d = queue.enqueue(&transfer("d", "host1"), 5);
+        assert_eq!(waiting(&queue), vec!["c", "d", "b"]);
+
+        assert!(queue.update(1, "b", Some(9), None));
+        assert_eq!(waiting(&queue), vec!["b", "c", "d"]);
+
+        drop(a);
+        assert_eq!(waiting(&queue), vec!["c", "d"]);
+    }
+
+    #[test]
+    fn paused_transfers_are_skipped() {
+        let queue = queue(1, 0);
+        let a = queue.enqueue(&transfer("a", "host1"), 0);
+        let _b = queue.enqueue(&transfer("b", "host1"), 5);
+        let _c = queue.enqueue(&transfer("c", "host1"), 0);
+        assert!(queue.update(1, "b", None, Some(true)));
+
+        drop(a);
+        assert_eq!(waiting(&queue), vec!["b"]);
+        assert!(!queue.update(2, "b", None, Some(false)));
+        assert!(queue.remove(1, "b"));
+        assert!(waiting(&queue).is_empty());
+    }
+}
@@ -9,13 +9,15 @@ type ViewMode string
 
 // Agent describes an agent.
 type Agent struct {
-	ID         uint       `storm:"id,increment" json:"id"`
-	UserID     uint       `json:"userID"`
-	Branding   string     `json:"branding"`
-	Host       string     `json:"host"`
-	Port       string     `json:"port"`
-	Secret     string     `json:"secret,omitempty"`
-	RemoteUser RemoteUser `json:"remote_user"`
+	ID             uint       `storm:"id,increment" json:"id"`
+	UserID         uint       `json:"userID"`
+	Branding       string     `json:"branding"`
+	Host           string     `json:"host"`
+	Port           string     `json:"port"`
+	Secret         string     `json:"secret,omitempty"`
+	RemoteUser     RemoteUser `json:"remote_user"`
+	BandwidthLimit uint64     `json:"bandwidth_limit"`
+	KeyFingerprint string     `json:"key_fingerprint"`
 }
 
 type RemoteUser struct {
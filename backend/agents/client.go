@@ -12,11 +12,12 @@ import (
 
 type AgentBackend interface {
 	GetTokenUser(userID uint, user *TokenUser, accessToken, token string) (status int, err error)
-	ExchangeKeys(userID uint, host, port, secret, token string) (status int, err error)
+	ExchangeKeys(userID uint, secret, token string) (status int, err error)
 	GetRemoteUser(userID uint, user *RemoteUser, token string) (status int, err error)
 	GetResource(url, token string) (response *GetResourceResponse, status int, err error)
-	RemoteCopy(archiveName, srcRoot, token string, items []ResourceItem, compress bool) (response *BeforeCopyResponse, status int, err error)
+	RemoteCopy(archiveName, srcRoot, token string, items []ResourceItem, compress string) (response *BeforeCopyResponse, status int, err error)
 	CancelTransfer(transferID, token string) (status int, err error)
+	RevokeKey(token string) (status int, err error)
 	GetVersion(token string) GetVersionResponse
 }
 
@@ -32,8 +33,9 @@ type GetAccessTokenResponse struct {
 }
 
 type ExchangeKeysResponse struct {
-	Success bool   `json:"success"`
-	Error   string `json:"error"`
+	Success     bool   `json:"success"`
+	Error       string `json:"error"`
+	Fingerprint string `json:"fingerprint"`
 }
 
 type GetRemoteUserResponse struct {
@@ -82,11 +84,14 @@ type ResourceItem struct {
 	Destination string `json:"destination"`
 	Overwrite   bool   `json:"overwrite"`
 	Keep        bool   `json:"keep"`
+	// Conflict is the strategy for the existing files of the item applied by
+	// the agent: overwrite, skip, rename, overwrite-if-newer or fail
+	Conflict    string `json:"conflict,omitempty"`
 }
 
 type RemoteResourceAgentRequest struct {
 	Items      []ResourceItem `json:"items"`
-	Compress   bool           `json:"compress"`
+	Compress   string         `json:"compress"`
 	SourceRoot string         `json:"source_root"`
 }
 
@@ -177,13 +182,17 @@ func (c *AgentClient) GetTokenUser(userID uint, user *TokenUser, accessToken, to
 	return 0, nil
 }
 
-func (c *AgentClient) ExchangeKeys(userID uint, host, port, secret, token string) (status int, err error) {
+// ExchangeKeys pairs the agent with the remote agent of c.Agent, which has to be
+// saved already: the agent generates a key pair for the connection named after
+// its id. The fingerprint of the key is set on c.Agent.
+func (c *AgentClient) ExchangeKeys(userID uint, secret, token string) (status int, err error) {
 	agentAddress := os.Getenv("AGENT_ADDRESS")
 	requestURL := fmt.Sprintf("%s/api/users/%d/connections", agentAddress, userID)
 	body := []byte(`{
-		"host": "` + host + `", 
-		"port": "` + port + `",
-		"secret": "` + secret + `"
+		"host": "` + c.Agent.Host + `",
+		"port": "` + c.Agent.Port + `",
+		"secret": "` + secret + `",
+		"agent_id": ` + fmt.Sprint(c.Agent.ID) + `
 	}`)
 
 	r, err := nethttps.NewRequest("POST", requestURL, bytes.NewBuffer(body))
@@ -218,6 +227,8 @@ func (c *AgentClient) ExchangeKeys(userID uint, host, port, secret, token string
 		return nethttps.StatusInternalServerError, fmt.Errorf("unexpected error while sending agent API request")
 	}
 
+	c.Agent.KeyFingerprint = resp.Fingerprint
+
 	return nethttps.StatusOK, nil
 }
 
@@ -303,7 +314,7 @@ func (c *AgentClient) RemoteCopy(
 	srcRoot,
 	token string,
 	items []ResourceItem,
-	compress bool,
+	compress string,
 ) (response *BeforeCopyResponse, status int, err error) {
 	agentAddress := os.Getenv("AGENT_ADDRESS")
 	requestURL := fmt.Sprintf("%s/api/agents/%d/resources/%s", agentAddress, c.Agent.ID, strings.Trim(archiveName, "\n"))
@@ -375,6 +386,34 @@ func (c *AgentClient) CancelTransfer(transferID, token string) (status int, err
 	return nethttps.StatusOK, nil
 }
 
+// RevokeKey removes the key of the connection of c.Agent from the remote agent.
+func (c *AgentClient) RevokeKey(token string) (status int, err error) {
+	agentAddress := os.Getenv("AGENT_ADDRESS")
+	requestURL := fmt.Sprintf("%s/api/agents/%d/key", agentAddress, c.Agent.ID)
+
+	r, err := nethttps.NewRequest("DELETE", requestURL, nethttps.NoBody)
+	if err != nil {
+		return nethttps.StatusInternalServerError, fmt.Errorf("error initializing agent API request: %v", err)
+	}
+
+	cookie := nethttps.Cookie{Name: "rc_auth", Value: token}
+	r.AddCookie(&cookie)
+
+	client := &nethttps.Client{}
+	agentResponse, err := client.Do(r)
+	if err != nil {
+		return nethttps.StatusInternalServerError, fmt.Errorf("error sending agent API request: %v", err)
+	}
+
+	defer agentResponse.Body.Close()
+
+	if agentResponse.StatusCode != nethttps.StatusOK {
+		return agentResponse.StatusCode, fmt.Errorf("revoke key error: %s", agentResponse.Status)
+	}
+
+	return nethttps.StatusOK, nil
+}
+
 func (c *AgentClient) GetVersion(token string) GetVersionResponse {
 	agentAddress := os.Getenv("AGENT_ADDRESS")
 	requestURL := fmt.Sprintf("%s/api/agents/%d/version", agentAddress, c.Agent.ID)
@@ -3,6 +3,7 @@ package http
 import (
 	"encoding/json"
 	"fmt"
+	"log"
 	"net/http"
 	"net/url"
 	"os"
@@ -210,6 +211,16 @@ var agentDeleteHandler = withUser(func(w http.ResponseWriter, r *http.Request, d
 		return http.StatusForbidden, nil
 	}
 
+	// agents paired with a key pair of their own have it removed from the
+	// remote, the key shared by older agents may still be used by others
+	if agent.KeyFingerprint != "" {
+		client := agents.AgentClient{Agent: agent}
+		authCookie, _ := r.Cookie("auth")
+		if _, rErr := client.RevokeKey(authCookie.Value); rErr != nil {
+			log.Printf("cannot revoke key of agent %d: %v", id, rErr)
+		}
+	}
+
 	aErr := d.store.Agents.Delete(id)
 	if aErr != nil {
 		return errToStatus(err), err
@@ -248,14 +259,7 @@ var agentPostHandler = withUser(func(w http.ResponseWriter, r *http.Request, d *
 		return userStatus, err
 	}
 
-	kexStatus, err := client.ExchangeKeys(d.user.ID, req.Data.Host, req.Data.Port, req.Data.Secret, authCookie.Value)
-	if err != nil {
-		if kexStatus == http.StatusUnauthorized {
-			kexStatus = http.StatusForbidden
-		}
-		return kexStatus, err
-	}
-
+	secret := req.Data.Secret
 	req.Data.Secret = ""
 	req.Data.UserID = d.user.ID
 	req.Data.Branding = user.Branding
@@ -263,11 +267,29 @@ var agentPostHandler = withUser(func(w http.ResponseWriter, r *http.Request, d *
 	req.Data.RemoteUser.Name = user.Name
 	req.Data.RemoteUser.Token = "x.0"
 
+	// the agent is saved before the keys are exchanged, the key pair of the
+	// connection is named after its id
 	err = d.store.Agents.Save(req.Data)
 	if err != nil {
 		return http.StatusInternalServerError, err
 	}
 
+	kexStatus, err := client.ExchangeKeys(d.user.ID, secret, authCookie.Value)
+	if err != nil {
+		if dErr := d.store.Agents.Delete(req.Data.ID); dErr != nil {
+			log.Printf("cannot delete agent %d after failed key exchange: %v", req.Data.ID, dErr)
+		}
+		if kexStatus == http.StatusUnauthorized {
+			kexStatus = http.StatusForbidden
+		}
+		return kexStatus, err
+	}
+
+	err = d.store.Agents.Update(req.Data, "KeyFingerprint")
+	if err != nil {
+		return http.StatusInternalServerError, err
+	}
+
 	w.Header().Set("Location", "/settings/agents")
 	return http.StatusCreated, nil
 })
@@ -46,7 +46,11 @@ func remoteSourceResourcePostHandler() handleFunc {
 		}
 
 		action := r.URL.Query().Get("action")
-		compress := r.URL.Query().Get("compress") == "true"
+		// "true", "false" or a codec with an optional level, e.g. "zstd:3" or "auto"
+		compress := r.URL.Query().Get("compress")
+		if compress == "" {
+			compress = "false"
+		}
 		for idx, item := range req {
 			src := item.Source
 			dst := item.Destination
@@ -97,6 +101,11 @@ func remoteDestinationResourcePostHandler() handleFunc {
 			return http.StatusBadRequest, err
 		}
 
+		// The source side of a remote-download operation only needs read access
+		if r.URL.Query().Get("action") == "remote-download" {
+			return remoteDownloadSourceCheck(w, r, req, d)
+		}
+
 		for _, item := range req {
 			dst := item.Destination
 			dst, err := url.QueryUnescape(dst)
@@ -141,6 +150,39 @@ func remoteDestinationResourcePostHandler() handleFunc {
 	})
 }
 
+func remoteDownloadSourceCheck(w http.ResponseWriter, r *http.Request, items []agents.ResourceItem, d *data) (int, error) {
+	if !d.user.Perm.Download {
+		return http.StatusForbidden, nil
+	}
+
+	for _, item := range items {
+		src, err := url.QueryUnescape(item.Source)
+		if err != nil {
+			return errToStatus(err), err
+		}
+
+		if !d.Check(src) {
+			return http.StatusForbidden, nil
+		}
+
+		if src == "/" {
+			return http.StatusForbidden, nil
+		}
+
+		if !checkReadable(src, d) {
+			return http.StatusForbidden, fmt.Errorf("cannot read %s", src)
+		}
+	}
+
+	// Return the Agent User's scope that will be used in the archive phase
+	// of this remote-download operation
+	scope := d.user.Scope
+	if scope == "." {
+		scope = ""
+	}
+	return renderJSON(w, r, d.server.Root+scope)
+}
+
 func checkWriteable(dst string, d *data) (writeable bool, base string) {
 	dir := filepath.Dir(dst)
 	scope := d.user.Scope
@@ -166,7 +208,7 @@ func remoteResourcePostAction(
 	action string,
 	items []agents.ResourceItem,
 	d *data,
-	compress bool,
+	compress string,
 ) (int, *agents.BeforeCopyResponse, error) {
 	switch action {
 	// TODO: use enum
@@ -47,7 +47,8 @@ function moveCopyStart(
   for (let item of items) {
     const source = item.from;
     const destination = encodeURIComponent(removePrefix(item.to));
-    requestItems.push({ source, destination, overwrite, keep });
+    const conflict = item.conflict;
+    requestItems.push({ source, destination, overwrite, keep, conflict });
   }
   const action = copy ? "remote-copy" : "remote-rename";
   const query = `?action=${action}&compress=${compress}`;